@@ -30,6 +30,32 @@ impl AABB {
         }
         true
     }
+    // slab test with the reciprocal of the ray direction computed once per ray, the comparisons
+    // are written out because f64::max and f64::min are slow to handle NaN, a NaN slab distance
+    // (a ray in the plane of a slab) leaves the interval unchanged instead
+    pub fn hit_inverse(&self, origin: Vec3, inv_dir: Vec3, mut t_min: f64, mut t_max: f64) -> bool {
+        for a in 0..3 {
+            let inv = inv_dir.getcoordinate(a);
+            let mut t0 = (self.minimum.getcoordinate(a) - origin.getcoordinate(a)) * inv;
+            let mut t1 = (self.maximum.getcoordinate(a) - origin.getcoordinate(a)) * inv;
+            if inv < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            t_min = if t0 > t_min { t0 } else { t_min };
+            t_max = if t1 < t_max { t1 } else { t_max };
+            if t_max <= t_min {
+                return false;
+            }
+        }
+        true
+    }
+    pub fn centroid(&self) -> Vec3 {
+        (self.minimum + self.maximum) * 0.5
+    }
+    pub fn surface_area(&self) -> f64 {
+        let d = self.maximum - self.minimum;
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
 }
 pub fn surrounding_box(box0: AABB, box1: AABB) -> AABB {
     let small: Vec3 = Vec3::new(
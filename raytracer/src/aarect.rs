@@ -1,4 +1,5 @@
-use crate::rtweekend::random_f64;
+use crate::sampler::sample_2d;
+use crate::stats::{count_intersection, count_ray};
 use crate::HitRecord;
 use crate::Hittable;
 use crate::Material;
@@ -30,6 +31,7 @@ impl<T: Material> XYRect<T> {
 
 impl<T: Material> Hittable for XYRect<T> {
     fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        count_intersection();
         let t: f64 = (self.k - r.orig.z) / r.dir.z;
         if t < t_min || t > t_max {
             return None;
@@ -58,6 +60,26 @@ impl<T: Material> Hittable for XYRect<T> {
         );
         true
     }
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
+        count_ray();
+        let rec = match self.hit(Ray::new(o, v, time), 0.001, f64::INFINITY) {
+            Some(rec) => rec,
+            None => return 0.0,
+        };
+        let area = (self.x1 - self.x0) * (self.y1 - self.y0);
+        let distance_squared = rec.t * rec.t * v.squared_length();
+        let cosine = (v * rec.normal / v.length()).abs();
+        distance_squared / (cosine * area)
+    }
+    fn random(&self, o: Vec3, _time: f64) -> Vec3 {
+        let (u, v) = sample_2d();
+        let random_point = Vec3::new(
+            self.x0 + (self.x1 - self.x0) * u,
+            self.y0 + (self.y1 - self.y0) * v,
+            self.k,
+        );
+        random_point - o
+    }
 }
 
 #[derive(Clone)]
@@ -83,6 +105,7 @@ impl<T: Material> XZRect<T> {
 }
 impl<T: Material> Hittable for XZRect<T> {
     fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        count_intersection();
         let t: f64 = (self.k - r.orig.y) / r.dir.y;
         if t < t_min || t > t_max {
             return None;
@@ -111,21 +134,23 @@ impl<T: Material> Hittable for XZRect<T> {
         );
         true
     }
-    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
-        if let None = self.hit(Ray::new(o, v, 0.0), 0.001, f64::INFINITY) {
-            return 0.0;
-        }
-        let rec = self.hit(Ray::new(o, v, 0.0), 0.001, f64::INFINITY).unwrap();
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
+        count_ray();
+        let rec = match self.hit(Ray::new(o, v, time), 0.001, f64::INFINITY) {
+            Some(rec) => rec,
+            None => return 0.0,
+        };
         let area = (self.x1 - self.x0) * (self.z1 - self.z0);
         let distance_squared = rec.t * rec.t * v.squared_length();
         let cosine = (v * rec.normal / v.length()).abs();
         distance_squared / (cosine * area)
     }
-    fn random(&self, o: Vec3) -> Vec3 {
+    fn random(&self, o: Vec3, _time: f64) -> Vec3 {
+        let (u, v) = sample_2d();
         let random_point = Vec3::new(
-            random_f64(self.x0, self.x1),
+            self.x0 + (self.x1 - self.x0) * u,
             self.k,
-            random_f64(self.z0, self.z1),
+            self.z0 + (self.z1 - self.z0) * v,
         );
         random_point - o
     }
@@ -154,6 +179,7 @@ impl<T: Material> YZRect<T> {
 }
 impl<T: Material> Hittable for YZRect<T> {
     fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        count_intersection();
         let t: f64 = (self.k - r.orig.x) / r.dir.x;
         if t < t_min || t > t_max {
             return None;
@@ -182,4 +208,24 @@ impl<T: Material> Hittable for YZRect<T> {
         );
         true
     }
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
+        count_ray();
+        let rec = match self.hit(Ray::new(o, v, time), 0.001, f64::INFINITY) {
+            Some(rec) => rec,
+            None => return 0.0,
+        };
+        let area = (self.y1 - self.y0) * (self.z1 - self.z0);
+        let distance_squared = rec.t * rec.t * v.squared_length();
+        let cosine = (v * rec.normal / v.length()).abs();
+        distance_squared / (cosine * area)
+    }
+    fn random(&self, o: Vec3, _time: f64) -> Vec3 {
+        let (u, v) = sample_2d();
+        let random_point = Vec3::new(
+            self.k,
+            self.y0 + (self.y1 - self.y0) * u,
+            self.z0 + (self.z1 - self.z0) * v,
+        );
+        random_point - o
+    }
 }
@@ -0,0 +1,121 @@
+use crate::bvh::{BvhNode, FlatBvh};
+use crate::cli::RenderSettings;
+use crate::rtweekend::INFINITY;
+use crate::sampler::{sample_2d, set_sampler, start_sample};
+use crate::scene::{load_scene, SceneError};
+use crate::stats::{count_ray, RayStats};
+use crate::vec3::random_unit_vector;
+use crate::Hittable;
+use crate::Ray;
+use std::time::Instant;
+use std::vec::Vec;
+
+struct Trace {
+    // closest hit distance of every ray, infinity for misses
+    distances: Vec<f64>,
+    seconds: f64,
+    stats: RayStats,
+}
+
+fn trace(world: &dyn Hittable, rays: &[Ray]) -> Trace {
+    RayStats::take();
+    let start = Instant::now();
+    let distances = rays
+        .iter()
+        .map(|&r| {
+            count_ray();
+            match world.hit(r, 0.001, INFINITY) {
+                Some(rec) => rec.t,
+                None => INFINITY,
+            }
+        })
+        .collect();
+    Trace {
+        distances,
+        seconds: start.elapsed().as_secs_f64(),
+        stats: RayStats::take(),
+    }
+}
+impl Trace {
+    fn mrays_per_second(&self) -> f64 {
+        self.distances.len() as f64 / self.seconds / 1e6
+    }
+}
+
+// Times the recursive BvhNode against FlatBvh on the scene: camera rays for every sample of
+// every pixel, and one diffuse bounce from each of their hits as a set of incoherent rays.
+pub fn run(settings: &RenderSettings) -> Result<(), SceneError> {
+    // building a hierarchy consumes the objects, each one gets its own copy of the scene
+    let scene = load_scene(&settings.scene, settings.aspect_ratio())?;
+    let object_count = scene.world.objects.len();
+    let (time0, time1) = scene.camera.shutter();
+    let start = Instant::now();
+    let recursive = BvhNode::new(scene.world.objects, time0, time1);
+    let recursive_build = start.elapsed().as_secs_f64();
+    let cam = scene.camera;
+    let scene = load_scene(&settings.scene, settings.aspect_ratio())?;
+    let start = Instant::now();
+    let flat = FlatBvh::new(scene.world.objects, time0, time1);
+    let flat_build = start.elapsed().as_secs_f64();
+
+    set_sampler(settings.sampler, settings.samples_per_pixel as u32);
+    let (width, height) = (settings.width as usize, settings.height as usize);
+    let mut camera_rays = Vec::with_capacity(width * height * settings.samples_per_pixel as usize);
+    let mut bounce_rays = Vec::new();
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = y * width + x;
+            for s in 0..settings.samples_per_pixel {
+                start_sample(settings.seed, pixel as u64, s as u64);
+                let (dx, dy) = sample_2d();
+                let u = (x as f64 + dx) / (width as f64 - 1.0);
+                let v = ((height - 1 - y) as f64 + dy) / (height as f64 - 1.0);
+                let r = cam.get_ray(&u, &v);
+                camera_rays.push(r);
+                if let Some(rec) = flat.hit(r, 0.001, INFINITY) {
+                    let direction = rec.normal + random_unit_vector();
+                    bounce_rays.push(Ray::new(rec.p, direction, r.tm));
+                }
+            }
+        }
+    }
+
+    let recursive_camera = trace(&recursive, &camera_rays);
+    let flat_camera = trace(&flat, &camera_rays);
+    let recursive_bounce = trace(&recursive, &bounce_rays);
+    let flat_bounce = trace(&flat, &bounce_rays);
+
+    println!(
+        "scene `{}`: {} objects, {} camera rays, {} bounce rays",
+        settings.scene,
+        object_count,
+        camera_rays.len(),
+        bounce_rays.len()
+    );
+    println!("             build         camera rays                 bounce rays");
+    for (name, build, camera, bounce) in &[
+        (
+            "recursive",
+            recursive_build,
+            &recursive_camera,
+            &recursive_bounce,
+        ),
+        ("flat", flat_build, &flat_camera, &flat_bounce),
+    ] {
+        println!(
+            "{:<9} {:>7.2} ms  {:>7.2} Mrays/s {:>6.2} tests  {:>7.2} Mrays/s {:>6.2} tests",
+            name,
+            build * 1e3,
+            camera.mrays_per_second(),
+            camera.stats.intersections_per_ray(),
+            bounce.mrays_per_second(),
+            bounce.stats.intersections_per_ray()
+        );
+    }
+    println!(
+        "flat speedup: {:.2}x on camera rays, {:.2}x on bounce rays",
+        recursive_camera.seconds / flat_camera.seconds,
+        recursive_bounce.seconds / flat_bounce.seconds
+    );
+    Ok(())
+}
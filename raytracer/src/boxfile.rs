@@ -75,14 +75,17 @@ impl<'a> RealBox {
 }
 impl Hittable for RealBox {
     fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        if let None = self.sides.hit(r, t_min, t_max) {
-            return None;
-        }
-        let rec = self.sides.hit(r, t_min, t_max).unwrap();
-        Some(rec)
+        self.sides.hit(r, t_min, t_max)
     }
     fn bounding_box(&self, time0: f64, time1: f64, output_box: &mut crate::aabb::AABB) -> bool {
         *output_box = AABB::new(self.box_min, self.box_max);
         true
     }
+    // one of the six sides picked uniformly
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
+        self.sides.pdf_value(o, v, time)
+    }
+    fn random(&self, o: Vec3, time: f64) -> Vec3 {
+        self.sides.random(o, time)
+    }
 }
@@ -1,116 +1,575 @@
 use crate::aabb::surrounding_box;
-use crate::rtweekend::random_i32;
+use crate::rtweekend::INFINITY;
 use crate::HitRecord;
 use crate::Hittable;
 use crate::Ray;
 use crate::Vec3;
 use crate::AABB;
-use std::cmp::Ordering;
 use std::boxed::Box;
+use std::cmp::Ordering;
 use std::vec::Vec;
 
+// number of buckets the centroid range is split into when looking for the cheapest split
+const BINS: usize = 16;
+// cost of visiting a node relative to intersecting one primitive
+const TRAVERSAL_COST: f64 = 0.125;
+// FlatBvh leaves hold at most this many primitives
+const MAX_LEAF_SIZE: usize = 4;
+// deeper FlatBvh nodes are split at the median, which bounds the depth of the tree and with it
+// the traversal stack
+const MAX_SAH_DEPTH: usize = 32;
+const STACK_SIZE: usize = 64;
+
+struct Primitive {
+    object: Box<dyn Hittable>,
+    boxb: AABB,
+    centroid: Vec3,
+}
+
+fn empty_box() -> AABB {
+    AABB::new(
+        Vec3::new(INFINITY, INFINITY, INFINITY),
+        Vec3::new(-INFINITY, -INFINITY, -INFINITY),
+    )
+}
+
+// the scene loader rejects objects without a bounding box, there is no place for them in the tree
+fn primitives(objects: Vec<Box<dyn Hittable>>, time0: f64, time1: f64) -> Vec<Primitive> {
+    objects
+        .into_iter()
+        .map(|object| {
+            let mut boxb = AABB::new(Vec3::zero(), Vec3::zero());
+            assert!(
+                object.bounding_box(time0, time1, &mut boxb),
+                "objects in a BVH need a bounding box"
+            );
+            Primitive {
+                object,
+                boxb,
+                centroid: boxb.centroid(),
+            }
+        })
+        .collect()
+}
+
+fn bounds(primitives: &[Primitive]) -> AABB {
+    match primitives.first() {
+        Some(first) => primitives
+            .iter()
+            .fold(first.boxb, |b, p| surrounding_box(b, p.boxb)),
+        None => AABB::new(Vec3::zero(), Vec3::zero()),
+    }
+}
+
+fn centroid_bounds(primitives: &[Primitive]) -> AABB {
+    let mut centroids = AABB::new(primitives[0].centroid, primitives[0].centroid);
+    for p in primitives {
+        centroids = surrounding_box(centroids, AABB::new(p.centroid, p.centroid));
+    }
+    centroids
+}
+
+#[derive(Clone, Copy)]
+struct Bin {
+    count: usize,
+    boxb: AABB,
+}
+
+// primitives whose centroid falls in a bucket below `bin` go to the left child
+#[derive(Clone, Copy)]
+struct Split {
+    axis: i32,
+    low: f64,
+    scale: f64,
+    bin: usize,
+}
+impl Split {
+    fn goes_left(&self, p: &Primitive) -> bool {
+        bin_index(p.centroid.getcoordinate(self.axis), self.low, self.scale) < self.bin
+    }
+}
+
+// Picks the split of `primitives` with the lowest surface area heuristic cost, the area of each
+// side weighted by the number of primitives in it. Centroids are sorted into BINS buckets per
+// axis and only the boundaries between buckets are evaluated. Returns None if all centroids
+// coincide. The cost is not normalised by the area of the node.
+fn find_split(primitives: &[Primitive]) -> Option<(f64, Split)> {
+    let centroids = centroid_bounds(primitives);
+    let mut best: Option<(f64, Split)> = None;
+    for axis in 0..3 {
+        let low = centroids.minimum.getcoordinate(axis);
+        let extent = centroids.maximum.getcoordinate(axis) - low;
+        if extent <= 0.0 {
+            continue;
+        }
+        let scale = BINS as f64 / extent;
+        let mut bins = [Bin {
+            count: 0,
+            boxb: empty_box(),
+        }; BINS];
+        for p in primitives {
+            let b = bin_index(p.centroid.getcoordinate(axis), low, scale);
+            bins[b].count += 1;
+            bins[b].boxb = surrounding_box(bins[b].boxb, p.boxb);
+        }
+        // sweep from the right to get the cost of every right side, then from the left
+        let mut right_area = [0.0; BINS];
+        let mut right_count = [0; BINS];
+        let mut boxb = empty_box();
+        let mut count = 0;
+        for i in (1..BINS).rev() {
+            boxb = surrounding_box(boxb, bins[i].boxb);
+            count += bins[i].count;
+            right_area[i] = boxb.surface_area();
+            right_count[i] = count;
+        }
+        let mut boxb = empty_box();
+        let mut count = 0;
+        for i in 1..BINS {
+            boxb = surrounding_box(boxb, bins[i - 1].boxb);
+            count += bins[i - 1].count;
+            if count == 0 || right_count[i] == 0 {
+                continue;
+            }
+            let cost = boxb.surface_area() * count as f64 + right_area[i] * right_count[i] as f64;
+            if best.map_or(true, |(best_cost, _)| cost < best_cost) {
+                let split = Split {
+                    axis,
+                    low,
+                    scale,
+                    bin: i,
+                };
+                best = Some((cost, split));
+            }
+        }
+    }
+    best
+}
+
+// splits into two halves along the axis with the largest centroid extent
+fn median_split(mut primitives: Vec<Primitive>) -> (i32, Vec<Primitive>, Vec<Primitive>) {
+    let centroids = centroid_bounds(&primitives);
+    let extent = centroids.maximum - centroids.minimum;
+    let axis = if extent.x >= extent.y && extent.x >= extent.z {
+        0
+    } else if extent.y >= extent.z {
+        1
+    } else {
+        2
+    };
+    primitives.sort_by(|a, b| {
+        a.centroid
+            .getcoordinate(axis)
+            .partial_cmp(&b.centroid.getcoordinate(axis))
+            .unwrap_or(Ordering::Equal)
+    });
+    let right = primitives.split_off(primitives.len() / 2);
+    (axis, primitives, right)
+}
+
+fn bin_index(x: f64, low: f64, scale: f64) -> usize {
+    (((x - low) * scale) as usize).min(BINS - 1)
+}
+
 pub struct BvhNode {
     pub left: Option<Box<dyn Hittable>>,
     pub right: Option<Box<dyn Hittable>>,
     pub boxb: AABB,
 }
 impl BvhNode {
-    pub fn new(
-        mut objects: Vec<Box<dyn Hittable>>,
-        time0: f64,
-        time1: f64,
-    ) -> Self {
-        let axis: i32 = random_i32(0, 2);
-        let comparator = if axis == 0 {
-            box_x_compare
-        } else if axis == 1 {
-            box_y_compare
-        } else {
-            box_z_compare
-        };
-        let object_span = objects.len();
-        let mut left:Option<Box<dyn Hittable>> = None;
-        let mut right:Option<Box<dyn Hittable>> = None;
-        if object_span == 1 {
-            left = objects.pop();
-        } else if object_span == 2 {
-            if comparator(&objects[0], &objects[1]) == Ordering::Less {
-                right = objects.pop();
-                left = objects.pop();
-            } else {
-                left = objects.pop();
-                right = objects.pop();
+    pub fn new(objects: Vec<Box<dyn Hittable>>, time0: f64, time1: f64) -> Self {
+        Self::build(primitives(objects, time0, time1))
+    }
+
+    fn build(mut primitives: Vec<Primitive>) -> Self {
+        let boxb = bounds(&primitives);
+        if primitives.len() <= 1 {
+            return Self {
+                left: primitives.pop().map(|p| p.object),
+                right: None,
+                boxb,
+            };
+        }
+        let right = match find_split(&primitives) {
+            Some((_, split)) => {
+                let (left, right) = primitives.into_iter().partition(|p| split.goes_left(p));
+                primitives = left;
+                right
+            }
+            // every centroid is in the same place, any split is as good as another
+            None => {
+                let mid = primitives.len() / 2;
+                primitives.split_off(mid)
             }
+        };
+        Self {
+            left: Some(Self::child(primitives)),
+            right: Some(Self::child(right)),
+            boxb,
+        }
+    }
+
+    // a single primitive is stored directly instead of in a node of its own
+    fn child(mut primitives: Vec<Primitive>) -> Box<dyn Hittable> {
+        if primitives.len() == 1 {
+            primitives.pop().unwrap().object
         } else {
-            objects.sort_by(|a, b| comparator(a, b));
-            let mid = object_span / 2;
-            let a = objects.split_off(mid);
-            left = Some(Box::new(BvhNode::new(objects, time0, time1)));
-            right = Some(Box::new(BvhNode::new(a, time0, time1)));
-        }
-        let box_left = AABB::new(Vec3::zero(), Vec3::zero());
-        let box_right = AABB::new(Vec3::zero(), Vec3::zero());
-            /*if !left.bounding_box(time0, time1, &mut box_left)
-                || !right.bounding_box(time0, time1, &mut box_right)
-            {
-                print!("No bounding box in bvh_node constructor");
-            }*/
-            let boxb = surrounding_box(box_left, box_right);
-            Self { left, right, boxb }
+            Box::new(Self::build(primitives))
+        }
     }
 }
 
 impl Hittable for BvhNode {
-    fn bounding_box(&self, time0: f64, time1: f64, output_box: &mut AABB) -> bool {
+    fn bounding_box(&self, _time0: f64, _time1: f64, output_box: &mut AABB) -> bool {
         *output_box = self.boxb;
         true
     }
-    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
         if !self.boxb.hit(r, t_min, t_max) {
             return None;
         }
-        let mut hit_left: Option<HitRecord> = None;
-        let mut hit_right: Option<HitRecord> = None;
-        let left = self.left.as_ref().unwrap();
-        let right = self.right.as_ref().unwrap();
-        if let None = left.hit(r, t_min, t_max) {
-           hit_right = right.hit(r, t_min, t_max);
-           return hit_right;
-        }
-        else {
-            hit_left = left.hit(r, t_min, t_max);
-            let tem = hit_left.unwrap();
-            let t = tem.t;
-            if let None = right.hit(r, t_min, t) {
-                return Some(tem);
-            }
-            else {
-                hit_right = right.hit(r, t_min, t);
-                return hit_right;
+        let left = match &self.left {
+            Some(left) => left,
+            None => return None,
+        };
+        let hit_left = left.hit(r, t_min, t_max);
+        let t = match &hit_left {
+            Some(rec) => rec.t,
+            None => t_max,
+        };
+        if let Some(right) = &self.right {
+            if let Some(rec) = right.hit(r, t_min, t) {
+                return Some(rec);
             }
         }
+        hit_left
     }
 }
-pub fn box_compare(a: &Box<dyn Hittable>, b: &Box<dyn Hittable>, axis: i32) -> Ordering {
-    let mut box_a: AABB = AABB::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 0.0));
-    let mut box_b: AABB = AABB::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 0.0));
-    if !a.bounding_box(0.0, 0.0, &mut box_a) || !b.bounding_box(0.0, 0.0, &mut box_b) {
-        println!("No bounding box in bvh_node constructor");
+unsafe impl Sync for BvhNode {}
+unsafe impl Send for BvhNode {}
+
+// Interior nodes are followed by their first child, `offset` is the index of the second one.
+// Leaves hold `count` primitives starting at `offset`.
+struct LinearNode {
+    boxb: AABB,
+    offset: u32,
+    count: u16,
+    axis: u8,
+}
+
+// The same hierarchy as BvhNode stored in one array in depth first order. Leaves group a few
+// primitives so that the tree is shallower, and the traversal uses an explicit stack and
+// visits the child on the side the ray comes from first, so that closer hits cut off the
+// search in the other child early.
+pub struct FlatBvh {
+    nodes: Vec<LinearNode>,
+    objects: Vec<Box<dyn Hittable>>,
+}
+impl FlatBvh {
+    pub fn new(objects: Vec<Box<dyn Hittable>>, time0: f64, time1: f64) -> Self {
+        let mut bvh = Self {
+            nodes: Vec::new(),
+            objects: Vec::with_capacity(objects.len()),
+        };
+        let primitives = primitives(objects, time0, time1);
+        if !primitives.is_empty() {
+            bvh.build(primitives, 0);
+        }
+        bvh
     }
-    if box_a.minimum.getcoordinate(axis) < box_b.minimum.getcoordinate(axis) {
-        return Ordering::Less;
+
+    fn build(&mut self, primitives: Vec<Primitive>, depth: usize) {
+        let boxb = bounds(&primitives);
+        let index = self.nodes.len();
+        self.nodes.push(LinearNode {
+            boxb,
+            offset: self.objects.len() as u32,
+            count: primitives.len() as u16,
+            axis: 0,
+        });
+        let n = primitives.len();
+        if n == 1 {
+            self.objects
+                .extend(primitives.into_iter().map(|p| p.object));
+            return;
+        }
+        let sah = if depth < MAX_SAH_DEPTH {
+            find_split(&primitives)
+        } else {
+            None
+        };
+        let (axis, left, right) = match sah {
+            Some((cost, split))
+                if n > MAX_LEAF_SIZE || TRAVERSAL_COST + cost / boxb.surface_area() < n as f64 =>
+            {
+                let (left, right) = primitives.into_iter().partition(|p| split.goes_left(p));
+                (split.axis, left, right)
+            }
+            _ if n > MAX_LEAF_SIZE => median_split(primitives),
+            _ => {
+                self.objects
+                    .extend(primitives.into_iter().map(|p| p.object));
+                return;
+            }
+        };
+        self.build(left, depth + 1);
+        self.nodes[index].offset = self.nodes.len() as u32;
+        self.nodes[index].count = 0;
+        self.nodes[index].axis = axis as u8;
+        self.build(right, depth + 1);
     }
-    else {
-        return Ordering::Greater;
+
+    // Calls `f` with every primitive in the leaves whose box the ray passes through between
+    // `t_min` and `t_max`. Unlike `hit` the search doesn't stop at the closest primitive.
+    pub fn for_each_candidate<F: FnMut(&dyn Hittable)>(
+        &self,
+        r: Ray,
+        t_min: f64,
+        t_max: f64,
+        mut f: F,
+    ) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let inv_dir = Vec3::new(1.0 / r.dir.x, 1.0 / r.dir.y, 1.0 / r.dir.z);
+        let mut stack = [0usize; STACK_SIZE];
+        let mut top = 0;
+        let mut current = 0;
+        loop {
+            let node = &self.nodes[current];
+            if node.boxb.hit_inverse(r.orig, inv_dir, t_min, t_max) {
+                if node.count > 0 {
+                    let start = node.offset as usize;
+                    for object in &self.objects[start..start + node.count as usize] {
+                        f(&**object);
+                    }
+                } else {
+                    stack[top] = node.offset as usize;
+                    top += 1;
+                    current += 1;
+                    continue;
+                }
+            }
+            if top == 0 {
+                break;
+            }
+            top -= 1;
+            current = stack[top];
+        }
     }
 }
-pub fn box_x_compare(a: &Box<dyn Hittable>, b: &Box<dyn Hittable>) -> Ordering {
-    box_compare(a, b, 0)
-}
-pub fn box_y_compare(a: &Box<dyn Hittable>, b: &Box<dyn Hittable>) -> Ordering {
-    box_compare(a, b, 1)
-}
-pub fn box_z_compare(a: &Box<dyn Hittable>, b: &Box<dyn Hittable>) -> Ordering {
-    box_compare(a, b, 2)
+
+impl Hittable for FlatBvh {
+    fn bounding_box(&self, _time0: f64, _time1: f64, output_box: &mut AABB) -> bool {
+        match self.nodes.first() {
+            Some(root) => {
+                *output_box = root.boxb;
+                true
+            }
+            None => false,
+        }
+    }
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let inv_dir = Vec3::new(1.0 / r.dir.x, 1.0 / r.dir.y, 1.0 / r.dir.z);
+        let negative = [inv_dir.x < 0.0, inv_dir.y < 0.0, inv_dir.z < 0.0];
+        let mut closest = t_max;
+        let mut result = None;
+        let mut stack = [0usize; STACK_SIZE];
+        let mut top = 0;
+        let mut current = 0;
+        loop {
+            let node = &self.nodes[current];
+            if node.boxb.hit_inverse(r.orig, inv_dir, t_min, closest) {
+                if node.count > 0 {
+                    let start = node.offset as usize;
+                    for object in &self.objects[start..start + node.count as usize] {
+                        if let Some(rec) = object.hit(r, t_min, closest) {
+                            closest = rec.t;
+                            result = Some(rec);
+                        }
+                    }
+                } else {
+                    // the second child is on the near side for rays going down the split axis
+                    let (near, far) = if negative[node.axis as usize] {
+                        (node.offset as usize, current + 1)
+                    } else {
+                        (current + 1, node.offset as usize)
+                    };
+                    stack[top] = far;
+                    top += 1;
+                    current = near;
+                    continue;
+                }
+            }
+            if top == 0 {
+                break;
+            }
+            top -= 1;
+            current = stack[top];
+        }
+        result
+    }
 }
+unsafe impl Sync for FlatBvh {}
+unsafe impl Send for FlatBvh {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtweekend::Pcg32;
+    use crate::{HittableList, Lambertian, Sphere};
 
+    fn sphere(center: Vec3, radius: f64) -> Box<dyn Hittable> {
+        Box::new(Sphere::new(center, radius, Lambertian::new2(Vec3::ones())))
+    }
+    fn random_point(rng: &mut Pcg32) -> Vec3 {
+        Vec3::new(rng.next_f64(), rng.next_f64(), rng.next_f64()) * 10.0
+    }
+    // the same spheres for the same seed, in a 10 x 10 x 10 box
+    fn random_spheres(seed: u64, n: usize) -> Vec<Box<dyn Hittable>> {
+        let mut rng = Pcg32::new(seed, 0);
+        (0..n)
+            .map(|_| {
+                let center = random_point(&mut rng);
+                sphere(center, 0.1 + 0.9 * rng.next_f64())
+            })
+            .collect()
+    }
+    // rays from far away aimed into the box, and rays from inside it in any direction
+    fn random_rays(n: usize) -> Vec<Ray> {
+        let mut rng = Pcg32::new(1, 1);
+        (0..n)
+            .map(|i| {
+                let target = random_point(&mut rng);
+                let origin = random_point(&mut rng);
+                if i % 2 == 0 {
+                    let away = (origin - Vec3::new(5.0, 5.0, 5.0)).unit();
+                    Ray::new(target + away * 30.0, -away, 0.0)
+                } else {
+                    Ray::new(origin, target - origin, 0.0)
+                }
+            })
+            .collect()
+    }
+    fn closest(world: &dyn Hittable, r: Ray) -> Option<u64> {
+        world.hit(r, 0.001, INFINITY).map(|rec| rec.t.to_bits())
+    }
+    fn bounding_box(object: &dyn Hittable) -> AABB {
+        let mut boxb = AABB::new(Vec3::zero(), Vec3::zero());
+        assert!(object.bounding_box(0.0, 1.0, &mut boxb));
+        boxb
+    }
+    fn encloses(outer: AABB, inner: AABB) -> bool {
+        (0..3).all(|a| {
+            outer.minimum.getcoordinate(a) <= inner.minimum.getcoordinate(a)
+                && inner.maximum.getcoordinate(a) <= outer.maximum.getcoordinate(a)
+        })
+    }
+    fn list(objects: Vec<Box<dyn Hittable>>) -> HittableList {
+        let mut list = HittableList::new();
+        for object in objects {
+            list.add(object);
+        }
+        list
+    }
+
+    #[test]
+    fn sah_splits_between_clusters() {
+        let mut rng = Pcg32::new(2, 0);
+        let objects = (0..10)
+            .map(|i| {
+                let x = if i % 2 == 0 { 0.0 } else { 20.0 };
+                sphere(Vec3::new(x, 0.0, 0.0) + random_point(&mut rng) * 0.1, 0.5)
+            })
+            .collect();
+        let primitives = primitives(objects, 0.0, 1.0);
+        let (_, split) = find_split(&primitives).unwrap();
+        assert_eq!(split.axis, 0);
+        for p in &primitives {
+            assert_eq!(split.goes_left(p), p.centroid.x < 10.0);
+        }
+    }
+
+    #[test]
+    fn coincident_centroids_are_split_in_half() {
+        let concentric = || {
+            (1..=8)
+                .map(|i| sphere(Vec3::new(1.0, 2.0, 3.0), i as f64 * 0.1))
+                .collect::<Vec<_>>()
+        };
+        assert!(find_split(&primitives(concentric(), 0.0, 1.0)).is_none());
+        let bvh = BvhNode::new(concentric(), 0.0, 1.0);
+        assert!(bvh.left.is_some() && bvh.right.is_some());
+        let brute_force = list(concentric());
+        for r in random_rays(200) {
+            assert_eq!(closest(&bvh, r), closest(&brute_force, r));
+        }
+    }
+
+    #[test]
+    fn single_primitive_is_a_leaf() {
+        let bvh = BvhNode::new(random_spheres(3, 1), 0.0, 1.0);
+        let only = random_spheres(3, 1).pop().unwrap();
+        assert!(bvh.left.is_some() && bvh.right.is_none());
+        assert_eq!(bounding_box(&bvh), bounding_box(&only));
+        for r in random_rays(200) {
+            assert_eq!(closest(&bvh, r), closest(&only, r));
+        }
+    }
+
+    #[test]
+    fn children_enclose_their_primitives() {
+        let bvh = BvhNode::new(random_spheres(4, 200), 0.0, 1.0);
+        let (left, right) = (bvh.left.as_ref().unwrap(), bvh.right.as_ref().unwrap());
+        let (left, right) = (bounding_box(&**left), bounding_box(&**right));
+        assert!(encloses(bvh.boxb, left) && encloses(bvh.boxb, right));
+        for object in &random_spheres(4, 200) {
+            let boxb = bounding_box(&**object);
+            assert!(encloses(left, boxb) || encloses(right, boxb));
+        }
+        let brute_force = list(random_spheres(4, 200));
+        for r in random_rays(2000) {
+            assert_eq!(closest(&bvh, r), closest(&brute_force, r));
+        }
+    }
+
+    #[test]
+    fn flat_and_recursive_bvh_find_the_same_closest_hit() {
+        for &n in &[0, 1, 2, 7, 300] {
+            let recursive = BvhNode::new(random_spheres(5, n), 0.0, 1.0);
+            let flat = FlatBvh::new(random_spheres(5, n), 0.0, 1.0);
+            let brute_force = list(random_spheres(5, n));
+            if n == 0 {
+                let mut boxb = AABB::new(Vec3::zero(), Vec3::zero());
+                assert!(!flat.bounding_box(0.0, 1.0, &mut boxb));
+            }
+            for r in random_rays(2000) {
+                let expected = closest(&brute_force, r);
+                assert_eq!(closest(&recursive, r), expected, "{} objects", n);
+                assert_eq!(closest(&flat, r), expected, "{} objects", n);
+            }
+        }
+    }
+
+    #[test]
+    fn flat_nodes_enclose_their_children() {
+        let bvh = FlatBvh::new(random_spheres(6, 300), 0.0, 1.0);
+        let mut leaf_objects = 0;
+        for (i, node) in bvh.nodes.iter().enumerate() {
+            if node.count > 0 {
+                let start = node.offset as usize;
+                for object in &bvh.objects[start..start + node.count as usize] {
+                    assert!(encloses(node.boxb, bounding_box(&**object)));
+                }
+                leaf_objects += node.count as usize;
+            } else {
+                assert!(encloses(node.boxb, bvh.nodes[i + 1].boxb));
+                assert!(encloses(node.boxb, bvh.nodes[node.offset as usize].boxb));
+            }
+        }
+        assert_eq!(leaf_objects, 300);
+    }
+}
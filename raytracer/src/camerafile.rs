@@ -1,6 +1,6 @@
-use crate::random_f64;
 use crate::rtweekend::degrees_to_radians;
-use crate::vec3::random_in_unit_disk;
+use crate::sampler::{sample_1d, sample_2d};
+use crate::vec3::sample_unit_disk;
 use crate::Ray;
 use crate::Vec3;
 
@@ -53,15 +53,20 @@ impl Camera {
             time1,
         }
     }
+    // the time span rays are spread over, moving objects are bounded over the same interval
+    pub fn shutter(&self) -> (f64, f64) {
+        (self.time0, self.time1)
+    }
     pub fn get_ray(&self, s: &f64, t: &f64) -> Ray {
-        let rd: Vec3 = random_in_unit_disk() * self.lens_radius;
+        let (lens_u, lens_v) = sample_2d();
+        let rd: Vec3 = sample_unit_disk(lens_u, lens_v) * self.lens_radius;
         let offset: Vec3 = self.u * rd.x + self.v * rd.y;
         Ray::new(
             self.origin + offset,
             self.lower_left_corner + self.horizontal * *s + self.vertical * *t
                 - self.origin
                 - offset,
-            random_f64(self.time0, self.time1),
+            self.time0 + (self.time1 - self.time0) * sample_1d(),
         )
     }
 }
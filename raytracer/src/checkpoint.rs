@@ -0,0 +1,323 @@
+use crate::cli::RenderSettings;
+use crate::progressive::{Accumulator, PixelStats};
+use crate::sampler::SamplerKind;
+use crate::tonemap::{ToneMapper, ToneMapping};
+use crate::Vec3;
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::vec::Vec;
+
+const MAGIC: &[u8; 8] = b"RTCKPT\0\0";
+const VERSION: u32 = 2;
+// five f64 sums and the u32 sample count
+const PIXEL_SIZE: usize = 5 * 8 + 4;
+
+#[derive(Debug)]
+pub enum CheckpointError {
+    Io(String, io::Error),
+    Invalid(String, String),
+}
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheckpointError::Io(path, err) => write!(f, "checkpoint `{}`: {}", path, err),
+            CheckpointError::Invalid(path, message) => {
+                write!(f, "checkpoint `{}` is invalid: {}", path, message)
+            }
+        }
+    }
+}
+impl std::error::Error for CheckpointError {}
+
+// everything a render needs to continue where it stopped, the settings that define the image,
+// the target and outputs of the render and the accumulated samples of every pixel
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checkpoint {
+    pub scene: String,
+    pub max_depth: i32,
+    pub seed: u64,
+    pub sampler: SamplerKind,
+    pub spectral: bool,
+    pub samples_per_pixel: i32,
+    pub tonemap: ToneMapping,
+    pub outputs: Vec<String>,
+    pub accumulator: Accumulator,
+}
+impl Checkpoint {
+    pub fn new(settings: &RenderSettings, accumulator: Accumulator) -> Self {
+        Self {
+            scene: settings.scene.clone(),
+            max_depth: settings.max_depth,
+            seed: settings.seed,
+            sampler: settings.sampler,
+            spectral: settings.spectral,
+            samples_per_pixel: settings.samples_per_pixel,
+            tonemap: settings.tonemap,
+            outputs: settings.outputs.clone(),
+            accumulator,
+        }
+    }
+    // settings that define the image override the ones from the command line, the target and
+    // outputs are only defaults for options that were not given
+    pub fn apply(&self, settings: &mut RenderSettings) {
+        settings.scene = self.scene.clone();
+        settings.width = self.accumulator.width as i32;
+        settings.height = self.accumulator.height as i32;
+        settings.max_depth = self.max_depth;
+        settings.seed = self.seed;
+        settings.sampler = self.sampler;
+        settings.spectral = self.spectral;
+        if !settings.is_given("--spp") {
+            settings.samples_per_pixel = self.samples_per_pixel;
+        }
+        if !settings.is_given("--output") {
+            settings.outputs = self.outputs.clone();
+        }
+        if !settings.is_given("--tonemap") {
+            settings.tonemap.operator = self.tonemap.operator;
+        }
+        if !settings.is_given("--exposure") {
+            settings.tonemap.exposure = self.tonemap.exposure;
+        }
+        if !settings.is_given("--white") {
+            settings.tonemap.white = self.tonemap.white;
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out: Vec<u8> = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.extend_from_slice(&(self.scene.len() as u32).to_le_bytes());
+        out.extend_from_slice(self.scene.as_bytes());
+        out.extend_from_slice(&(self.accumulator.width as u32).to_le_bytes());
+        out.extend_from_slice(&(self.accumulator.height as u32).to_le_bytes());
+        out.extend_from_slice(&self.max_depth.to_le_bytes());
+        out.extend_from_slice(&self.seed.to_le_bytes());
+        out.push(self.sampler as u8);
+        out.push(self.spectral as u8);
+        out.extend_from_slice(&self.samples_per_pixel.to_le_bytes());
+        out.push(self.tonemap.operator as u8);
+        out.extend_from_slice(&self.tonemap.exposure.to_le_bytes());
+        out.extend_from_slice(&self.tonemap.white.to_le_bytes());
+        out.extend_from_slice(&(self.outputs.len() as u32).to_le_bytes());
+        for output in &self.outputs {
+            out.extend_from_slice(&(output.len() as u32).to_le_bytes());
+            out.extend_from_slice(output.as_bytes());
+        }
+        for p in &self.accumulator.pixels {
+            for x in &[
+                p.sum.x,
+                p.sum.y,
+                p.sum.z,
+                p.luminance_sum,
+                p.luminance_squared_sum,
+            ] {
+                out.extend_from_slice(&x.to_le_bytes());
+            }
+            out.extend_from_slice(&p.samples.to_le_bytes());
+        }
+        out
+    }
+
+    // written next to the target first and renamed, a crash while saving keeps the previous checkpoint
+    pub fn save(&self, path: &str) -> Result<(), CheckpointError> {
+        let io_error = |err| CheckpointError::Io(path.to_string(), err);
+        let temporary = format!("{}.tmp", path);
+        let mut out = BufWriter::new(File::create(&temporary).map_err(io_error)?);
+        out.write_all(&self.to_bytes()).map_err(io_error)?;
+        out.flush().map_err(io_error)?;
+        drop(out);
+        fs::rename(&temporary, path).map_err(io_error)
+    }
+
+    pub fn load(path: &str) -> Result<Self, CheckpointError> {
+        let data = fs::read(path).map_err(|err| CheckpointError::Io(path.to_string(), err))?;
+        let mut reader = Reader {
+            data: &data,
+            offset: 0,
+            path,
+        };
+        if reader.bytes(MAGIC.len())? != MAGIC {
+            return reader.invalid("not a checkpoint file");
+        }
+        let version = reader.u32()?;
+        if version != VERSION {
+            return reader.invalid(&format!("unsupported version {}", version));
+        }
+        let scene = reader.string("scene path")?;
+        let width = reader.u32()? as usize;
+        let height = reader.u32()? as usize;
+        let max_depth = reader.u32()? as i32;
+        let seed = reader.u64()?;
+        let sampler = match reader.bytes(1)?[0] {
+            0 => SamplerKind::Independent,
+            1 => SamplerKind::Stratified,
+            2 => SamplerKind::Halton,
+            3 => SamplerKind::Sobol,
+            id => return reader.invalid(&format!("unknown sampler {}", id)),
+        };
+        let spectral = match reader.bytes(1)?[0] {
+            0 => false,
+            1 => true,
+            flag => return reader.invalid(&format!("invalid spectral flag {}", flag)),
+        };
+        let samples_per_pixel = reader.u32()? as i32;
+        let operator = match reader.bytes(1)?[0] {
+            0 => ToneMapper::Clamp,
+            1 => ToneMapper::Reinhard,
+            2 => ToneMapper::ExtendedReinhard,
+            3 => ToneMapper::Aces,
+            4 => ToneMapper::Uncharted2,
+            id => return reader.invalid(&format!("unknown tone mapper {}", id)),
+        };
+        let tonemap = ToneMapping {
+            operator,
+            exposure: reader.f64()?,
+            white: reader.f64()?,
+        };
+        let output_count = reader.u32()?;
+        let mut outputs = Vec::new();
+        for _ in 0..output_count {
+            outputs.push(reader.string("output path")?);
+        }
+        if width == 0 || height == 0 || max_depth <= 0 || samples_per_pixel <= 0 {
+            return reader.invalid("image size, depth and samples per pixel must be positive");
+        }
+        let expected = width
+            .checked_mul(height)
+            .and_then(|n| n.checked_mul(PIXEL_SIZE));
+        if expected != Some(data.len() - reader.offset) {
+            return reader.invalid("pixel data does not match the image size");
+        }
+        let mut accumulator = Accumulator::new(width, height);
+        for pixel in accumulator.pixels.iter_mut() {
+            *pixel = PixelStats {
+                sum: Vec3::new(reader.f64()?, reader.f64()?, reader.f64()?),
+                luminance_sum: reader.f64()?,
+                luminance_squared_sum: reader.f64()?,
+                samples: reader.u32()?,
+            };
+        }
+        Ok(Self {
+            scene,
+            max_depth,
+            seed,
+            sampler,
+            spectral,
+            samples_per_pixel,
+            tonemap,
+            outputs,
+            accumulator,
+        })
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+    path: &'a str,
+}
+impl<'a> Reader<'a> {
+    fn invalid<T>(&self, message: &str) -> Result<T, CheckpointError> {
+        Err(CheckpointError::Invalid(
+            self.path.to_string(),
+            message.to_string(),
+        ))
+    }
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], CheckpointError> {
+        if self.data.len() - self.offset < n {
+            return self.invalid("file is truncated");
+        }
+        let bytes = &self.data[self.offset..self.offset + n];
+        self.offset += n;
+        Ok(bytes)
+    }
+    fn u32(&mut self) -> Result<u32, CheckpointError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+    fn u64(&mut self) -> Result<u64, CheckpointError> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+    fn f64(&mut self) -> Result<f64, CheckpointError> {
+        Ok(f64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+    // a UTF-8 string after its length in bytes
+    fn string(&mut self, what: &str) -> Result<String, CheckpointError> {
+        let len = self.u32()? as usize;
+        match String::from_utf8(self.bytes(len)?.to_vec()) {
+            Ok(s) => Ok(s),
+            Err(_) => self.invalid(&format!("{} is not UTF-8", what)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn temporary(name: &str) -> String {
+        let path = env::temp_dir().join(format!("raytracer-{}-{}", process::id(), name));
+        path.to_string_lossy().into_owned()
+    }
+    fn checkpoint() -> Checkpoint {
+        let settings = RenderSettings::parse(
+            [
+                "--spp=64",
+                "--seed=9",
+                "--sampler=halton",
+                "--tonemap=aces",
+                "--exposure=-1.5",
+                "--output=a.png",
+                "--output=a.exr",
+            ]
+            .iter()
+            .map(|arg| arg.to_string()),
+        )
+        .unwrap();
+        let mut accumulator = Accumulator::new(3, 2);
+        for (i, pixel) in accumulator.pixels.iter_mut().enumerate() {
+            for _ in 0..i {
+                pixel.add(Vec3::new(i as f64, 0.5, 0.25));
+            }
+        }
+        Checkpoint::new(&settings, accumulator)
+    }
+
+    #[test]
+    fn saved_checkpoints_load_unchanged() {
+        let path = temporary("round-trip.ckpt");
+        let saved = checkpoint();
+        saved.save(&path).unwrap();
+        let loaded = Checkpoint::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), saved);
+    }
+
+    #[test]
+    fn truncated_checkpoints_are_invalid() {
+        let path = temporary("truncated.ckpt");
+        let bytes = checkpoint().to_bytes();
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        let message = Checkpoint::load(&path).err().unwrap().to_string();
+        fs::remove_file(&path).unwrap();
+        assert!(message.ends_with("pixel data does not match the image size"));
+    }
+
+    #[test]
+    fn the_command_line_overrides_the_stored_target_and_outputs() {
+        let args = ["--resume=a.ckpt", "--spp=256", "--white=2"];
+        let mut settings = RenderSettings::parse(args.iter().map(|arg| arg.to_string())).unwrap();
+        checkpoint().apply(&mut settings);
+        assert_eq!((settings.width, settings.height, settings.seed), (3, 2, 9));
+        assert_eq!(settings.samples_per_pixel, 256);
+        assert_eq!(settings.outputs, vec!["a.png", "a.exr"]);
+        assert_eq!(settings.tonemap.operator, ToneMapper::Aces);
+        assert_eq!(settings.tonemap.exposure, -1.5);
+        assert_eq!(settings.tonemap.white, 2.0);
+    }
+}
@@ -0,0 +1,363 @@
+use crate::progressive::PassSettings;
+use crate::sampler::SamplerKind;
+use crate::scheduler::TileOrder;
+use crate::tonemap::{ToneMapper, ToneMapping};
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+
+pub const USAGE: &str = "Usage: raytracer [OPTIONS]
+
+Options:
+    --scene <PATH>      scene description file [default: scenes/cornell_box.toml]
+    --output <PATH>     output image path, may be repeated; .exr and .hdr keep
+                        linear HDR values [default: output/test.png]
+    --width <N>         image width in pixels [default: 600]
+    --height <N>        image height in pixels [default: 600]
+    --spp <N>           samples per pixel, the maximum when rendering progressively
+                        [default: 1000]
+    --depth <N>         maximum ray depth [default: 50]
+    --threads <N>       worker threads [default: 6]
+    --tile-size <N>     edge length of the square tiles in pixels [default: 32]
+    --tile-order <NAME> scanline, spiral or hilbert [default: spiral]
+    --seed <N>          random seed [default: 0]
+    --sampler <NAME>    independent, stratified, halton or sobol [default: sobol]
+    --spectral          trace spectra instead of RGB, colors are upsampled and
+                        dispersive dielectrics split light into colors
+    --tonemap <NAME>    tone mapper for 8-bit outputs: clamp, reinhard,
+                        reinhard-extended, aces or uncharted2 [default: clamp]
+    --exposure <STOPS>  exposure applied before tone mapping [default: 0]
+    --white <L>         white point of reinhard-extended [default: 4]
+    --progressive       render in passes and update the outputs after each one
+    --pass-spp <N>      samples per pixel in each progressive pass [default: 16]
+    --time <SECONDS>    stop before exceeding this time budget, implies --progressive
+    --noise-threshold <E>
+                        adaptive sampling, pixels stop once the relative error of
+                        their neighbourhood is below E, implies --progressive
+    --min-spp <N>       samples a pixel takes before it may stop [default: 16]
+    --checkpoint <PATH> save the render state after passes, implies --progressive
+    --checkpoint-interval <SECONDS>
+                        minimum time between two checkpoints [default: 60]
+    --resume <PATH>     continue a checkpointed render, the scene, size, depth,
+                        seed, sampler and spectral mode are taken from the checkpoint,
+                        and so are the spp, outputs and tone mapping unless given
+    --benchmark         time the recursive and the flat BVH on the camera rays of
+                        --width x --height x --spp samples and one bounce from each
+                        of their hits, instead of rendering
+    -h, --help          print this message";
+
+const OPTIONS: [&str; 21] = [
+    "--scene",
+    "--output",
+    "--width",
+    "--height",
+    "--spp",
+    "--depth",
+    "--threads",
+    "--tile-size",
+    "--tile-order",
+    "--seed",
+    "--sampler",
+    "--tonemap",
+    "--exposure",
+    "--white",
+    "--pass-spp",
+    "--time",
+    "--noise-threshold",
+    "--min-spp",
+    "--checkpoint",
+    "--checkpoint-interval",
+    "--resume",
+];
+const FLAGS: [&str; 3] = ["--progressive", "--spectral", "--benchmark"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RenderSettings {
+    pub scene: String,
+    pub outputs: Vec<String>,
+    pub width: i32,
+    pub height: i32,
+    pub samples_per_pixel: i32,
+    pub max_depth: i32,
+    pub threads: usize,
+    pub tile_size: usize,
+    pub tile_order: TileOrder,
+    pub seed: u64,
+    pub sampler: SamplerKind,
+    pub spectral: bool,
+    pub tonemap: ToneMapping,
+    pub progressive: bool,
+    pub pass_spp: i32,
+    pub time_limit: Option<f64>,
+    pub noise_threshold: Option<f64>,
+    pub min_spp: i32,
+    pub checkpoint: Option<String>,
+    pub checkpoint_interval: f64,
+    pub resume: Option<String>,
+    pub benchmark: bool,
+    // the options that were given a value on the command line
+    pub given: Vec<String>,
+}
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            scene: "scenes/cornell_box.toml".to_string(),
+            outputs: vec!["output/test.png".to_string()],
+            width: 600,
+            height: 600,
+            samples_per_pixel: 1000,
+            max_depth: 50,
+            threads: 6,
+            tile_size: 32,
+            tile_order: TileOrder::Spiral,
+            seed: 0,
+            sampler: SamplerKind::Sobol,
+            spectral: false,
+            tonemap: ToneMapping::default(),
+            progressive: false,
+            pass_spp: 16,
+            time_limit: None,
+            noise_threshold: None,
+            min_spp: 16,
+            checkpoint: None,
+            checkpoint_interval: 60.0,
+            resume: None,
+            benchmark: false,
+            given: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CliError {
+    Help,
+    MissingValue(String),
+    InvalidValue(String, String),
+    UnknownOption(String),
+}
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Help => write!(f, "{}", USAGE),
+            CliError::MissingValue(option) => write!(f, "option `{}` requires a value", option),
+            CliError::InvalidValue(option, value) => {
+                write!(f, "invalid value `{}` for option `{}`", value, option)
+            }
+            CliError::UnknownOption(option) => write!(f, "unknown option `{}`", option),
+        }
+    }
+}
+impl std::error::Error for CliError {}
+
+fn parse_value<T: FromStr>(option: &str, value: &str) -> Result<T, CliError> {
+    match value.parse() {
+        Ok(x) => Ok(x),
+        Err(_) => Err(CliError::InvalidValue(
+            option.to_string(),
+            value.to_string(),
+        )),
+    }
+}
+fn parse_positive<T: FromStr + PartialOrd + Default>(
+    option: &str,
+    value: &str,
+) -> Result<T, CliError> {
+    let x: T = parse_value(option, value)?;
+    if x > T::default() {
+        Ok(x)
+    } else {
+        Err(CliError::InvalidValue(
+            option.to_string(),
+            value.to_string(),
+        ))
+    }
+}
+
+fn parse_finite(option: &str, value: &str) -> Result<f64, CliError> {
+    let x: f64 = parse_value(option, value)?;
+    if x.is_finite() {
+        Ok(x)
+    } else {
+        Err(CliError::InvalidValue(
+            option.to_string(),
+            value.to_string(),
+        ))
+    }
+}
+
+impl RenderSettings {
+    pub fn from_env() -> Result<Self, CliError> {
+        Self::parse(env::args().skip(1))
+    }
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, CliError> {
+        let mut settings = Self::default();
+        let mut default_output = true;
+        while let Some(arg) = args.next() {
+            if arg == "-h" || arg == "--help" {
+                return Err(CliError::Help);
+            }
+            let (option, inline_value) = match arg.find('=') {
+                Some(i) if arg.starts_with("--") => {
+                    (arg[..i].to_string(), Some(arg[i + 1..].to_string()))
+                }
+                _ => (arg.clone(), None),
+            };
+            if FLAGS.contains(&option.as_str()) {
+                if let Some(value) = inline_value {
+                    return Err(CliError::InvalidValue(option, value));
+                }
+                match option.as_str() {
+                    "--progressive" => settings.progressive = true,
+                    "--spectral" => settings.spectral = true,
+                    _ => settings.benchmark = true,
+                }
+                continue;
+            }
+            if !OPTIONS.contains(&option.as_str()) {
+                return Err(CliError::UnknownOption(option));
+            }
+            let value = match inline_value.or_else(|| args.next()) {
+                Some(value) => value,
+                None => return Err(CliError::MissingValue(option)),
+            };
+            settings.given.push(option.clone());
+            match option.as_str() {
+                "--scene" => settings.scene = value,
+                "--output" => {
+                    if default_output {
+                        settings.outputs.clear();
+                        default_output = false;
+                    }
+                    settings.outputs.push(value);
+                }
+                "--width" => settings.width = parse_positive(&option, &value)?,
+                "--height" => settings.height = parse_positive(&option, &value)?,
+                "--spp" => settings.samples_per_pixel = parse_positive(&option, &value)?,
+                "--depth" => settings.max_depth = parse_positive(&option, &value)?,
+                "--threads" => settings.threads = parse_positive(&option, &value)?,
+                "--tile-size" => settings.tile_size = parse_positive(&option, &value)?,
+                "--tile-order" => settings.tile_order = parse_value(&option, &value)?,
+                "--seed" => settings.seed = parse_value(&option, &value)?,
+                "--sampler" => settings.sampler = parse_value(&option, &value)?,
+                "--tonemap" => {
+                    settings.tonemap.operator = parse_value::<ToneMapper>(&option, &value)?
+                }
+                "--exposure" => settings.tonemap.exposure = parse_finite(&option, &value)?,
+                "--white" => settings.tonemap.white = parse_positive(&option, &value)?,
+                "--pass-spp" => settings.pass_spp = parse_positive(&option, &value)?,
+                "--time" => settings.time_limit = Some(parse_positive(&option, &value)?),
+                "--noise-threshold" => {
+                    settings.noise_threshold = Some(parse_positive(&option, &value)?)
+                }
+                "--min-spp" => settings.min_spp = parse_positive(&option, &value)?,
+                "--checkpoint" => settings.checkpoint = Some(value),
+                "--checkpoint-interval" => {
+                    settings.checkpoint_interval = parse_finite(&option, &value)?.max(0.0)
+                }
+                "--resume" => settings.resume = Some(value),
+                _ => return Err(CliError::UnknownOption(option)),
+            }
+        }
+        // a resumed render keeps updating the checkpoint it started from
+        if settings.checkpoint.is_none() {
+            settings.checkpoint = settings.resume.clone();
+        }
+        if settings.time_limit.is_some()
+            || settings.noise_threshold.is_some()
+            || settings.checkpoint.is_some()
+        {
+            settings.progressive = true;
+        }
+        Ok(settings)
+    }
+    pub fn pass_settings(&self) -> PassSettings {
+        PassSettings {
+            pass_spp: if self.progressive {
+                self.pass_spp as u32
+            } else {
+                self.samples_per_pixel as u32
+            },
+            max_spp: self.samples_per_pixel as u32,
+            noise_threshold: self.noise_threshold,
+            min_spp: self.min_spp as u32,
+        }
+    }
+    pub fn is_given(&self, option: &str) -> bool {
+        self.given.iter().any(|given| given == option)
+    }
+    pub fn aspect_ratio(&self) -> f64 {
+        self.width as f64 / self.height as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<RenderSettings, CliError> {
+        RenderSettings::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn no_arguments_give_the_defaults() {
+        assert_eq!(parse(&[]), Ok(RenderSettings::default()));
+    }
+
+    #[test]
+    fn values_follow_the_option_or_an_equals_sign() {
+        let settings = parse(&["--width", "320", "--height=240", "--scene=a.toml"]).unwrap();
+        assert_eq!(settings.width, 320);
+        assert_eq!(settings.height, 240);
+        assert_eq!(settings.scene, "a.toml");
+    }
+
+    #[test]
+    fn outputs_replace_the_default_and_accumulate() {
+        let settings = parse(&["--output", "a.png", "--output", "a.exr"]).unwrap();
+        assert_eq!(settings.outputs, vec!["a.png", "a.exr"]);
+    }
+
+    #[test]
+    fn stopping_criteria_imply_progressive_rendering() {
+        assert!(!parse(&[]).unwrap().progressive);
+        assert!(parse(&["--time", "10"]).unwrap().progressive);
+        assert!(parse(&["--noise-threshold", "0.01"]).unwrap().progressive);
+        let settings = parse(&["--resume", "render.ckpt"]).unwrap();
+        assert!(settings.progressive);
+        assert_eq!(settings.checkpoint.as_deref(), Some("render.ckpt"));
+    }
+
+    #[test]
+    fn bad_arguments_are_reported() {
+        assert_eq!(parse(&["-h"]), Err(CliError::Help));
+        assert_eq!(
+            parse(&["--frobnicate"]),
+            Err(CliError::UnknownOption("--frobnicate".to_string()))
+        );
+        assert_eq!(
+            parse(&["--spp"]),
+            Err(CliError::MissingValue("--spp".to_string()))
+        );
+        for args in &[
+            ["--spp", "0"],
+            ["--width", "-4"],
+            ["--threads", "many"],
+            ["--exposure", "inf"],
+        ] {
+            assert_eq!(
+                parse(args),
+                Err(CliError::InvalidValue(
+                    args[0].to_string(),
+                    args[1].to_string()
+                ))
+            );
+        }
+        assert_eq!(
+            parse(&["--spectral=yes"]),
+            Err(CliError::InvalidValue(
+                "--spectral".to_string(),
+                "yes".to_string()
+            ))
+        );
+    }
+}
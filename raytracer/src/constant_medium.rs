@@ -35,17 +35,8 @@ impl <T:Hittable, U:Texture> ConstantMedium<T,U> {
 }
 impl<T: Hittable, U: Texture> Hittable for ConstantMedium<T, U> {
     fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        if let None = self.boundary.hit(r, -f64::INFINITY, f64::INFINITY) {
-            return None;
-        }
-        let mut rec1 = self.boundary.hit(r, -f64::INFINITY, f64::INFINITY).unwrap();
-        if let None = self.boundary.hit(r, rec1.t + 0.0001, f64::INFINITY) {
-            return None;
-        }
-        let mut rec2 = self
-            .boundary
-            .hit(r, rec1.t + 0.0001, f64::INFINITY)
-            .unwrap();
+        let mut rec1 = self.boundary.hit(r, -f64::INFINITY, f64::INFINITY)?;
+        let mut rec2 = self.boundary.hit(r, rec1.t + 0.0001, f64::INFINITY)?;
         //if(debugging) cout<<"t_min"<<rec1.t<<"t_max"<<rec2.t;
         if rec1.t < t_min {
             rec1.t = t_min;
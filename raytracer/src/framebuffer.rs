@@ -0,0 +1,254 @@
+use crate::tonemap::ToneMapping;
+use crate::Vec3;
+use image::{ImageBuffer, RgbImage};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::vec::Vec;
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io(String, io::Error),
+    Image(String, image::ImageError),
+}
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveError::Io(path, err) => write!(f, "cannot write `{}`: {}", path, err),
+            SaveError::Image(path, err) => write!(f, "cannot write `{}`: {}", path, err),
+        }
+    }
+}
+impl std::error::Error for SaveError {}
+
+// linear radiance per pixel, rows are stored top to bottom
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrameBuffer {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Vec3>,
+}
+impl FrameBuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![Vec3::zero(); width * height],
+        }
+    }
+    pub fn get(&self, x: usize, y: usize) -> Vec3 {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn to_rgb8(&self, tonemap: &ToneMapping) -> RgbImage {
+        let mut img: RgbImage = ImageBuffer::new(self.width as u32, self.height as u32);
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            *pixel = image::Rgb(tonemap.srgb8(self.get(x as usize, y as usize)));
+        }
+        img
+    }
+
+    // picks the format from the extension, anything that is not .exr or .hdr goes through the image crate
+    // and is tone mapped, the HDR formats keep the linear radiance
+    pub fn save(&self, path: &str, tonemap: &ToneMapping) -> Result<(), SaveError> {
+        let extension = Path::new(path)
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase());
+        let io_result = match extension.as_deref() {
+            Some("exr") => self.write_exr(path),
+            Some("hdr") => self.write_hdr(path),
+            _ => {
+                return self
+                    .to_rgb8(tonemap)
+                    .save(path)
+                    .map_err(|err| SaveError::Image(path.to_string(), err))
+            }
+        };
+        io_result.map_err(|err| SaveError::Io(path.to_string(), err))
+    }
+
+    // single part scanline OpenEXR, uncompressed 32-bit float channels
+    pub fn write_exr(&self, path: &str) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        let (width, height) = (self.width as i32, self.height as i32);
+        let mut header: Vec<u8> = Vec::new();
+        header.extend_from_slice(&20000630i32.to_le_bytes());
+        header.extend_from_slice(&2i32.to_le_bytes());
+
+        // channels have to be listed in alphabetical order
+        let mut channels: Vec<u8> = Vec::new();
+        for name in &[b"B", b"G", b"R"] {
+            channels.extend_from_slice(*name);
+            channels.push(0);
+            channels.extend_from_slice(&2i32.to_le_bytes()); // FLOAT
+            channels.extend_from_slice(&[0, 0, 0, 0]); // pLinear and reserved
+            channels.extend_from_slice(&1i32.to_le_bytes());
+            channels.extend_from_slice(&1i32.to_le_bytes());
+        }
+        channels.push(0);
+        let mut window: Vec<u8> = Vec::new();
+        for v in &[0, 0, width - 1, height - 1] {
+            window.extend_from_slice(&v.to_le_bytes());
+        }
+        let mut screen_center: Vec<u8> = Vec::new();
+        screen_center.extend_from_slice(&0f32.to_le_bytes());
+        screen_center.extend_from_slice(&0f32.to_le_bytes());
+        let attributes: [(&str, &str, &[u8]); 8] = [
+            ("channels", "chlist", &channels),
+            ("compression", "compression", &[0]),
+            ("dataWindow", "box2i", &window),
+            ("displayWindow", "box2i", &window),
+            ("lineOrder", "lineOrder", &[0]),
+            ("pixelAspectRatio", "float", &1f32.to_le_bytes()),
+            ("screenWindowCenter", "v2f", &screen_center),
+            ("screenWindowWidth", "float", &1f32.to_le_bytes()),
+        ];
+        for (name, kind, value) in attributes.iter() {
+            header.extend_from_slice(name.as_bytes());
+            header.push(0);
+            header.extend_from_slice(kind.as_bytes());
+            header.push(0);
+            header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+            header.extend_from_slice(value);
+        }
+        header.push(0);
+        out.write_all(&header)?;
+
+        let line_size = self.width * 3 * 4;
+        let chunk_size = (4 + 4 + line_size) as u64;
+        let first_chunk = (header.len() + self.height * 8) as u64;
+        for y in 0..self.height as u64 {
+            out.write_all(&(first_chunk + y * chunk_size).to_le_bytes())?;
+        }
+        let mut line: Vec<u8> = Vec::with_capacity(line_size);
+        for y in 0..self.height {
+            line.clear();
+            let row = &self.pixels[y * self.width..(y + 1) * self.width];
+            for channel in 0..3 {
+                for color in row {
+                    let c = match channel {
+                        0 => color.z,
+                        1 => color.y,
+                        _ => color.x,
+                    };
+                    line.extend_from_slice(&(c as f32).to_le_bytes());
+                }
+            }
+            out.write_all(&(y as i32).to_le_bytes())?;
+            out.write_all(&(line_size as i32).to_le_bytes())?;
+            out.write_all(&line)?;
+        }
+        out.flush()
+    }
+
+    // Radiance RGBE, written as flat (not run length encoded) scanlines
+    pub fn write_hdr(&self, path: &str) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        write!(
+            out,
+            "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+            self.height, self.width
+        )?;
+        for color in &self.pixels {
+            out.write_all(&to_rgbe(*color))?;
+        }
+        out.flush()
+    }
+}
+
+fn to_rgbe(color: Vec3) -> [u8; 4] {
+    let color = Vec3::new(color.x.max(0.0), color.y.max(0.0), color.z.max(0.0));
+    let brightest = color.x.max(color.y).max(color.z);
+    if !brightest.is_finite() || brightest < 1e-32 {
+        return [0, 0, 0, 0];
+    }
+    // brightest = mantissa * 2^exponent with the mantissa in [0.5, 1)
+    let mut exponent = brightest.log2().floor() as i32 + 1;
+    if brightest / 2f64.powi(exponent) >= 1.0 {
+        exponent += 1;
+    }
+    let scale = 256.0 / 2f64.powi(exponent);
+    [
+        (color.x * scale) as u8,
+        (color.y * scale) as u8,
+        (color.z * scale) as u8,
+        (exponent + 128).clamp(0, 255) as u8,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    // writes the frame with `write` to a temporary file and reads the bytes back
+    fn written(
+        frame: &FrameBuffer,
+        name: &str,
+        write: fn(&FrameBuffer, &str) -> io::Result<()>,
+    ) -> Vec<u8> {
+        let path = env::temp_dir().join(format!("raytracer-{}-{}", process::id(), name));
+        let path = path.to_string_lossy();
+        write(frame, &path).unwrap();
+        let bytes = fs::read(&*path).unwrap();
+        fs::remove_file(&*path).unwrap();
+        bytes
+    }
+    fn gradient() -> FrameBuffer {
+        let mut frame = FrameBuffer::new(3, 2);
+        for (i, pixel) in frame.pixels.iter_mut().enumerate() {
+            *pixel = Vec3::new(i as f64, 0.5, 0.25);
+        }
+        frame
+    }
+
+    #[test]
+    fn rgbe_shares_the_exponent_of_the_brightest_channel() {
+        assert_eq!(to_rgbe(Vec3::new(1.0, 0.5, 0.0)), [128, 64, 0, 129]);
+        assert_eq!(to_rgbe(Vec3::new(0.75, 0.75, 0.75)), [192, 192, 192, 128]);
+        assert_eq!(to_rgbe(Vec3::new(-1.0, 0.0, 0.0)), [0, 0, 0, 0]);
+        assert_eq!(to_rgbe(Vec3::new(f64::NAN, 1.0, 1.0)), [0, 128, 128, 129]);
+        assert_eq!(to_rgbe(Vec3::new(f64::INFINITY, 1.0, 1.0)), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn hdr_is_a_header_and_one_rgbe_per_pixel() {
+        let frame = gradient();
+        let bytes = written(&frame, "frame.hdr", FrameBuffer::write_hdr);
+        let header = "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 2 +X 3\n";
+        assert!(bytes.starts_with(header.as_bytes()));
+        assert_eq!(bytes.len(), header.len() + 6 * 4);
+        assert_eq!(
+            bytes[header.len() + 4..header.len() + 8],
+            to_rgbe(frame.get(1, 0))
+        );
+    }
+
+    #[test]
+    fn exr_line_offsets_point_at_the_scanlines() {
+        let frame = gradient();
+        let bytes = written(&frame, "frame.exr", FrameBuffer::write_exr);
+        let word = |at: usize| {
+            i32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+        };
+        assert_eq!(word(0), 20000630);
+        assert_eq!(word(4), 2);
+        let line_size = 3 * 3 * 4;
+        let header_size = bytes.len() - 2 * 8 - 2 * (8 + line_size);
+        for y in 0..2 {
+            let offset = &bytes[header_size + y * 8..header_size + y * 8 + 8];
+            let offset = u64::from_le_bytes(offset.try_into().unwrap()) as usize;
+            assert_eq!(offset, header_size + 2 * 8 + y * (8 + line_size));
+            assert_eq!(word(offset), y as i32);
+            assert_eq!(word(offset + 4), line_size as i32);
+        }
+        // the channels of a line are blue, green and red, each for the whole line
+        let second_line = header_size + 2 * 8 + 8 + line_size + 8;
+        let red = &bytes[second_line + 2 * 3 * 4..second_line + 2 * 3 * 4 + 4];
+        assert_eq!(f32::from_le_bytes(red.try_into().unwrap()), 3.0);
+    }
+}
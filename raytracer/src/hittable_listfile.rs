@@ -1,5 +1,5 @@
 use crate::aabb::surrounding_box;
-use crate::rtweekend::random_i32;
+use crate::sampler::sample_1d;
 use crate::HitRecord;
 use crate::Hittable;
 use crate::Ray;
@@ -24,28 +24,15 @@ impl HittableList {
 
 impl Hittable for HittableList {
     fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        let mut hit_anything: bool = false;
         let mut closest_so_far: f64 = t_max;
-        let mut i: i32 = -1;
-        let mut j = 0;
-        let mut temt = 0.0;
+        let mut closest_hit = None;
         for object in &self.objects {
-            i += 1;
-            if let None = object.hit(r, t_min, closest_so_far) {
-                continue;
-            } else {
-                let temp_rec = object.hit(r, t_min, closest_so_far).unwrap();
-                hit_anything = true;
-                temt = closest_so_far;
-                closest_so_far = temp_rec.t;
-                j = i;
+            if let Some(rec) = object.hit(r, t_min, closest_so_far) {
+                closest_so_far = rec.t;
+                closest_hit = Some(rec);
             }
         }
-        if hit_anything {
-            return self.objects[j as usize].hit(r, t_min, temt);
-        } else {
-            None
-        }
+        closest_hit
     }
     fn bounding_box(&self, time0: f64, time1: f64, output_box: &mut crate::aabb::AABB) -> bool {
         if self.objects.is_empty() {
@@ -66,17 +53,18 @@ impl Hittable for HittableList {
         }
         true
     }
-    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
         let weight = 1.0 / (self.objects.len() as f64);
         let mut sum = 0.0;
         for object in &self.objects {
-            sum += weight * object.pdf_value(o, v);
+            sum += weight * object.pdf_value(o, v, time);
         }
         sum
     }
-    fn random(&self, o: Vec3) -> Vec3 {
-        let int_size = self.objects.len() as i32;
-        self.objects[random_i32(0, int_size - 1) as usize].random(o)
+    fn random(&self, o: Vec3, time: f64) -> Vec3 {
+        let size = self.objects.len();
+        let i = ((sample_1d() * size as f64) as usize).min(size - 1);
+        self.objects[i].random(o, time)
     }
 }
 unsafe impl Sync for HittableList {}
@@ -1,8 +1,13 @@
+use crate::aabb::surrounding_box;
+use crate::mat4::Mat4;
 use crate::materialfile::Material;
-use crate::rtweekend::{degrees_to_radians, fmax, fmin};
+use crate::quat::Quat;
+use crate::rtweekend::{fmax, fmin};
 use crate::Ray;
 use crate::Vec3;
 use crate::AABB;
+use std::cmp::Ordering;
+use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct HitRecord<'a> {
@@ -28,132 +33,315 @@ impl<'a> HitRecord<'a> {
 pub trait Hittable {
     fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord>;
     fn bounding_box(&self, time0: f64, time1: f64, output_box: &mut AABB) -> bool;
-    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+    fn pdf_value(&self, _o: Vec3, _v: Vec3, _time: f64) -> f64 {
         0.0
     }
-    fn random(&self, o: Vec3) -> Vec3 {
+    fn random(&self, _o: Vec3, _time: f64) -> Vec3 {
         Vec3::new(1.0, 0.0, 0.0)
     }
 }
+impl<T: Hittable + ?Sized> Hittable for Box<T> {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        (**self).hit(r, t_min, t_max)
+    }
+    fn bounding_box(&self, time0: f64, time1: f64, output_box: &mut AABB) -> bool {
+        (**self).bounding_box(time0, time1, output_box)
+    }
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
+        (**self).pdf_value(o, v, time)
+    }
+    fn random(&self, o: Vec3, time: f64) -> Vec3 {
+        (**self).random(o, time)
+    }
+}
+impl<T: Hittable + ?Sized> Hittable for Arc<T> {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        (**self).hit(r, t_min, t_max)
+    }
+    fn bounding_box(&self, time0: f64, time1: f64, output_box: &mut AABB) -> bool {
+        (**self).bounding_box(time0, time1, output_box)
+    }
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
+        (**self).pdf_value(o, v, time)
+    }
+    fn random(&self, o: Vec3, time: f64) -> Vec3 {
+        (**self).random(o, time)
+    }
+}
+// Places an object with an affine transform. Rays are moved into object space without
+// normalising their direction, so hit distances are the same in both spaces, and normals are
+// brought back with the inverse transpose so they stay perpendicular under scaling and shear.
 #[derive(Clone)]
-pub struct Translate<T: Hittable> {
+pub struct Transform<T: Hittable> {
     ptr: T,
-    offset: Vec3,
+    to_world: Mat4,
+    to_object: Mat4,
+    normal_to_world: Mat4,
 }
-impl<T: Hittable> Translate<T> {
-    pub fn new(p: T, displacement: Vec3) -> Self {
-        Self {
+impl<T: Hittable> Transform<T> {
+    // None if the matrix can't be inverted
+    pub fn new(p: T, matrix: Mat4) -> Option<Self> {
+        let to_object = matrix.inverse()?;
+        Some(Self {
             ptr: p,
-            offset: displacement,
-        }
+            to_world: matrix,
+            to_object,
+            normal_to_world: to_object.transpose(),
+        })
     }
 }
-impl<T: Hittable> Hittable for Translate<T> {
-    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        let move_r: Ray = Ray::new(r.orig - self.offset, r.dir, r.tm);
-        if let None = self.ptr.hit(move_r, t_min, t_max) {
-            return None;
-        }
-        let mut rec = self.ptr.hit(move_r, t_min, t_max).unwrap();
-        rec.p += self.offset;
-        rec.set_face_normal(move_r, rec.normal);
+impl<T: Hittable> Hittable for Transform<T> {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let object_r = Ray::new(
+            self.to_object.transform_point(r.orig),
+            self.to_object.transform_vector(r.dir),
+            r.tm,
+        );
+        // the normal keeps its side of the surface relative to the ray, front_face stays valid
+        let mut rec = self.ptr.hit(object_r, t_min, t_max)?;
+        rec.p = self.to_world.transform_point(rec.p);
+        rec.normal = self.normal_to_world.transform_vector(rec.normal).unit();
         Some(rec)
     }
+    // the box around the eight transformed corners of the object's box
     fn bounding_box(&self, time0: f64, time1: f64, output_box: &mut AABB) -> bool {
-        if !self.ptr.bounding_box(time0, time1, output_box) {
+        let mut bbox = AABB::new(Vec3::zero(), Vec3::zero());
+        if !self.ptr.bounding_box(time0, time1, &mut bbox) {
             return false;
         }
-        *output_box = AABB::new(
-            output_box.minimum + self.offset,
-            output_box.maximum + self.offset,
-        );
+        *output_box = points_box(&transformed_corners(&bbox, &self.to_world));
         true
     }
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
+        transformed_pdf_value(&self.ptr, &self.to_object, o, v, time)
+    }
+    fn random(&self, o: Vec3, time: f64) -> Vec3 {
+        let direction = self.ptr.random(self.to_object.transform_point(o), time);
+        self.to_world.transform_vector(direction)
+    }
+}
+
+// The density of `ptr` sampling the direction `v` from `o`, both in world space, for a child
+// that samples in the object space of `to_object`. A direction w maps to B w with B the linear
+// part of `to_object`, which changes the density of unit directions by |det B| / |B w|^3.
+fn transformed_pdf_value<T: Hittable>(
+    ptr: &T,
+    to_object: &Mat4,
+    o: Vec3,
+    v: Vec3,
+    time: f64,
+) -> f64 {
+    let object_v = to_object.transform_vector(v);
+    let pdf = ptr.pdf_value(to_object.transform_point(o), object_v, time);
+    if pdf == 0.0 {
+        return 0.0;
+    }
+    let stretch = v.length() / object_v.length();
+    pdf * to_object.linear_determinant().abs() * stretch * stretch * stretch
 }
-#[derive(Clone)]
-pub struct Rotatey<T: Hittable> {
-    ptr: T,
-    sin_theta: f64,
-    cos_theta: f64,
-    hasbox: bool,
-    bbox: AABB,
-}
-impl<T: Hittable> Rotatey<T> {
-    pub fn new(p: T, angle: f64) -> Self {
-        let radians: f64 = degrees_to_radians(angle);
-        let sin_theta = f64::sin(radians);
-        let cos_theta = f64::cos(radians);
-        let mut bbox = AABB::new(Vec3::zero(), Vec3::zero());
-        let hasbox = p.bounding_box(0.0, 1.0, &mut bbox);
 
-        let mut min: Vec3 = Vec3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY);
-        let mut max: Vec3 = Vec3::new(-f64::INFINITY, -f64::INFINITY, -f64::INFINITY);
+fn transformed_corners(bbox: &AABB, matrix: &Mat4) -> Vec<Vec3> {
+    (0..8)
+        .map(|i| {
+            let corner = Vec3::new(
+                if i & 1 == 0 {
+                    bbox.minimum.x
+                } else {
+                    bbox.maximum.x
+                },
+                if i & 2 == 0 {
+                    bbox.minimum.y
+                } else {
+                    bbox.maximum.y
+                },
+                if i & 4 == 0 {
+                    bbox.minimum.z
+                } else {
+                    bbox.maximum.z
+                },
+            );
+            matrix.transform_point(corner)
+        })
+        .collect()
+}
+fn points_box(points: &[Vec3]) -> AABB {
+    let mut min = Vec3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY);
+    let mut max = Vec3::new(-f64::INFINITY, -f64::INFINITY, -f64::INFINITY);
+    for p in points {
+        min = Vec3::new(fmin(min.x, p.x), fmin(min.y, p.y), fmin(min.z, p.z));
+        max = Vec3::new(fmax(max.x, p.x), fmax(max.y, p.y), fmax(max.z, p.z));
+    }
+    AABB::new(min, max)
+}
 
-        for i in 0..2 {
-            for j in 0..2 {
-                for k in 0..2 {
-                    let x = i as f64 * bbox.maximum.x + (1.0 - i as f64) * bbox.minimum.x;
-                    let y = j as f64 * bbox.maximum.y + (1.0 - j as f64) * bbox.minimum.y;
-                    let z = k as f64 * bbox.maximum.z + (1.0 - k as f64) * bbox.minimum.z;
+// One pose of an animated transform, the object is scaled first, then rotated and then moved.
+#[derive(Clone, Copy, Debug)]
+pub struct Keyframe {
+    pub time: f64,
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+}
+impl Keyframe {
+    pub fn matrix(&self) -> Mat4 {
+        Mat4::translate(self.translation) * self.rotation.to_mat4() * Mat4::scale(self.scale)
+    }
+    // undoes the three steps in reverse order, no general inversion is needed
+    pub fn inverse_matrix(&self) -> Mat4 {
+        let s = self.scale;
+        Mat4::scale(Vec3::new(1.0 / s.x, 1.0 / s.y, 1.0 / s.z))
+            * self.rotation.conjugate().to_mat4()
+            * Mat4::translate(-self.translation)
+    }
+}
 
-                    let newx = cos_theta * x + sin_theta * z;
-                    let newz = -sin_theta * x + cos_theta * z;
+// Samples of the motion used for the bounding box, on top of the keyframes inside the interval.
+const MOTION_BOX_STEPS: usize = 64;
 
-                    let tester = Vec3::new(newx, y, newz);
-                    for c in 0..3 {
-                        if c == 0 {
-                            min.x = fmin(min.getcoordinate(c), tester.getcoordinate(c));
-                            max.x = fmax(max.getcoordinate(c), tester.getcoordinate(c));
-                        }
-                        if c == 1 {
-                            min.y = fmin(min.getcoordinate(c), tester.getcoordinate(c));
-                            max.y = fmax(max.getcoordinate(c), tester.getcoordinate(c));
-                        }
-                        if c == 2 {
-                            min.z = fmin(min.getcoordinate(c), tester.getcoordinate(c));
-                            max.z = fmax(max.getcoordinate(c), tester.getcoordinate(c));
-                        }
-                    }
+// A transform that changes over the shutter interval. The pose at the time of a ray is
+// interpolated between the keyframes around it, translation and scale linearly and the rotation
+// with slerp, and held at the first or last keyframe outside of them.
+#[derive(Clone)]
+pub struct AnimatedTransform<T: Hittable> {
+    ptr: T,
+    keyframes: Vec<Keyframe>,
+}
+impl<T: Hittable> AnimatedTransform<T> {
+    // None without keyframes or if a keyframe scales an axis to zero
+    pub fn new(p: T, mut keyframes: Vec<Keyframe>) -> Option<Self> {
+        if keyframes.is_empty()
+            || keyframes
+                .iter()
+                .any(|k| k.scale.x == 0.0 || k.scale.y == 0.0 || k.scale.z == 0.0)
+        {
+            return None;
+        }
+        keyframes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap_or(Ordering::Equal));
+        Some(Self { ptr: p, keyframes })
+    }
+    pub fn pose(&self, time: f64) -> Keyframe {
+        let frames = &self.keyframes;
+        match frames.iter().position(|k| k.time > time) {
+            None => frames[frames.len() - 1],
+            Some(0) => frames[0],
+            Some(i) => {
+                let (a, b) = (frames[i - 1], frames[i]);
+                let s = (time - a.time) / (b.time - a.time);
+                Keyframe {
+                    time,
+                    translation: a.translation + (b.translation - a.translation) * s,
+                    rotation: a.rotation.slerp(b.rotation, s),
+                    scale: a.scale + (b.scale - a.scale) * s,
                 }
             }
         }
-        bbox = AABB::new(min, max);
-        Self {
-            ptr: p,
-            sin_theta,
-            cos_theta,
-            hasbox,
-            bbox,
-        }
     }
 }
-impl<T: Hittable> Hittable for Rotatey<T> {
+impl<T: Hittable> Hittable for AnimatedTransform<T> {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let pose = self.pose(r.tm);
+        let to_object = pose.inverse_matrix();
+        let object_r = Ray::new(
+            to_object.transform_point(r.orig),
+            to_object.transform_vector(r.dir),
+            r.tm,
+        );
+        let mut rec = self.ptr.hit(object_r, t_min, t_max)?;
+        rec.p = pose.matrix().transform_point(rec.p);
+        rec.normal = to_object.transpose().transform_vector(rec.normal).unit();
+        Some(rec)
+    }
+    // the corners of the object's box are followed through the motion, between two samples a
+    // corner moves along an arc that strays from the straight line by less than half of the
+    // distance it covers, so every step's box is grown by that much
     fn bounding_box(&self, time0: f64, time1: f64, output_box: &mut AABB) -> bool {
-        *output_box = self.bbox;
-        self.hasbox
+        let mut bbox = AABB::new(Vec3::zero(), Vec3::zero());
+        if !self.ptr.bounding_box(time0, time1, &mut bbox) {
+            return false;
+        }
+        let mut times: Vec<f64> = (0..=MOTION_BOX_STEPS)
+            .map(|i| time0 + (time1 - time0) * i as f64 / MOTION_BOX_STEPS as f64)
+            .collect();
+        times.extend(
+            self.keyframes
+                .iter()
+                .map(|k| k.time)
+                .filter(|&time| time > time0 && time < time1),
+        );
+        times.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+
+        let mut previous = transformed_corners(&bbox, &self.pose(times[0]).matrix());
+        let mut motion_box = points_box(&previous);
+        for &time in &times[1..] {
+            let corners = transformed_corners(&bbox, &self.pose(time).matrix());
+            let pad = corners
+                .iter()
+                .zip(&previous)
+                .map(|(a, b)| (*a - *b).length())
+                .fold(0.0, fmax)
+                * 0.5;
+            let step_box = surrounding_box(points_box(&corners), points_box(&previous));
+            let pad = Vec3::new(pad, pad, pad);
+            motion_box = surrounding_box(
+                motion_box,
+                AABB::new(step_box.minimum - pad, step_box.maximum + pad),
+            );
+            previous = corners;
+        }
+        *output_box = motion_box;
+        true
     }
-    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        let mut origin = r.orig;
-        let mut direction = r.dir;
-        origin.x = self.cos_theta * r.orig.x - self.sin_theta * r.orig.z;
-        origin.z = self.sin_theta * r.orig.x + self.cos_theta * r.orig.z;
-        direction.x = self.cos_theta * r.dir.x - self.sin_theta * r.dir.z;
-        direction.z = self.sin_theta * r.dir.x + self.cos_theta * r.dir.z;
-        let rotated_r = Ray::new(origin, direction, r.tm);
-        if let None = self.ptr.hit(rotated_r, t_min, t_max) {
-            return None;
+    // the light is sampled where it is at the time of the ray
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
+        let to_object = self.pose(time).inverse_matrix();
+        transformed_pdf_value(&self.ptr, &to_object, o, v, time)
+    }
+    fn random(&self, o: Vec3, time: f64) -> Vec3 {
+        let pose = self.pose(time);
+        let direction = self
+            .ptr
+            .random(pose.inverse_matrix().transform_point(o), time);
+        pose.matrix().transform_vector(direction)
+    }
+}
+
+// A placement of geometry shared with other instances, only the transform and an optional
+// material that replaces the geometry's own are stored per instance. Many copies of a mesh
+// take little more memory than one.
+pub struct Instance {
+    transform: Transform<Arc<dyn Hittable>>,
+    material: Option<Arc<dyn Material>>,
+}
+impl Instance {
+    // None if the matrix can't be inverted
+    pub fn new(
+        geometry: Arc<dyn Hittable>,
+        matrix: Mat4,
+        material: Option<Arc<dyn Material>>,
+    ) -> Option<Self> {
+        Some(Self {
+            transform: Transform::new(geometry, matrix)?,
+            material,
+        })
+    }
+}
+impl Hittable for Instance {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let mut rec = self.transform.hit(r, t_min, t_max)?;
+        if let Some(material) = &self.material {
+            rec.mat_ptr = &**material;
         }
-        let mut rec = self.ptr.hit(rotated_r, t_min, t_max).unwrap();
-        let mut p = rec.p;
-        let mut normal = rec.normal;
-        p.x = self.cos_theta * rec.p.x + self.sin_theta * rec.p.z;
-        p.z = -self.sin_theta * rec.p.x + self.cos_theta * rec.p.z;
-        normal.x = self.cos_theta * rec.normal.x + self.sin_theta * rec.normal.z;
-        normal.z = -self.sin_theta * rec.normal.x + self.cos_theta * rec.normal.z;
-        rec.p = p;
-        rec.set_face_normal(rotated_r, normal);
         Some(rec)
     }
+    fn bounding_box(&self, time0: f64, time1: f64, output_box: &mut AABB) -> bool {
+        self.transform.bounding_box(time0, time1, output_box)
+    }
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
+        self.transform.pdf_value(o, v, time)
+    }
+    fn random(&self, o: Vec3, time: f64) -> Vec3 {
+        self.transform.random(o, time)
+    }
 }
 
 #[derive(Clone)]
@@ -167,14 +355,76 @@ impl<T: Hittable> FlipFace<T> {
 }
 impl<T: Hittable> Hittable for FlipFace<T> {
     fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        if let None = self.ptr.hit(r, t_min, t_max) {
-            return None;
-        }
-        let mut rec = self.ptr.hit(r, t_min, t_max).unwrap();
+        let mut rec = self.ptr.hit(r, t_min, t_max)?;
         rec.front_face = !rec.front_face;
         Some(rec)
     }
     fn bounding_box(&self, time0: f64, time1: f64, output_box: &mut AABB) -> bool {
         self.ptr.bounding_box(time0, time1, output_box)
     }
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
+        self.ptr.pdf_value(o, v, time)
+    }
+    fn random(&self, o: Vec3, time: f64) -> Vec3 {
+        self.ptr.random(o, time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DiffuseLight, SolidColor, XZRect};
+
+    fn light() -> DiffuseLight<SolidColor> {
+        DiffuseLight::new2(Vec3::new(1.0, 1.0, 1.0))
+    }
+
+    #[test]
+    fn transformed_rect_has_the_density_of_the_placed_rect() {
+        // the unit square stretched to 2 x 3, turned a quarter around y and lifted to y = 4
+        let matrix = Mat4::translate(Vec3::new(0.0, 4.0, 0.0))
+            * Mat4::rotate(Vec3::new(0.0, 1.0, 0.0), 90.0)
+            * Mat4::scale(Vec3::new(2.0, 1.0, 3.0));
+        let transformed =
+            Transform::new(XZRect::new(0.0, 1.0, 0.0, 1.0, 0.0, light()), matrix).unwrap();
+        let placed = XZRect::new(0.0, 3.0, -2.0, 0.0, 4.0, light());
+        let o = Vec3::new(1.0, 0.5, -0.5);
+        for &(x, z) in &[(0.5, -1.0), (2.9, -0.1), (1.3, -1.7)] {
+            let v = Vec3::new(x, 4.0, z) - o;
+            let expected = placed.pdf_value(o, v, 0.0);
+            assert!((transformed.pdf_value(o, v, 0.0) - expected).abs() < 1e-9 * expected);
+        }
+        for _ in 0..100 {
+            let v = transformed.random(o, 0.0);
+            assert!(((o + v).y - 4.0).abs() < 1e-9);
+            assert!(placed.pdf_value(o, v, 0.0) > 0.0);
+        }
+    }
+
+    #[test]
+    fn animated_rect_is_sampled_where_it_is_at_the_ray_time() {
+        // the unit square rises from y = 0 to y = 4 over the shutter
+        let keyframe = |time, y| Keyframe {
+            time,
+            translation: Vec3::new(0.0, y, 0.0),
+            rotation: Quat::identity(),
+            scale: Vec3::new(1.0, 1.0, 1.0),
+        };
+        let animated = AnimatedTransform::new(
+            XZRect::new(0.0, 1.0, 0.0, 1.0, 0.0, light()),
+            vec![keyframe(0.0, 0.0), keyframe(1.0, 4.0)],
+        )
+        .unwrap();
+        let o = Vec3::new(0.5, -1.0, 0.5);
+        let v = Vec3::new(0.3, 2.0, 0.6) - o;
+        for &(time, y) in &[(0.0, 0.0), (0.5, 2.0)] {
+            let placed = XZRect::new(0.0, 1.0, 0.0, 1.0, y, light());
+            let expected = placed.pdf_value(o, v, time);
+            assert!((animated.pdf_value(o, v, time) - expected).abs() < 1e-9 * expected);
+        }
+        for _ in 0..100 {
+            let v = animated.random(o, 0.5);
+            assert!(((o + v).y - 2.0).abs() < 1e-9);
+        }
+    }
 }
@@ -1,29 +1,51 @@
 #[allow(clippy::float_cmp)]
 mod aabb;
+mod benchmark;
 mod camerafile;
 mod hittable_listfile;
 mod hittablefile;
+mod mat4;
 mod materialfile;
+mod microfacet;
 mod moving_sphere;
+mod obj_loader;
+mod quat;
 mod ray;
+mod rgb2spec;
 mod boxfile;
 mod rtweekend;
+mod sampler;
+mod scheduler;
+mod stats;
 mod spherefile;
 mod texture;
+mod tonemap;
 mod vec3;
 mod perlin;
 mod aarect;
 mod constant_medium;
 mod bvh;
+mod checkpoint;
+mod cli;
+mod framebuffer;
 mod onb;
 mod pdf;
+mod progressive;
+mod sample_guard;
+mod scene;
+mod spectrum;
+mod triangle;
 
 use aabb::AABB;
+use bvh::FlatBvh;
 use camerafile::Camera;
+use checkpoint::Checkpoint;
+use cli::{CliError, RenderSettings, USAGE};
 use hittable_listfile::HittableList;
-use hittablefile::{HitRecord,Hittable,Translate, Rotatey, FlipFace};
-use image::{ImageBuffer, RgbImage};
-use indicatif::ProgressBar;
+use hittablefile::{AnimatedTransform,HitRecord,Hittable,Instance,Keyframe,Transform, FlipFace};
+use framebuffer::FrameBuffer;
+use progressive::{Accumulator, PixelStats};
+use indicatif::{ProgressBar, ProgressStyle};
 use materialfile::{Dielectric,Lambertian, DiffuseLight,Material,Isotropic,ScatterRecord};
 use ray::Ray;
 use rtweekend::INFINITY;
@@ -35,338 +57,317 @@ use aarect::{XYRect,XZRect, YZRect};
 use boxfile::RealBox;
 use onb::Onb;
 use pdf::{Pdf,CosinePdf,HittablePdf,MixturePdf};
-use crate::rtweekend::random_f64;
+use sample_guard::SampleGuard;
+use scene::load_scene;
+use rgb2spec::RgbToSpectrum;
+use spectrum::{SampledSpectrum, SampledWavelengths};
+use scheduler::{make_tiles, Tile, TileQueue};
+use stats::{count_ray, RayStats};
+use crate::rtweekend::{random_f64, seed_rng};
+use sampler::{sample_1d, sample_2d, set_sampler, start_sample};
 use std::sync::mpsc::channel;
 use std::sync::Arc;
+use std::time::Instant;
 use threadpool::ThreadPool;
 use std::boxed::Box;
+use std::ops::{Add, Mul};
 
-/* 
-fn final_scene() -> HittableList {
-    let mut boxes1 = HittableList::new();
-    let ground = Arc::new(Lambertian::new2(&Vec3::new(0.48, 0.83, 0.53)));
-    let boxes_per_side = 20;
-    for i in 0..boxes_per_side {
-        for j in 0..boxes_per_side {
-            let w =100.0;
-            let x0 = -1000.0 + i as f64 * w;
-            let z0 = -1000.0 + j as f64 * w;
-            let y0 = 0.0;
-            let x1 = x0 + w;
-            let y1 = random_f64(1.0, 101.0);
-            let z1 = z0 + w;
-            boxes1.add(Arc::new(Box::new(Vec3::new(x0, y0, z0), Vec3::new(x1, y1, z1), ground.clone())));
-        }
-    }
-    let mut objects = HittableList::new();
-    objects.add(Arc::new(BvhNode::new(&boxes1.objects, 0, boxes1.objects.len(), 0.0, 1.0)));
-
-    let light = Arc::new(DiffuseLight::new2(Vec3::new(7.0, 7.0, 7.0)));
-    objects.add(Arc::new(XZRect::new(123.0, 423.0, 147.0, 412.0, 554.0, light.clone())));
-    let center1: Vec3 = Vec3::new(400.0, 400.0, 200.0);
-    let center2: Vec3 = center1 + Vec3::new(30.0, 0.0, 0.0);
-    let moving_sphere_material = Arc::new(Lambertian::new2(&Vec3::new(0.7, 0.3, 0.1)));
-    objects.add(Arc::new(MovingSphere::new(center1, center2, 0.0, 1.0, 50.0, moving_sphere_material.clone())));
-
-    objects.add(Arc::new(Sphere::new(Vec3::new(260.0, 150.0, 45.0), 50.0, Arc::new(Dielectric::new(1.5)))));
-    objects.add(Arc::new(Sphere::new(Vec3::new(0.0, 150.0, 145.0), 50.0, Arc::new(Metal::new(Vec3::new(0.8, 0.8, 0.9), 1.0)))));
-
-    let mut boundary = Arc::new(Sphere::new(Vec3::new(360.0, 150.0, 145.0), 70.0, Arc::new(Dielectric::new(1.5))));
-    objects.add(boundary.clone());
-    objects.add(Arc::new(ConstantMedium::new2(boundary.clone(), 0.2, Vec3::new(0.2, 0.4, 0.9))));
-    boundary = Arc::new(Sphere::new(Vec3::new(0.0, 0.0, 0.0), 5000.0, Arc::new(Dielectric::new(1.5))));
-    objects.add(Arc::new(ConstantMedium::new2(boundary.clone(), 0.0001, Vec3::new(1.0, 1.0, 1.0))));
-
-    let emat = Arc::new(Lambertian::new1(Arc::new(ImageTexture::new2("input/earthmap.jpg"))));
-    objects.add(Arc::new(Sphere::new(Vec3::new(400.0, 200.0, 400.0), 100.0, emat.clone())));
-    let pertext = Arc::new(NoiseTexture::new(0.1));
-    objects.add(Arc::new(Sphere::new(Vec3::new(220.0, 280.0, 300.0), 80.0, Arc::new(Lambertian::new1(pertext.clone())))));
-
-    let mut boxes2 = HittableList::new();
-    let white = Arc::new(Lambertian::new2(&Vec3::new(0.73, 0.73, 0.73)));
-    let ns = 1000;
-    for j in 0..ns {
-        boxes2.add(Arc::new(Sphere::new(Vec3::random(0.0, 165.0), 10.0, white.clone())));
+// What tracing RGB and tracing the wavelengths of a spectral path don't share: the radiance a
+// path carries and how the RGB colors of the scene turn into it.
+trait PathRadiance {
+    type Value: Copy + Add<Output = Self::Value> + Mul<f64, Output = Self::Value>;
+    fn zero(&self) -> Self::Value;
+    fn rgb(&self, rgb: Vec3) -> Self::Value;
+    fn product(a: Self::Value, b: Self::Value) -> Self::Value;
+    // emission the material gives directly, None if its RGB emission is converted
+    fn emitted(&self, _r: Ray, _rec: &HitRecord) -> Option<Self::Value> {
+        None
     }
-    objects.add(Arc::new(Translate::new(Arc::new(Rotatey::new(Arc::new(BvhNode::new(&boxes2.objects, 0, boxes2.objects.len(), 0.0, 1.0)), 15.0)), Vec3::new(-100.0, 270.0, 395.0))));
-    objects
-
+    fn scattered_by(&mut self, _material: &dyn Material) {}
 }
 
-
-fn cornell_smoke() -> HittableList {
-    let mut objects = HittableList::new();
-    let red = Arc::new(Lambertian::new2(&Vec3::new(0.65, 0.05, 0.05)));
-    let white = Arc::new(Lambertian::new2(&Vec3::new(0.73, 0.73, 0.73)));
-    let green = Arc::new(Lambertian::new2(&Vec3::new(0.12, 0.45, 0.15)));
-    let light = Arc::new(DiffuseLight::new2(Vec3::new(7.0, 7.0, 7.0)));
-    
-    objects.add(Arc::new(YZRect::new(0.0, 555.0, 0.0, 555.0, 555.0, green)));
-    objects.add(Arc::new(YZRect::new(0.0, 555.0, 0.0, 555.0, 0.0, red)));
-    objects.add(Arc::new(XZRect::new(113.0, 443.0, 127.0, 432.0, 554.0, light)));
-    objects.add(Arc::new(XZRect::new(0.0, 555.0, 0.0, 555.0, 0.0, white.clone())));
-    objects.add(Arc::new(XZRect::new(0.0, 555.0, 0.0, 555.0, 555.0, white.clone())));
-    objects.add(Arc::new(XYRect::new(0.0, 555.0, 0.0, 555.0, 555.0, white.clone())));
-    let mut box1: Arc<dyn Hittable> = Arc::new(Box::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(165.0, 330.0, 165.0), white.clone()));
-    box1 = Arc::new(Rotatey::new(box1, 15.0));
-    box1 = Arc::new(Translate::new(box1, Vec3::new(265.0, 0.0, 295.0)));
-    let mut box2: Arc<dyn Hittable> = Arc::new(Box::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(165.0, 165.0, 165.0), white.clone()));
-    box2 = Arc::new(Rotatey::new(box2, -18.0));
-    box2 = Arc::new(Translate::new(box2, Vec3::new(130.0, 0.0, 65.0)));
-    objects.add(Arc::new(ConstantMedium::new2(box1, 0.01, Vec3::zero())));
-    objects.add(Arc::new(ConstantMedium::new2(box2, 0.01, Vec3::new(1.0, 1.0, 1.0))));
-    objects
-}
-*/
-/* 
-fn simple_light () -> HittableList {
-    let mut objects = HittableList::new();
-    let pertext = Arc::new(NoiseTexture::new(4.0));
-    objects.add(Arc::new(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, Arc::new(Lambertian::new1(pertext.clone())))));
-    objects.add(Arc::new(Sphere::new(Vec3::new(0.0, 2.0, 0.0), 2.0, Arc::new(Lambertian::new1(pertext.clone())))));
-    let difflight = Arc::new(DiffuseLight::new2(Vec3::new(4.0, 4.0, 4.0)));
-    objects.add(Arc::new(XYRect::new(3.0, 5.0, 1.0, 3.0, -2.0, difflight)));
-    objects
+struct RgbPath;
+impl PathRadiance for RgbPath {
+    type Value = Vec3;
+    fn zero(&self) -> Vec3 {
+        Vec3::zero()
+    }
+    fn rgb(&self, rgb: Vec3) -> Vec3 {
+        rgb
+    }
+    fn product(a: Vec3, b: Vec3) -> Vec3 {
+        Vec3::elemul(a, b)
+    }
 }
 
-fn two_perlin_sphere() -> HittableList{
-    let mut objects = HittableList::new();
-    let pertext = Arc::new(NoiseTexture::new(4.0));
-    objects.add(Arc::new(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, Arc::new(Lambertian::new1(pertext.clone())))));
-    objects.add(Arc::new(Sphere::new(Vec3::new(0.0, 2.0, 0.0), 2.0, Arc::new(Lambertian::new1(pertext.clone())))));
-    objects
+// Colors from textures and materials are upsampled to spectra, lights that have a spectrum are
+// evaluated at the wavelengths directly.
+struct SpectralPath<'a> {
+    wavelengths: &'a mut SampledWavelengths,
+    upsampler: &'a RgbToSpectrum,
 }
-
-fn random_scene() -> HittableList {
-    let mut world = HittableList::new();
-    let checker = Arc::new(CheckerTexture::new2(
-        Vec3::new(0.2, 0.3, 0.1),
-        Vec3::new(0.9, 0.9, 0.9),
-    ));
-    world.add(Arc::new(Sphere::new(
-        Vec3::new(0.0, -1000.0, 0.0),
-        1000.0,
-        Arc::new(Lambertian::new1(checker.clone())),
-    )));
-    for a in -11..11 {
-        for b in -11..11 {
-            let choose_mat: f64 = random_f64(0.0, 1.0);
-            let center: Vec3 = Vec3::new(
-                a as f64 + 0.9 * random_f64(0.0, 1.0),
-                0.2,
-                b as f64 + 0.9 * random_f64(0.0, 1.0),
-            );
-
-            if (center - Vec3::new(4.0, 0.2, 0.0)).length() > 0.9 {
-                if choose_mat < 0.8 {
-                    let albedo: Vec3 = Vec3::elemul(Vec3::random(0.0, 1.0), Vec3::random(0.0, 1.0));
-                    let sphere_material = Arc::new(Lambertian::new2(&albedo));
-                    let center2: Vec3 = center + Vec3::new(0.0, random_f64(0.0, 0.5), 0.0);
-                    world.add(Arc::new(MovingSphere::new(
-                        center,
-                        center2,
-                        0.0,
-                        1.0,
-                        0.2,
-                        sphere_material.clone(),
-                    )));
-                } else if choose_mat < 0.95 {
-                    let albedo = Vec3::random(0.5, 1.0);
-                    let fuzz: f64 = random_f64(0.0, 1.0);
-                    let sphere_material = Arc::new(Metal::new(albedo, fuzz));
-                    world.add(Arc::new(Sphere::new(center, 0.2, sphere_material.clone())));
-                } else {
-                    let sphere_material = Arc::new(Dielectric::new(1.5));
-                    world.add(Arc::new(Sphere::new(center, 0.2, sphere_material.clone())));
-                }
-            }
+impl<'a> PathRadiance for SpectralPath<'a> {
+    type Value = SampledSpectrum;
+    fn zero(&self) -> SampledSpectrum {
+        SampledSpectrum::zero()
+    }
+    fn rgb(&self, rgb: Vec3) -> SampledSpectrum {
+        self.upsampler.sample(rgb, self.wavelengths)
+    }
+    fn product(a: SampledSpectrum, b: SampledSpectrum) -> SampledSpectrum {
+        a * b
+    }
+    fn emitted(&self, r: Ray, rec: &HitRecord) -> Option<SampledSpectrum> {
+        rec.mat_ptr.emitted_spectrum(r, rec, self.wavelengths)
+    }
+    // the other wavelengths would have scattered elsewhere
+    fn scattered_by(&mut self, material: &dyn Material) {
+        if material.is_dispersive() {
+            self.wavelengths.terminate_secondary();
         }
     }
-    let material1 = Arc::new(Dielectric::new(1.5));
-    world.add(Arc::new(Sphere::new(
-        Vec3::new(0.0, 1.0, 0.0),
-        1.0,
-        material1,
-    )));
-
-    let material2 = Arc::new(Lambertian::new2(&Vec3::new(0.4, 0.2, 0.1)));
-    world.add(Arc::new(Sphere::new(
-        Vec3::new(-4.0, 1.0, 0.0),
-        1.0,
-        material2,
-    )));
-
-    let material3 = Arc::new(Metal::new(Vec3::new(0.7, 0.6, 0.5), 0.0));
-    world.add(Arc::new(Sphere::new(
-        Vec3::new(4.0, 1.0, 0.0),
-        1.0,
-        material3,
-    )));
-    world
 }
-*/
-fn cornell_box() -> HittableList {
-    let mut objects = HittableList::new();
-    let red = Lambertian::new2(Vec3::new(0.65, 0.05, 0.05));
-    let white = Lambertian::new2(Vec3::new(0.73, 0.73, 0.73));
-    let green = Lambertian::new2(Vec3::new(0.12, 0.45, 0.15));
-    let light = DiffuseLight::new2(Vec3::new(15.0, 15.0, 15.0));
-    
-    objects.add(Box::new(YZRect::new(0.0, 555.0, 0.0, 555.0, 555.0, green)));
-    objects.add(Box::new(YZRect::new(0.0, 555.0, 0.0, 555.0, 0.0, red)));
-    objects.add(Box::new(FlipFace::new(XZRect::new(213.0, 343.0, 227.0, 332.0, 554.0, light))));
-    objects.add(Box::new(XZRect::new(0.0, 555.0, 0.0, 555.0, 0.0, white.clone())));
-    objects.add(Box::new(XZRect::new(0.0, 555.0, 0.0, 555.0, 555.0, white.clone())));
-    objects.add(Box::new(XYRect::new(0.0, 555.0, 0.0, 555.0, 555.0, white.clone())));
-    let box1 = RealBox::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(165.0, 330.0, 165.0), white.clone());
-    let box1 = Rotatey::new(box1, 15.0);
-    let box1 = Translate::new(box1, Vec3::new(265.0, 0.0, 295.0));
-    let box1 = Box::new(box1);
-    objects.add(box1);
 
-    let glass = Dielectric::new(1.5);
-    objects.add(Box::new(Sphere::new(Vec3::new(190.0, 90.0, 190.0), 90.0, glass.clone())));
-
-    objects
-}
-fn ray_color(r: Ray,background: Vec3, world: &Arc<HittableList>,lights: &Arc<HittableList>, depth: i32) -> Vec3 {
+fn ray_color<P: PathRadiance>(r: Ray, path: &mut P, background: Vec3, world: &Arc<FlatBvh>, lights: &Arc<HittableList>, guard: &SampleGuard, depth: i32) -> P::Value {
     if depth <= 0 {
-        return Vec3::new(0.0, 0.0, 0.0);
+        return path.zero();
     }
-    if let None = world.hit(r, 0.001, INFINITY) {
-        return background;
-    }
-    let rec = world.hit(r, 0.001, INFINITY).unwrap();
+    count_ray();
+    let rec = match world.hit(r, 0.001, INFINITY) {
+        Some(rec) => rec,
+        None => return path.rgb(background),
+    };
     let mut srec = ScatterRecord {specular_ray:Ray::new(Vec3::zero(), Vec3::zero(), 0.0),
                                            is_specular:true,
                                            attenuation: Vec3::zero(),
                                            pdf_ptr: Box::new(CosinePdf::new(Vec3::zero())),};
-    let emitted = rec.mat_ptr.emitted(r, &rec, rec.u, rec.v, rec.p);
+    // invalid values are dropped (or clamped if negative) and tallied by the guard
+    let material = rec.mat_ptr.name();
+    let emitted = match path.emitted(r, &rec) {
+        Some(emitted) => emitted,
+        None => path.rgb(guard.check(rec.mat_ptr.emitted(r, &rec, rec.u, rec.v, rec.p), "emission", material).unwrap_or_else(Vec3::zero)),
+    };
     if !rec.mat_ptr.scatter(r, &rec, &mut srec) {
         return emitted;
     }
+    path.scattered_by(rec.mat_ptr);
+    let attenuation = match guard.check(srec.attenuation, "attenuation", material) {
+        Some(attenuation) => path.rgb(attenuation),
+        None => return emitted,
+    };
+    // the path keeps its wavelength
     if srec.is_specular {
-        //let a = ray_color(srec.specular_ray, background, world, lights, depth - 1);
-        //println!("{}, {}, {}", a.x, a.y, a.z);
-        return Vec3::elemul(srec.attenuation, ray_color(srec.specular_ray, background, world, lights, depth - 1));
+        let specular_ray = srec.specular_ray.with_wavelength(r.wavelength);
+        return P::product(attenuation, ray_color(specular_ray, path, background, world, lights, guard, depth - 1));
     }
     let tem1pdf = &**lights;
-    let light_ptr = HittablePdf::new(tem1pdf, rec.p);
+    let light_ptr = HittablePdf::new(tem1pdf, rec.p, r.tm);
     let tem2pdf = &*srec.pdf_ptr;
-    let p = MixturePdf::new(&light_ptr, tem2pdf);
-    let scattered = Ray::new(rec.p, p.generate(), r.tm);
-    let pdf_val = p.value(scattered.dir);
-    emitted + Vec3::elemul(srec.attenuation, ray_color(scattered, background, world, lights, depth - 1)) * rec.mat_ptr.scattering_pdf(r, &rec, scattered) / pdf_val
+    let mixture = MixturePdf::new(&light_ptr, tem2pdf);
+    let p: &dyn Pdf = if lights.objects.is_empty() { tem2pdf } else { &mixture };
+    let scattered = Ray::new(rec.p, p.generate(), r.tm).with_wavelength(r.wavelength);
+    let bsdf = match guard.check(rec.mat_ptr.eval(r, &rec, &srec, scattered), "bsdf", material) {
+        Some(bsdf) => path.rgb(bsdf),
+        None => return emitted,
+    };
+    // microfacet lobes may pick a direction through the surface that nothing scatters into
+    let pdf_value = p.value(scattered.dir);
+    if pdf_value == 0.0 {
+        return emitted;
+    }
+    let pdf_weight = match guard.check_scalar(1.0 / pdf_value, "sampling pdf", material) {
+        Some(pdf_weight) => pdf_weight,
+        None => return emitted,
+    };
+    emitted + P::product(bsdf, ray_color(scattered, path, background, world, lights, guard, depth - 1)) * pdf_weight
+}
+
+fn save_outputs(settings: &RenderSettings, frame: &FrameBuffer) {
+    for output in &settings.outputs {
+        if let Err(err) = frame.save(output, &settings.tonemap) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn save_checkpoint(settings: &RenderSettings, accumulator: &Accumulator) {
+    if let Some(path) = &settings.checkpoint {
+        if let Err(err) = Checkpoint::new(settings, accumulator.clone()).save(path) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
 }
 
 fn main() {
+    let mut settings = match RenderSettings::from_env() {
+        Ok(settings) => settings,
+        Err(CliError::Help) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            std::process::exit(2);
+        }
+    };
+    if settings.benchmark {
+        if let Err(err) = benchmark::run(&settings) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let resumed = match &settings.resume {
+        Some(path) => match Checkpoint::load(path) {
+            Ok(checkpoint) => Some(checkpoint),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    if let Some(checkpoint) = &resumed {
+        checkpoint.apply(&mut settings);
+    }
+    seed_rng(settings.seed);
     let (tx, rx) = channel();
-    let n_jobs: usize = 32;
-    let n_workers = 6;
+    let n_workers = settings.threads;
     let pool = ThreadPool::new(n_workers);
-    
-    let bar = ProgressBar::new(n_jobs as u64);
 
     //image
-    let aspect_ratio: f64 = 1.0;
-    const IMAGE_WIDTH: i32 = 600;
-    const IMAGE_HEIGHT: i32 = 600; //IMAGE_WIDTH / aspect_ratio
-    let samples_per_pixel: i32 = 1000;
+    let image_width: i32 = settings.width;
+    let image_height: i32 = settings.height;
+    let samples_per_pixel: i32 = settings.samples_per_pixel;
+    let max_depth: i32 = settings.max_depth;
     //world
-    let mut lights =HittableList::new();
-    lights.add(Box::new(XZRect::new(213.0, 343.0, 227.0, 332.0, 554.0, DiffuseLight::new2(Vec3::new(7.0, 7.0, 7.0)))));
-    lights.add(Box::new(Sphere::new(Vec3::new(190.0, 90.0, 190.0), 90.0,DiffuseLight::new2(Vec3::new(7.0, 7.0, 7.0)))));
-    let world = Arc::new(cornell_box());
-    let lights = Arc::new(lights);
+    let scene = match load_scene(&settings.scene, settings.aspect_ratio()) {
+        Ok(scene) => scene,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let cam = scene.camera;
+    let (time0, time1) = cam.shutter();
+    let world = Arc::new(FlatBvh::new(scene.world.objects, time0, time1));
+    let lights = Arc::new(scene.lights);
+    let background = scene.background;
+    let guard = Arc::new(SampleGuard::new());
+    // the coefficient table is fitted once per render, only spectral renders need it
+    let upsampler = if settings.spectral { Some(Arc::new(RgbToSpectrum::new())) } else { None };
 
-    //Camera
-    let lookfrom: Vec3 = Vec3::new(278.0, 278.0, -800.0);
-    let lookat: Vec3 = Vec3::new(278.0, 278.0, 0.0);
-    let vup: Vec3 = Vec3::new(0.0, 1.0, 0.0);
-    let dist_to_focus: f64 = 10.0;
-    let aperture: f64 = 0.0;
-    let background: Vec3 = Vec3::new(0.0, 0.0, 0.0);
-    let cam: Camera = Camera::new(
-        lookfrom,
-        lookat,
-        vup,
-        &40.0,
-        &aspect_ratio,
-        &aperture,
-        &dist_to_focus,
-        0.0,
-        1.0,
-    );
-    
-    for i in 0..n_jobs {
-        let tx = tx.clone();
-        let world_ptr = world.clone();
-        let lightscolne =  lights.clone(); 
-        pool.execute(move || {
-        let row_begin = IMAGE_HEIGHT as usize * i / n_jobs;
-        let row_end = IMAGE_HEIGHT as usize * (i + 1) / n_jobs;
-        let render_height = row_end - row_begin;
-        let mut img: RgbImage = ImageBuffer::new(IMAGE_WIDTH as u32, render_height as u32);
-        for x in 0..IMAGE_WIDTH {
-            for(img_y, y) in (row_begin..row_end).enumerate() {
-                let pixel = img.get_pixel_mut(x as u32, img_y as u32);
-                let x1 = x as f64;
-                let y1 = (IMAGE_HEIGHT - 1 - y as i32) as f64;
-                let mut color: Vec3 = Vec3::new(0.0, 0.0, 0.0);
-                for _s in 0..samples_per_pixel {
-                    let u: f64 = (x1 + random_f64(0.0, 1.0)) / (IMAGE_WIDTH as f64 - 1.0);
-                    let v: f64 = (y1 + random_f64(0.0, 1.0)) / (IMAGE_HEIGHT as f64 - 1.0);
-                    let r: Ray = cam.get_ray(&u, &v);
-                    color += ray_color(r, background, &world_ptr,&lightscolne , 50);
+    let pass_settings = settings.pass_settings();
+    let bar = ProgressBar::new(image_width as u64 * image_height as u64 * samples_per_pixel as u64);
+    bar.set_style(ProgressStyle::default_bar().template("[{elapsed_precise}] {wide_bar} {percent}% (eta {eta}) {msg}"));
+    let tiles = make_tiles(image_width as usize, image_height as usize, settings.tile_size, settings.tile_order);
+    let mut accumulator = match resumed {
+        Some(checkpoint) => checkpoint.accumulator,
+        None => Accumulator::new(image_width as usize, image_height as usize),
+    };
+    bar.inc(accumulator.total_samples());
+    let mut last_checkpoint = Instant::now();
+    let start_time = Instant::now();
+    let mut passes = 0;
+    let mut last_pass: Option<(f64, u64)> = None;
+    loop {
+        // every pixel gets its own number of samples, zero once it reached the target spp or converged
+        let plan = accumulator.plan(&pass_settings);
+        let planned: u64 = plan.iter().map(|&n| n as u64).sum();
+        if planned == 0 {
+            break;
+        }
+        if let (Some(limit), Some((seconds, samples))) = (settings.time_limit, last_pass) {
+            let predicted = seconds * planned as f64 / samples as f64;
+            if start_time.elapsed().as_secs_f64() + predicted > limit {
+                break;
+            }
+        }
+        let pass_start = Instant::now();
+        let plan = Arc::new(plan);
+        let first_sample: Arc<Vec<u32>> = Arc::new(accumulator.pixels.iter().map(|p| p.samples).collect());
+        // tiles without work in this pass (all of their pixels converged) are skipped
+        let pass_tiles: Vec<Tile> = tiles.iter().copied().filter(|tile| {
+            (tile.y0..tile.y1).any(|y| plan[y * image_width as usize + tile.x0..y * image_width as usize + tile.x1].iter().any(|&n| n > 0))
+        }).collect();
+        let tile_count = pass_tiles.len();
+        let queue = Arc::new(TileQueue::new(pass_tiles));
+        for _ in 0..n_workers {
+            let tx = tx.clone();
+            let world_ptr = world.clone();
+            let lightscolne =  lights.clone(); 
+            let guard = guard.clone();
+            let plan = plan.clone();
+            let first_sample = first_sample.clone();
+            let queue = queue.clone();
+            let seed = settings.seed;
+            let sampler = settings.sampler;
+            let upsampler = upsampler.clone();
+            pool.execute(move || {
+            set_sampler(sampler, samples_per_pixel as u32);
+            while let Some(tile) = queue.pop() {
+                let mut stats = vec![PixelStats::default(); tile.width() * tile.height()];
+                for y in tile.y0..tile.y1 {
+                    for x in tile.x0..tile.x1 {
+                        let x1 = x as f64;
+                        let y1 = (image_height - 1 - y as i32) as f64;
+                        let pixel_index = y * image_width as usize + x;
+                        let pixel = &mut stats[(y - tile.y0) * tile.width() + x - tile.x0];
+                        let first = first_sample[pixel_index];
+                        for s in first..first + plan[pixel_index] {
+                            start_sample(seed, pixel_index as u64, s as u64);
+                            let (dx, dy) = sample_2d();
+                            let u: f64 = (x1 + dx) / (image_width as f64 - 1.0);
+                            let v: f64 = (y1 + dy) / (image_height as f64 - 1.0);
+                            let r: Ray = cam.get_ray(&u, &v);
+                            let color = match &upsampler {
+                                Some(upsampler) => {
+                                    let mut wavelengths = SampledWavelengths::sample(sample_1d());
+                                    let r = r.with_wavelength(Some(wavelengths.hero()));
+                                    let mut path = SpectralPath { wavelengths: &mut wavelengths, upsampler };
+                                    let radiance = ray_color(r, &mut path, background, &world_ptr, &lightscolne, &guard, max_depth);
+                                    guard.check_spectral_sample(radiance.to_rgb(&wavelengths))
+                                }
+                                None => guard.check_sample(ray_color(r, &mut RgbPath, background, &world_ptr,&lightscolne, &guard, max_depth)),
+                            };
+                            pixel.add(color);
+                        }
+                    }
                 }
-                let red = (255.999 * ((color.x / samples_per_pixel as f64).sqrt())) as u8;
-                let green = (255.999 * ((color.y / samples_per_pixel as f64).sqrt())) as u8;
-                let blue = (255.999 * ((color.z / samples_per_pixel as f64).sqrt())) as u8;
-                *pixel = image::Rgb([red, green, blue]);
+                RayStats::flush();
+                tx.send((tile, stats)).expect("failed to send result");
+            }
             }
+            )
         }
-        tx.send((row_begin..row_end, img)).expect("failed to send result");
+        for (done, (tile, stats)) in rx.iter().take(tile_count).enumerate() {
+            accumulator.add_tile(&tile, &stats);
+            bar.inc(stats.iter().map(|p| p.samples as u64).sum());
+            bar.set_message(&format!("pass {}, tile {}/{}", passes + 1, done + 1, tile_count));
         }
-        )
-    }
-    let mut img: RgbImage = ImageBuffer::new(IMAGE_WIDTH as u32, IMAGE_HEIGHT as u32);
-    for (rows, data) in rx.iter().take(n_jobs) {
-        for (idx, row) in rows.enumerate() {
-            for col in 0..IMAGE_WIDTH {
-                let row = row as u32;
-                let idx = idx as u32;
-                *img.get_pixel_mut(col as u32, row) = *data.get_pixel(col as u32, idx);
-            }
+        last_pass = Some((pass_start.elapsed().as_secs_f64(), planned));
+        passes += 1;
+        if settings.progressive {
+            save_outputs(&settings, &accumulator.frame());
         }
-        bar.inc(1);
-    }
-    /*for x in 0..IMAGE_WIDTH {
-        for y in 0..IMAGE_HEIGHT {
-            let pixel = img.get_pixel_mut(x as u32, y as u32);
-            let x1 = x as f64;
-            let y1 = (IMAGE_HEIGHT - 1 - y) as f64;
-            let mut color: Vec3 = Vec3::new(0.0, 0.0, 0.0);
-            for _s in 0..samples_per_pixel {
-                let u: f64 = (x1 + random_f64(0.0, 1.0)) / (IMAGE_WIDTH as f64 - 1.0);
-                let v: f64 = (y1 + random_f64(0.0, 1.0)) / (IMAGE_HEIGHT as f64 - 1.0);
-                let r: Ray = cam.get_ray(&u, &v);
-                color += ray_color(r, background, &world,&lights , 50);
-            }
-            let r = color.x;
-            let g = color.y;
-            let b = color.z;
-            if r!=r {color.x = 0.0}
-            if g!=g {color.y = 0.0}
-            if b!=b {color.z = 0.0}
-            let samples_per_pixel: f64 = 10.0;
-            let red = (255.999 * ((color.x / samples_per_pixel).sqrt())) as u8;
-            let green = (255.999 * ((color.y / samples_per_pixel).sqrt())) as u8;
-            let blue = (255.999 * ((color.z / samples_per_pixel).sqrt())) as u8;
-            //println!("{}, {}, {}", red, green, blue);
-            *pixel = image::Rgb([red, green, blue]);
+        if last_checkpoint.elapsed().as_secs_f64() >= settings.checkpoint_interval {
+            save_checkpoint(&settings, &accumulator);
+            last_checkpoint = Instant::now();
         }
-        bar.inc(1);
-    }*/
-    img.save("output/test.png").unwrap();
+    }
     bar.finish();
+    if !settings.progressive || passes == 0 {
+        save_outputs(&settings, &accumulator.frame());
+    }
+    save_checkpoint(&settings, &accumulator);
+    eprintln!(
+        "{} passes, {:.1} samples per pixel on average, {:.1}s",
+        passes,
+        accumulator.total_samples() as f64 / (image_width as f64 * image_height as f64),
+        start_time.elapsed().as_secs_f64()
+    );
+    eprintln!("{}", RayStats::total());
+    eprintln!("{}", guard);
 }
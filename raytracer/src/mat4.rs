@@ -0,0 +1,226 @@
+use crate::rtweekend::degrees_to_radians;
+use crate::Vec3;
+use std::cmp::Ordering;
+use std::ops::Mul;
+
+// Row major 4x4 matrix of an affine transform, points are column vectors so `a * b` applies
+// `b` first and then `a`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mat4 {
+    pub m: [[f64; 4]; 4],
+}
+
+impl Mat4 {
+    pub fn new(m: [[f64; 4]; 4]) -> Self {
+        Self { m }
+    }
+    pub fn identity() -> Self {
+        Self::new([
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+    pub fn translate(offset: Vec3) -> Self {
+        Self::new([
+            [1.0, 0.0, 0.0, offset.x],
+            [0.0, 1.0, 0.0, offset.y],
+            [0.0, 0.0, 1.0, offset.z],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+    pub fn scale(factors: Vec3) -> Self {
+        Self::new([
+            [factors.x, 0.0, 0.0, 0.0],
+            [0.0, factors.y, 0.0, 0.0],
+            [0.0, 0.0, factors.z, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+    // counterclockwise rotation by `angle` degrees when looking down `axis` towards the origin
+    pub fn rotate(axis: Vec3, angle: f64) -> Self {
+        let a = axis.unit();
+        let (sin, cos) = degrees_to_radians(angle).sin_cos();
+        let k = 1.0 - cos;
+        Self::new([
+            [
+                a.x * a.x * k + cos,
+                a.x * a.y * k - a.z * sin,
+                a.x * a.z * k + a.y * sin,
+                0.0,
+            ],
+            [
+                a.y * a.x * k + a.z * sin,
+                a.y * a.y * k + cos,
+                a.y * a.z * k - a.x * sin,
+                0.0,
+            ],
+            [
+                a.z * a.x * k - a.y * sin,
+                a.z * a.y * k + a.x * sin,
+                a.z * a.z * k + cos,
+                0.0,
+            ],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+    // `xy` is how much x grows with y and so on
+    pub fn shear(xy: f64, xz: f64, yx: f64, yz: f64, zx: f64, zy: f64) -> Self {
+        Self::new([
+            [1.0, xy, xz, 0.0],
+            [yx, 1.0, yz, 0.0],
+            [zx, zy, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    pub fn transpose(&self) -> Self {
+        let mut out = [[0.0; 4]; 4];
+        for (i, row) in out.iter_mut().enumerate() {
+            for (j, x) in row.iter_mut().enumerate() {
+                *x = self.m[j][i];
+            }
+        }
+        Self::new(out)
+    }
+    // Gauss-Jordan elimination with partial pivoting, None if the matrix is singular
+    pub fn inverse(&self) -> Option<Self> {
+        let mut a = self.m;
+        let mut inv = Self::identity().m;
+        for column in 0..4 {
+            let pivot = (column..4)
+                .max_by(|&i, &j| {
+                    a[i][column]
+                        .abs()
+                        .partial_cmp(&a[j][column].abs())
+                        .unwrap_or(Ordering::Equal)
+                })
+                .unwrap();
+            if a[pivot][column].is_nan() || a[pivot][column].abs() < 1e-12 {
+                return None;
+            }
+            a.swap(column, pivot);
+            inv.swap(column, pivot);
+            let scale = 1.0 / a[column][column];
+            for j in 0..4 {
+                a[column][j] *= scale;
+                inv[column][j] *= scale;
+            }
+            for row in 0..4 {
+                if row != column {
+                    let factor = a[row][column];
+                    for j in 0..4 {
+                        a[row][j] -= factor * a[column][j];
+                        inv[row][j] -= factor * inv[column][j];
+                    }
+                }
+            }
+        }
+        Some(Self::new(inv))
+    }
+
+    // of the linear part, the factor by which the transform scales volumes
+    pub fn linear_determinant(&self) -> f64 {
+        let m = &self.m;
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+
+    pub fn transform_point(&self, p: Vec3) -> Vec3 {
+        let m = &self.m;
+        Vec3::new(
+            m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3],
+            m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3],
+            m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3],
+        )
+    }
+    // directions ignore the translation
+    pub fn transform_vector(&self, v: Vec3) -> Vec3 {
+        let m = &self.m;
+        Vec3::new(
+            m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+            m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+            m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+        )
+    }
+}
+
+impl Mul for Mat4 {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        let mut out = [[0.0; 4]; 4];
+        for (i, row) in out.iter_mut().enumerate() {
+            for (j, x) in row.iter_mut().enumerate() {
+                *x = (0..4).map(|k| self.m[i][k] * other.m[k][j]).sum();
+            }
+        }
+        Self::new(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: Mat4, b: Mat4) {
+        for i in 0..4 {
+            for j in 0..4 {
+                assert!((a.m[i][j] - b.m[i][j]).abs() < 1e-9, "{:?} != {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn inverse_undoes_the_transform() {
+        let m = Mat4::translate(Vec3::new(1.0, -2.0, 3.0))
+            * Mat4::rotate(Vec3::new(1.0, 1.0, 0.0), 30.0)
+            * Mat4::shear(0.5, 0.0, 0.0, 0.25, 0.0, 0.0)
+            * Mat4::scale(Vec3::new(2.0, 3.0, 0.5));
+        let inv = m.inverse().unwrap();
+        assert_close(m * inv, Mat4::identity());
+        assert_close(inv * m, Mat4::identity());
+        let p = Vec3::new(0.3, -1.2, 4.0);
+        assert!((inv.transform_point(m.transform_point(p)) - p).length() < 1e-9);
+    }
+
+    #[test]
+    fn inverse_needs_a_pivot_swap() {
+        // the first column is zero on the diagonal
+        let m = Mat4::new([
+            [0.0, 1.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0, 2.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        assert_close(m * m.inverse().unwrap(), Mat4::identity());
+    }
+
+    #[test]
+    fn singular_matrices_have_no_inverse() {
+        assert!(Mat4::scale(Vec3::new(1.0, 0.0, 1.0)).inverse().is_none());
+        assert!(Mat4::shear(1.0, 0.0, 1.0, 0.0, 0.0, 0.0)
+            .inverse()
+            .is_none());
+    }
+
+    #[test]
+    fn rotation_is_counterclockwise() {
+        let m = Mat4::rotate(Vec3::new(0.0, 0.0, 1.0), 90.0);
+        let v = m.transform_vector(Vec3::new(1.0, 0.0, 0.0));
+        assert!((v - Vec3::new(0.0, 1.0, 0.0)).length() < 1e-12);
+        // directions are not moved
+        let t = Mat4::translate(Vec3::new(5.0, 5.0, 5.0));
+        assert_eq!(t.transform_vector(v), v);
+    }
+
+    #[test]
+    fn determinant_is_the_volume_scale() {
+        let m =
+            Mat4::rotate(Vec3::new(0.0, 1.0, 0.0), 40.0) * Mat4::scale(Vec3::new(2.0, 3.0, 0.5));
+        assert!((m.linear_determinant() - 3.0).abs() < 1e-12);
+        assert!((Mat4::scale(Vec3::new(-1.0, 1.0, 1.0)).linear_determinant() + 1.0).abs() < 1e-12);
+    }
+}
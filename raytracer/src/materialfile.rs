@@ -1,9 +1,18 @@
-use crate::rtweekend::random_f64;
+use crate::microfacet::{
+    anisotropic_alphas, fresnel_conductor_rgb, fresnel_dielectric, ggx_d, ggx_d_aniso,
+    ggx_dielectric_bsdf, ggx_g1, ggx_g2, ggx_g2_aniso, gtr1_d, refract_about, roughness_to_alpha,
+    GgxDielectricPdf, GgxReflectionPdf, PrincipledPdf, MIN_ALPHA,
+};
+use crate::rtweekend::{clamp, fmin};
+use crate::sampler::sample_1d;
+use crate::spectrum::{SampledSpectrum, SampledWavelengths, Spd};
+use crate::tonemap::{self, luminance};
 use crate::vec3::random_in_unit_sphere;
 use crate::vec3::reflect;
 use crate::vec3::refract;
 use crate::CosinePdf;
 use crate::HitRecord;
+use crate::Onb;
 use crate::Pdf;
 use crate::Ray;
 use crate::SolidColor;
@@ -11,6 +20,7 @@ use crate::Texture;
 use crate::Vec3;
 use std::boxed::Box;
 use std::f64::consts::PI;
+use std::sync::Arc;
 
 pub trait Material {
     fn scatter(&self, r_in: Ray, rec: &HitRecord, srec: &mut ScatterRecord) -> bool {
@@ -22,6 +32,58 @@ pub trait Material {
     fn scattering_pdf(&self, r_in: Ray, rec: &HitRecord, scattered: Ray) -> f64 {
         0.0
     }
+    // the BSDF times the cosine towards `scattered`, which ray_color weights the incoming light
+    // with. The attenuation scaled by scattering_pdf is exact for materials whose color doesn't
+    // depend on the direction
+    fn eval(&self, r_in: Ray, rec: &HitRecord, srec: &ScatterRecord, scattered: Ray) -> Vec3 {
+        srec.attenuation * self.scattering_pdf(r_in, rec, scattered)
+    }
+    // whether the scattered direction depends on the wavelength, a spectral path then carries
+    // on at its hero wavelength alone
+    fn is_dispersive(&self) -> bool {
+        false
+    }
+    // the emission at the wavelengths of a spectral path, None if the RGB of emitted is upsampled
+    fn emitted_spectrum(
+        &self,
+        _r_in: Ray,
+        _rec: &HitRecord,
+        _wavelengths: &SampledWavelengths,
+    ) -> Option<SampledSpectrum> {
+        None
+    }
+    // used in diagnostics, e.g. to report which material produced invalid samples
+    fn name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+}
+impl<M: Material + ?Sized> Material for Arc<M> {
+    fn scatter(&self, r_in: Ray, rec: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        (**self).scatter(r_in, rec, srec)
+    }
+    fn emitted(&self, r_in: Ray, rec: &HitRecord, u: f64, v: f64, p: Vec3) -> Vec3 {
+        (**self).emitted(r_in, rec, u, v, p)
+    }
+    fn scattering_pdf(&self, r_in: Ray, rec: &HitRecord, scattered: Ray) -> f64 {
+        (**self).scattering_pdf(r_in, rec, scattered)
+    }
+    fn eval(&self, r_in: Ray, rec: &HitRecord, srec: &ScatterRecord, scattered: Ray) -> Vec3 {
+        (**self).eval(r_in, rec, srec, scattered)
+    }
+    fn is_dispersive(&self) -> bool {
+        (**self).is_dispersive()
+    }
+    fn emitted_spectrum(
+        &self,
+        r_in: Ray,
+        rec: &HitRecord,
+        wavelengths: &SampledWavelengths,
+    ) -> Option<SampledSpectrum> {
+        (**self).emitted_spectrum(r_in, rec, wavelengths)
+    }
+    fn name(&self) -> &'static str {
+        (**self).name()
+    }
 }
 
 #[derive(Clone)]
@@ -73,7 +135,11 @@ impl Metal {
 impl Material for Metal {
     fn scatter(&self, r_in: Ray, rec: &HitRecord, srec: &mut ScatterRecord) -> bool {
         let reflected: Vec3 = reflect(&r_in.dir.unit(), &rec.normal);
-        srec.specular_ray = Ray::new(rec.p, reflected + random_in_unit_sphere() * self.fuzz, 0.0);
+        srec.specular_ray = Ray::new(
+            rec.p,
+            reflected + random_in_unit_sphere() * self.fuzz,
+            r_in.tm,
+        );
         srec.attenuation = self.albedo;
         srec.is_specular = true;
         srec.pdf_ptr = Box::new(CosinePdf::new(Vec3::zero())); //这里应该把指针制空的
@@ -81,24 +147,156 @@ impl Material for Metal {
     }
 }
 
+// A rough metal with GGX microfacets and a complex index of refraction eta + ik per color
+// channel, the color comes from the Fresnel reflectance alone. Unlike Metal it is energy
+// conserving and has a real PDF, so light sampling works on it. A roughness of (almost) zero is
+// a perfect mirror.
+#[derive(Clone, Debug, PartialEq, Copy)]
+pub struct Conductor {
+    pub eta: Vec3,
+    pub k: Vec3,
+    pub roughness: f64,
+}
+impl Conductor {
+    pub fn new(eta: Vec3, k: Vec3, roughness: f64) -> Self {
+        Self {
+            eta,
+            k,
+            roughness: clamp(roughness, 0.0, 1.0),
+        }
+    }
+    // measured metals reduced to the red, green and blue primaries
+    pub fn preset(name: &str, roughness: f64) -> Option<Self> {
+        let (eta, k) = match name {
+            "gold" => (
+                Vec3::new(0.143, 0.374, 1.442),
+                Vec3::new(3.983, 2.385, 1.603),
+            ),
+            "copper" => (
+                Vec3::new(0.200, 0.924, 1.102),
+                Vec3::new(3.912, 2.452, 2.142),
+            ),
+            "silver" => (
+                Vec3::new(0.155, 0.117, 0.138),
+                Vec3::new(4.828, 3.122, 2.147),
+            ),
+            "aluminum" => (
+                Vec3::new(1.657, 0.880, 0.521),
+                Vec3::new(9.224, 6.270, 4.837),
+            ),
+            _ => return None,
+        };
+        Some(Self::new(eta, k, roughness))
+    }
+    fn alpha(&self) -> f64 {
+        roughness_to_alpha(self.roughness)
+    }
+}
+impl Material for Conductor {
+    fn scatter(&self, r_in: Ray, rec: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        let unit_direction = r_in.dir.unit();
+        if self.alpha() < MIN_ALPHA {
+            let cos_theta = fmin(-unit_direction * rec.normal, 1.0);
+            srec.is_specular = true;
+            srec.specular_ray = Ray::new(rec.p, reflect(&unit_direction, &rec.normal), r_in.tm);
+            srec.attenuation = fresnel_conductor_rgb(cos_theta, self.eta, self.k);
+            return true;
+        }
+        srec.is_specular = false;
+        // the color depends on the scattered direction, eval has it
+        srec.attenuation = Vec3::ones();
+        srec.pdf_ptr = Box::new(GgxReflectionPdf::new(
+            rec.normal,
+            -unit_direction,
+            self.alpha(),
+        ));
+        true
+    }
+    fn scattering_pdf(&self, r_in: Ray, rec: &HitRecord, scattered: Ray) -> f64 {
+        GgxReflectionPdf::new(rec.normal, -r_in.dir, self.alpha()).value(scattered.dir)
+    }
+    fn eval(&self, r_in: Ray, rec: &HitRecord, _srec: &ScatterRecord, scattered: Ray) -> Vec3 {
+        let uvw = Onb::new(rec.normal);
+        let wo = uvw.worldtolocal(-r_in.dir.unit());
+        let wi = uvw.worldtolocal(scattered.dir.unit());
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return Vec3::zero();
+        }
+        let alpha = self.alpha();
+        let h = (wo + wi).unit();
+        let fresnel = fresnel_conductor_rgb(fmin(wo * h, 1.0), self.eta, self.k);
+        fresnel * (ggx_d(h, alpha) * ggx_g2(wo, wi, alpha) / (4.0 * wo.z))
+    }
+}
+
+// An index of refraction that changes with the wavelength, which is given in nanometres while
+// the coefficients are for wavelengths in micrometres as in glass catalogues.
+#[derive(Clone, Debug, PartialEq, Copy)]
+pub enum Dispersion {
+    // n = a + b / λ²
+    Cauchy { a: f64, b: f64 },
+    // n² = 1 + Σ b_i λ² / (λ² - c_i)
+    Sellmeier { b: [f64; 3], c: [f64; 3] },
+}
+impl Dispersion {
+    // the helium d line, where catalogues quote the index of refraction of a glass
+    pub const D_LINE: f64 = 587.56;
+
+    pub fn ior(&self, wavelength: f64) -> f64 {
+        let l2 = (wavelength * 1e-3) * (wavelength * 1e-3);
+        match self {
+            Dispersion::Cauchy { a, b } => a + b / l2,
+            Dispersion::Sellmeier { b, c } => {
+                (1.0 + b.iter().zip(c).map(|(b, c)| b * l2 / (l2 - c)).sum::<f64>()).sqrt()
+            }
+        }
+    }
+}
+// the index of refraction a ray sees, RGB paths use the one at the d line
+fn dispersed_ior(ref_idx: f64, dispersion: &Option<Dispersion>, r_in: Ray) -> f64 {
+    match (dispersion, r_in.wavelength) {
+        (Some(dispersion), Some(wavelength)) => dispersion.ior(wavelength),
+        _ => ref_idx,
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Copy)]
 pub struct Dielectric {
     pub ref_idx: f64,
+    // absorption coefficient of the inside per unit of length, zero for clear glass
+    pub absorption: Vec3,
+    pub dispersion: Option<Dispersion>,
 }
 impl Dielectric {
     pub fn new(ref_idx: f64) -> Self {
-        Self { ref_idx: ref_idx }
+        Self {
+            ref_idx,
+            absorption: Vec3::zero(),
+            dispersion: None,
+        }
+    }
+    pub fn with_absorption(mut self, absorption: Vec3) -> Self {
+        self.absorption = absorption;
+        self
+    }
+    // RGB paths see the index of refraction at the d line
+    pub fn dispersive(dispersion: Dispersion) -> Self {
+        Self {
+            dispersion: Some(dispersion),
+            ..Self::new(dispersion.ior(Dispersion::D_LINE))
+        }
     }
 }
 impl Material for Dielectric {
     fn scatter(&self, r_in: Ray, rec: &HitRecord, srec: &mut ScatterRecord) -> bool {
         srec.is_specular = true;
         srec.pdf_ptr = Box::new(CosinePdf::new(Vec3::zero())); //这里其实要把指针制空
-        srec.attenuation = Vec3::new(1.0, 1.0, 1.0);
+        srec.attenuation = transmittance_inside(self.absorption, r_in, rec);
+        let ref_idx = dispersed_ior(self.ref_idx, &self.dispersion, r_in);
         let etai_over_etat: f64 = if rec.front_face {
-            1.0 / self.ref_idx
+            1.0 / ref_idx
         } else {
-            self.ref_idx
+            ref_idx
         };
         let unit_direction: Vec3 = r_in.dir.unit();
         let cos_theta: f64 = if -unit_direction * rec.normal > 1.0 {
@@ -107,13 +305,15 @@ impl Material for Dielectric {
             -unit_direction * rec.normal
         };
         let sin_theta: f64 = (1.0 - cos_theta * cos_theta).sqrt();
+        // drawn before the total internal reflection test so every path consumes the same dimensions
+        let choice = sample_1d();
         if etai_over_etat * sin_theta > 1.0 {
             let reflected: Vec3 = reflect(&unit_direction, &rec.normal);
             srec.specular_ray = Ray::new(rec.p, reflected, r_in.tm);
             return true;
         }
         let reflect_prob: f64 = schlick(cos_theta, etai_over_etat);
-        if random_f64(0.0, 1.0) < reflect_prob {
+        if choice < reflect_prob {
             let reflected: Vec3 = reflect(&unit_direction, &rec.normal);
             srec.specular_ray = Ray::new(rec.p, reflected, r_in.tm);
             return true;
@@ -122,6 +322,111 @@ impl Material for Dielectric {
         srec.specular_ray = Ray::new(rec.p, refracted, r_in.tm);
         true
     }
+    fn is_dispersive(&self) -> bool {
+        self.dispersion.is_some()
+    }
+}
+// Frosted glass, GGX microfacets that reflect and refract after Walter et al., "Microfacet
+// Models for Refraction through Rough Surfaces" (2007). Like Dielectric the radiance isn't
+// rescaled when it crosses the surface. A roughness of (almost) zero is smooth glass.
+#[derive(Clone, Debug, PartialEq, Copy)]
+pub struct RoughDielectric {
+    pub ref_idx: f64,
+    pub roughness: f64,
+    pub absorption: Vec3,
+    pub dispersion: Option<Dispersion>,
+}
+impl RoughDielectric {
+    pub fn new(ref_idx: f64, roughness: f64) -> Self {
+        Self {
+            ref_idx,
+            roughness: clamp(roughness, 0.0, 1.0),
+            absorption: Vec3::zero(),
+            dispersion: None,
+        }
+    }
+    pub fn with_absorption(mut self, absorption: Vec3) -> Self {
+        self.absorption = absorption;
+        self
+    }
+    // RGB paths see the index of refraction at the d line
+    pub fn dispersive(dispersion: Dispersion, roughness: f64) -> Self {
+        Self {
+            dispersion: Some(dispersion),
+            ..Self::new(dispersion.ior(Dispersion::D_LINE), roughness)
+        }
+    }
+    fn alpha(&self) -> f64 {
+        roughness_to_alpha(self.roughness)
+    }
+    // the index of refraction behind the surface over the one in front
+    fn eta(&self, r_in: Ray, rec: &HitRecord) -> f64 {
+        let ref_idx = dispersed_ior(self.ref_idx, &self.dispersion, r_in);
+        if rec.front_face {
+            ref_idx
+        } else {
+            1.0 / ref_idx
+        }
+    }
+}
+impl Material for RoughDielectric {
+    fn scatter(&self, r_in: Ray, rec: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        if self.alpha() < MIN_ALPHA {
+            let smooth = Dielectric {
+                ref_idx: self.ref_idx,
+                absorption: self.absorption,
+                dispersion: self.dispersion,
+            };
+            return smooth.scatter(r_in, rec, srec);
+        }
+        srec.is_specular = false;
+        srec.attenuation = transmittance_inside(self.absorption, r_in, rec);
+        srec.pdf_ptr = Box::new(GgxDielectricPdf::new(
+            rec.normal,
+            -r_in.dir,
+            self.alpha(),
+            self.eta(r_in, rec),
+        ));
+        true
+    }
+    fn scattering_pdf(&self, r_in: Ray, rec: &HitRecord, scattered: Ray) -> f64 {
+        GgxDielectricPdf::new(rec.normal, -r_in.dir, self.alpha(), self.eta(r_in, rec))
+            .value(scattered.dir)
+    }
+    fn eval(&self, r_in: Ray, rec: &HitRecord, srec: &ScatterRecord, scattered: Ray) -> Vec3 {
+        let uvw = Onb::new(rec.normal);
+        let wo = uvw.worldtolocal(-r_in.dir.unit());
+        let wi = uvw.worldtolocal(scattered.dir.unit());
+        match ggx_dielectric_bsdf(wo, wi, self.alpha(), self.eta(r_in, rec)) {
+            Some((value, _)) => srec.attenuation * value,
+            None => Vec3::zero(),
+        }
+    }
+    fn is_dispersive(&self) -> bool {
+        self.dispersion.is_some()
+    }
+}
+
+// Beer-Lambert attenuation of a ray that hit the surface from inside, front_face is false when
+// it travelled through the object from where it entered
+pub fn transmittance_inside(absorption: Vec3, r_in: Ray, rec: &HitRecord) -> Vec3 {
+    if rec.front_face {
+        return Vec3::ones();
+    }
+    let distance = rec.t * r_in.dir.length();
+    Vec3::new(
+        (-absorption.x * distance).exp(),
+        (-absorption.y * distance).exp(),
+        (-absorption.z * distance).exp(),
+    )
+}
+// the absorption coefficient that lets `color` through after `distance`
+pub fn absorption_from_transmittance(color: Vec3, distance: f64) -> Vec3 {
+    Vec3::new(
+        -color.x.ln() / distance,
+        -color.y.ln() / distance,
+        -color.z.ln() / distance,
+    )
 }
 pub fn schlick(cosine: f64, ref_idx: f64) -> f64 {
     let mut r0: f64 = (1.0 - ref_idx) / (1.0 + ref_idx);
@@ -129,6 +434,333 @@ pub fn schlick(cosine: f64, ref_idx: f64) -> f64 {
     r0 + (1.0 - r0) * f64::powf(1.0 - cosine, 5.0)
 }
 
+// Burley's principled BSDF, "Physically Based Shading at Disney" (2012) with the transmission of
+// its 2015 extension. A diffuse lobe with retro-reflection and sheen, an anisotropic GGX
+// specular lobe, a GTR1 clearcoat and rough glass are blended by the parameters, every one of
+// which is a texture. Scalar parameters read the mean of the texture's channels and are in [0, 1]
+// except the index of refraction.
+#[derive(Clone)]
+pub struct Principled {
+    pub base_color: Arc<dyn Texture>,
+    pub metallic: Arc<dyn Texture>,
+    pub roughness: Arc<dyn Texture>,
+    // reflectance of the dielectric base at normal incidence, 0.5 is 4%
+    pub specular: Arc<dyn Texture>,
+    // how much of the base color the dielectric specular takes on
+    pub specular_tint: Arc<dyn Texture>,
+    pub sheen: Arc<dyn Texture>,
+    pub sheen_tint: Arc<dyn Texture>,
+    pub clearcoat: Arc<dyn Texture>,
+    pub clearcoat_gloss: Arc<dyn Texture>,
+    // the fraction of the dielectric base that is glass instead of diffuse
+    pub transmission: Arc<dyn Texture>,
+    pub ior: Arc<dyn Texture>,
+    pub anisotropic: Arc<dyn Texture>,
+}
+// the parameters at one point of the surface
+struct PrincipledParams {
+    base_color: Vec3,
+    metallic: f64,
+    roughness: f64,
+    specular: f64,
+    specular_tint: f64,
+    sheen: f64,
+    sheen_tint: f64,
+    clearcoat: f64,
+    clearcoat_gloss: f64,
+    transmission: f64,
+    ior: f64,
+    anisotropic: f64,
+}
+fn schlick_weight(cosine: f64) -> f64 {
+    let m = clamp(1.0 - cosine, 0.0, 1.0);
+    m * m * m * m * m
+}
+impl PrincipledParams {
+    fn alpha(&self) -> f64 {
+        roughness_to_alpha(self.roughness).max(MIN_ALPHA)
+    }
+    fn clearcoat_alpha(&self) -> f64 {
+        0.1 + (0.001 - 0.1) * self.clearcoat_gloss
+    }
+    // the hue of the base color without its brightness
+    fn tint(&self) -> Vec3 {
+        let l = luminance(self.base_color);
+        if l > 0.0 {
+            self.base_color / l
+        } else {
+            Vec3::ones()
+        }
+    }
+    fn specular_color(&self) -> Vec3 {
+        let tint = Vec3::ones() + (self.tint() - Vec3::ones()) * self.specular_tint;
+        let dielectric = tint * (0.08 * self.specular);
+        dielectric + (self.base_color - dielectric) * self.metallic
+    }
+    // diffuse, specular, clearcoat and transmission
+    fn lobe_weights(&self) -> [f64; 4] {
+        let dielectric = 1.0 - self.metallic;
+        [
+            dielectric * (1.0 - self.transmission),
+            1.0 - dielectric * self.transmission,
+            0.25 * self.clearcoat,
+            dielectric * self.transmission,
+        ]
+    }
+    fn pdf(&self, normal: Vec3, wo: Vec3, eta: f64) -> PrincipledPdf {
+        // lobes are drawn by how much they reflect towards `wo`, never below a tenth of their
+        // weight so each one can still be picked
+        let cos_o = normal * wo.unit();
+        let weights = self.lobe_weights();
+        let specular = Vec3::ones() - self.specular_color();
+        let specular = self.specular_color() + specular * schlick_weight(cos_o);
+        let clearcoat = 0.04 + 0.96 * schlick_weight(cos_o);
+        PrincipledPdf::new(
+            normal,
+            wo,
+            [
+                weights[0] * luminance(self.base_color).max(0.1),
+                weights[1] * luminance(specular).max(0.1),
+                weights[2] * clearcoat.max(0.1),
+                weights[3],
+            ],
+            self.alpha(),
+            self.anisotropic,
+            self.clearcoat_alpha(),
+            eta,
+        )
+    }
+}
+impl Principled {
+    // the defaults of Burley's paper, a rough white plastic
+    pub fn new(base_color: Arc<dyn Texture>) -> Self {
+        let constant = |x: f64| -> Arc<dyn Texture> { Arc::new(SolidColor::new2(x, x, x)) };
+        Self {
+            base_color,
+            metallic: constant(0.0),
+            roughness: constant(0.5),
+            specular: constant(0.5),
+            specular_tint: constant(0.0),
+            sheen: constant(0.0),
+            sheen_tint: constant(0.5),
+            clearcoat: constant(0.0),
+            clearcoat_gloss: constant(1.0),
+            transmission: constant(0.0),
+            ior: constant(1.5),
+            anisotropic: constant(0.0),
+        }
+    }
+    fn params(&self, rec: &HitRecord) -> PrincipledParams {
+        let value = |texture: &Arc<dyn Texture>| texture.value(rec.u, rec.v, rec.p);
+        let scalar = |texture: &Arc<dyn Texture>| {
+            let c = value(texture);
+            (c.x + c.y + c.z) / 3.0
+        };
+        let unit = |texture: &Arc<dyn Texture>| clamp(scalar(texture), 0.0, 1.0);
+        PrincipledParams {
+            base_color: value(&self.base_color),
+            metallic: unit(&self.metallic),
+            roughness: unit(&self.roughness),
+            specular: unit(&self.specular),
+            specular_tint: unit(&self.specular_tint),
+            sheen: unit(&self.sheen),
+            sheen_tint: unit(&self.sheen_tint),
+            clearcoat: unit(&self.clearcoat),
+            clearcoat_gloss: unit(&self.clearcoat_gloss),
+            transmission: unit(&self.transmission),
+            ior: scalar(&self.ior).max(1.0),
+            anisotropic: unit(&self.anisotropic),
+        }
+    }
+    // the index of refraction behind the surface over the one in front
+    fn eta(params: &PrincipledParams, rec: &HitRecord) -> f64 {
+        if rec.front_face {
+            params.ior
+        } else {
+            1.0 / params.ior
+        }
+    }
+}
+impl Material for Principled {
+    fn scatter(&self, r_in: Ray, rec: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        let params = self.params(rec);
+        srec.is_specular = false;
+        // the color depends on the scattered direction, eval has it
+        srec.attenuation = Vec3::ones();
+        srec.pdf_ptr = Box::new(params.pdf(rec.normal, -r_in.dir, Self::eta(&params, rec)));
+        true
+    }
+    fn scattering_pdf(&self, r_in: Ray, rec: &HitRecord, scattered: Ray) -> f64 {
+        let params = self.params(rec);
+        params
+            .pdf(rec.normal, -r_in.dir, Self::eta(&params, rec))
+            .value(scattered.dir)
+    }
+    fn eval(&self, r_in: Ray, rec: &HitRecord, _srec: &ScatterRecord, scattered: Ray) -> Vec3 {
+        let params = self.params(rec);
+        let uvw = Onb::new(rec.normal);
+        let wo = uvw.worldtolocal(-r_in.dir.unit());
+        let wi = uvw.worldtolocal(scattered.dir.unit());
+        if wo.z <= 0.0 {
+            return Vec3::zero();
+        }
+        let weights = params.lobe_weights();
+        let mut value = Vec3::zero();
+        if wi.z > 0.0 {
+            let h = (wo + wi).unit();
+            let cos_d = wi * h;
+            // Burley's diffuse with its retro-reflection at grazing angles, and the sheen
+            let fd90 = 0.5 + 2.0 * params.roughness * cos_d * cos_d;
+            let fd = (1.0 + (fd90 - 1.0) * schlick_weight(wi.z))
+                * (1.0 + (fd90 - 1.0) * schlick_weight(wo.z));
+            let sheen_color = Vec3::ones() + (params.tint() - Vec3::ones()) * params.sheen_tint;
+            let sheen = sheen_color * (params.sheen * schlick_weight(cos_d));
+            value += (params.base_color * (fd / PI) + sheen) * (weights[0] * wi.z);
+
+            let (ax, ay) = anisotropic_alphas(params.alpha(), params.anisotropic);
+            let specular_color = params.specular_color();
+            let fresnel = specular_color + (Vec3::ones() - specular_color) * schlick_weight(cos_d);
+            let dg = ggx_d_aniso(h, ax, ay) * ggx_g2_aniso(wo, wi, ax, ay);
+            value += fresnel * (weights[1] * dg / (4.0 * wo.z));
+
+            let clearcoat_fresnel = 0.04 + 0.96 * schlick_weight(cos_d);
+            let clearcoat_dg =
+                gtr1_d(h, params.clearcoat_alpha()) * ggx_g1(wo, 0.25) * ggx_g1(wi, 0.25);
+            value += Vec3::ones() * (weights[2] * clearcoat_fresnel * clearcoat_dg / (4.0 * wo.z));
+        }
+        if weights[3] > 0.0 {
+            let eta = Self::eta(&params, rec);
+            if let Some((glass, reflection)) = ggx_dielectric_bsdf(wo, wi, params.alpha(), eta) {
+                // tinted on the way in and out, so light passing through takes on the base color
+                let color = if reflection {
+                    Vec3::ones()
+                } else {
+                    Vec3::new(
+                        params.base_color.x.sqrt(),
+                        params.base_color.y.sqrt(),
+                        params.base_color.z.sqrt(),
+                    )
+                };
+                value += color * (weights[3] * glass);
+            }
+        }
+        value
+    }
+}
+
+// the most reflections between the coat and its base before the light counts as absorbed
+const COAT_BOUNCES: usize = 16;
+
+// A smooth dielectric coat over another material, like varnish on wood, the clearcoat of car
+// paint or plastic. Light reflects off the coat by its Fresnel reflectance or refracts into it,
+// crosses the layer, scatters off the base and bounces between the two until it leaves. That
+// walk is sampled rather than integrated, each scattered ray is a sample of the whole layered
+// BSDF, so like Dielectric it is specular to the integrator.
+#[derive(Clone)]
+pub struct Coated {
+    pub base: Arc<dyn Material>,
+    pub ior: f64,
+    // absorption coefficient inside the coat and its thickness, clear if either is zero
+    pub absorption: Vec3,
+    pub thickness: f64,
+}
+impl Coated {
+    pub fn new(base: Arc<dyn Material>, ior: f64) -> Self {
+        Self {
+            base,
+            ior,
+            absorption: Vec3::zero(),
+            thickness: 0.0,
+        }
+    }
+    pub fn with_absorption(mut self, absorption: Vec3, thickness: f64) -> Self {
+        self.absorption = absorption;
+        self.thickness = thickness;
+        self
+    }
+    // Beer-Lambert attenuation of one crossing of the coat along `direction`
+    fn layer_transmittance(&self, direction: Vec3, normal: Vec3) -> Vec3 {
+        let cosine = (direction.unit() * normal).abs().max(1e-6);
+        let distance = self.thickness / cosine;
+        Vec3::new(
+            (-self.absorption.x * distance).exp(),
+            (-self.absorption.y * distance).exp(),
+            (-self.absorption.z * distance).exp(),
+        )
+    }
+}
+impl Material for Coated {
+    fn scatter(&self, r_in: Ray, rec: &HitRecord, srec: &mut ScatterRecord) -> bool {
+        let normal = rec.normal;
+        let unit_direction = r_in.dir.unit();
+        srec.is_specular = true;
+        srec.pdf_ptr = Box::new(CosinePdf::new(Vec3::zero()));
+        srec.attenuation = Vec3::ones();
+        let choice = sample_1d();
+        let mut direction = match refract_about(-unit_direction, normal, self.ior) {
+            Some(refracted) if choice >= fresnel_dielectric(-unit_direction * normal, self.ior) => {
+                refracted
+            }
+            _ => {
+                srec.specular_ray = Ray::new(rec.p, reflect(&unit_direction, &normal), r_in.tm);
+                return true;
+            }
+        };
+        let mut throughput = Vec3::ones();
+        for _ in 0..COAT_BOUNCES {
+            // down through the coat onto the base
+            throughput = Vec3::elemul(throughput, self.layer_transmittance(direction, normal));
+            let base_in = Ray::new(rec.p, direction, r_in.tm).with_wavelength(r_in.wavelength);
+            let mut base = ScatterRecord {
+                specular_ray: base_in,
+                is_specular: true,
+                attenuation: Vec3::zero(),
+                pdf_ptr: Box::new(CosinePdf::new(Vec3::zero())),
+            };
+            if !self.base.scatter(base_in, rec, &mut base) {
+                return false;
+            }
+            if base.is_specular {
+                throughput = Vec3::elemul(throughput, base.attenuation);
+                direction = base.specular_ray.dir.unit();
+            } else {
+                let scattered = Ray::new(rec.p, base.pdf_ptr.generate(), r_in.tm)
+                    .with_wavelength(r_in.wavelength);
+                let pdf = base.pdf_ptr.value(scattered.dir);
+                if pdf <= 0.0 {
+                    return false;
+                }
+                let bsdf = self.base.eval(base_in, rec, &base, scattered);
+                throughput = Vec3::elemul(throughput, bsdf) / pdf;
+                direction = scattered.dir.unit();
+            }
+            // a base that transmits, e.g. glass, lets the light leave below
+            if direction * normal <= 0.0 {
+                srec.specular_ray = Ray::new(rec.p, direction, r_in.tm);
+                srec.attenuation = throughput;
+                return true;
+            }
+            // up through the coat, out of it or reflected back onto the base
+            throughput = Vec3::elemul(throughput, self.layer_transmittance(direction, normal));
+            let choice = sample_1d();
+            match refract_about(-direction, -normal, 1.0 / self.ior) {
+                Some(refracted)
+                    if choice >= fresnel_dielectric(direction * normal, 1.0 / self.ior) =>
+                {
+                    srec.specular_ray = Ray::new(rec.p, refracted, r_in.tm);
+                    srec.attenuation = throughput;
+                    return true;
+                }
+                _ => direction = reflect(&direction, &normal),
+            }
+        }
+        false
+    }
+    fn is_dispersive(&self) -> bool {
+        self.base.is_dispersive()
+    }
+}
+
 #[derive(Clone)]
 pub struct DiffuseLight<T: Texture> {
     emit: T,
@@ -157,6 +789,47 @@ impl<T: Texture> Material for DiffuseLight<T> {
         }
     }
 }
+// A light with a physical spectrum, e.g. a blackbody or a measured lamp, scaled to the given
+// luminance. The RGB integrator sees the color of the spectrum, the spectral one the spectrum.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpectralLight {
+    pub spd: Spd,
+    pub scale: f64,
+    rgb: Vec3,
+}
+impl SpectralLight {
+    pub fn new(spd: Spd, luminance: f64) -> Self {
+        let rgb = spd.to_rgb();
+        let y = tonemap::luminance(rgb);
+        let scale = if y > 0.0 { luminance / y } else { 0.0 };
+        Self {
+            rgb: rgb * scale,
+            spd,
+            scale,
+        }
+    }
+}
+impl Material for SpectralLight {
+    fn emitted(&self, _r_in: Ray, rec: &HitRecord, _u: f64, _v: f64, _p: Vec3) -> Vec3 {
+        if rec.front_face {
+            self.rgb
+        } else {
+            Vec3::zero()
+        }
+    }
+    fn emitted_spectrum(
+        &self,
+        _r_in: Ray,
+        rec: &HitRecord,
+        wavelengths: &SampledWavelengths,
+    ) -> Option<SampledSpectrum> {
+        if rec.front_face {
+            Some(self.spd.sample(wavelengths) * self.scale)
+        } else {
+            Some(SampledSpectrum::zero())
+        }
+    }
+}
 #[derive(Clone)]
 pub struct Isotropic<T: Texture> {
     albedo: T,
@@ -186,3 +859,241 @@ pub struct ScatterRecord {
     pub attenuation: Vec3,
     pub pdf_ptr: Box<dyn Pdf>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::start_sample;
+
+    const NORMAL: Vec3 = Vec3 {
+        x: 0.0,
+        y: 0.0,
+        z: 1.0,
+    };
+    const SAMPLES: usize = 20_000;
+
+    fn hit(material: &dyn Material) -> HitRecord<'_> {
+        HitRecord {
+            p: Vec3::zero(),
+            normal: NORMAL,
+            mat_ptr: material,
+            t: 1.0,
+            u: 0.5,
+            v: 0.5,
+            front_face: true,
+        }
+    }
+    // a ray that arrives at the origin from `wo`
+    fn incoming(wo: Vec3) -> Ray {
+        Ray::new(wo, -wo, 0.0)
+    }
+    // A one sample estimate of the light `material` reflects towards `wo` under a white sky:
+    // the attenuation of a specular bounce or the BSDF over the density of a sampled direction.
+    fn throughput(material: &dyn Material, wo: Vec3) -> Vec3 {
+        let r_in = incoming(wo);
+        let rec = hit(material);
+        let mut srec = ScatterRecord {
+            specular_ray: r_in,
+            is_specular: true,
+            attenuation: Vec3::zero(),
+            pdf_ptr: Box::new(CosinePdf::new(Vec3::zero())),
+        };
+        if !material.scatter(r_in, &rec, &mut srec) {
+            return Vec3::zero();
+        }
+        if srec.is_specular {
+            return srec.attenuation;
+        }
+        let scattered = Ray::new(rec.p, srec.pdf_ptr.generate(), r_in.tm);
+        let pdf = srec.pdf_ptr.value(scattered.dir);
+        if pdf == 0.0 {
+            return Vec3::zero();
+        }
+        material.eval(r_in, &rec, &srec, scattered) / pdf
+    }
+    // every estimate passed to `check` and their mean
+    fn furnace<F: FnMut(Vec3)>(material: &dyn Material, wo: Vec3, mut check: F) -> Vec3 {
+        start_sample(5, 6, 7);
+        let mut sum = Vec3::zero();
+        for _ in 0..SAMPLES {
+            let t = throughput(material, wo);
+            check(t);
+            sum += t;
+        }
+        sum / SAMPLES as f64
+    }
+    fn assert_at_most_one(t: Vec3) {
+        assert!(
+            t.x <= 1.0 + 1e-9 && t.y <= 1.0 + 1e-9 && t.z <= 1.0 + 1e-9,
+            "{:?}",
+            t
+        );
+    }
+    fn outgoing() -> [Vec3; 3] {
+        [
+            NORMAL,
+            Vec3::new(0.6, 0.0, 0.8),
+            Vec3::new(0.95, 0.0, 0.31).unit(),
+        ]
+    }
+
+    #[test]
+    fn perfect_conductor_never_reflects_more_than_it_receives() {
+        // a huge extinction coefficient reflects everything at any angle, the single scattering
+        // model only loses the light that bounces between the microfacets
+        for &roughness in &[0.05, 0.3, 0.6, 1.0] {
+            let conductor = Conductor::new(Vec3::ones(), Vec3::ones() * 1e4, roughness);
+            for &wo in &outgoing() {
+                let mean = furnace(&conductor, wo, assert_at_most_one);
+                assert!(mean.x <= 1.0, "roughness {}: {:?}", roughness, mean);
+                if roughness <= 0.3 {
+                    assert!(mean.x > 0.9, "roughness {}: {:?}", roughness, mean);
+                }
+            }
+        }
+    }
+
+    fn constant(x: f64) -> Arc<dyn Texture> {
+        Arc::new(SolidColor::new2(x, x, x))
+    }
+    // the defaults, each of the parameters that change the lobe weights at its ends, and mixes
+    fn principled_variants() -> Vec<Principled> {
+        let base = Principled::new(Arc::new(SolidColor::new2(0.8, 0.4, 0.2)));
+        let mut variants = vec![
+            base.clone(),
+            Principled {
+                roughness: constant(0.0),
+                ..base.clone()
+            },
+            Principled {
+                sheen: constant(1.0),
+                specular_tint: constant(1.0),
+                ..base.clone()
+            },
+        ];
+        for &(metallic, transmission, anisotropic, clearcoat) in &[
+            (1.0, 0.0, 0.0, 0.0),
+            (0.0, 1.0, 0.0, 0.0),
+            (0.0, 0.0, 1.0, 0.0),
+            (0.0, 0.0, 0.0, 1.0),
+            (1.0, 1.0, 1.0, 1.0),
+            (0.5, 0.5, 0.5, 0.5),
+        ] {
+            variants.push(Principled {
+                metallic: constant(metallic),
+                transmission: constant(transmission),
+                anisotropic: constant(anisotropic),
+                clearcoat: constant(clearcoat),
+                ..base.clone()
+            });
+        }
+        variants
+    }
+    // directions all over the sphere, away from the poles
+    fn directions() -> Vec<Vec3> {
+        let mut directions = Vec::new();
+        for i in 0..12 {
+            let z = -1.0 + (i as f64 + 0.5) / 6.0;
+            let r = (1.0 - z * z).sqrt();
+            for j in 0..24 {
+                let phi = j as f64 / 24.0 * 2.0 * PI;
+                directions.push(Vec3::new(r * phi.cos(), r * phi.sin(), z));
+            }
+        }
+        directions
+    }
+
+    #[test]
+    fn principled_sampling_pdf_is_its_scattering_pdf() {
+        for material in &principled_variants() {
+            for &front_face in &[true, false] {
+                for &wo in &outgoing() {
+                    let r_in = incoming(wo);
+                    let rec = HitRecord {
+                        front_face,
+                        ..hit(material)
+                    };
+                    let mut srec = ScatterRecord {
+                        specular_ray: r_in,
+                        is_specular: true,
+                        attenuation: Vec3::zero(),
+                        pdf_ptr: Box::new(CosinePdf::new(Vec3::zero())),
+                    };
+                    assert!(material.scatter(r_in, &rec, &mut srec) && !srec.is_specular);
+                    for &d in &directions() {
+                        let sampled = srec.pdf_ptr.value(d);
+                        let scattering =
+                            material.scattering_pdf(r_in, &rec, Ray::new(rec.p, d, 0.0));
+                        assert!(sampled.is_finite() && sampled >= 0.0);
+                        assert!((sampled - scattering).abs() <= 1e-12 * sampled.max(1.0));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn principled_eval_is_finite_and_not_negative() {
+        let valid = |c: Vec3| {
+            assert!(
+                c.x.is_finite() && c.y.is_finite() && c.z.is_finite(),
+                "{:?}",
+                c
+            );
+            assert!(c.x >= 0.0 && c.y >= 0.0 && c.z >= 0.0, "{:?}", c);
+        };
+        for material in &principled_variants() {
+            for &wo in &outgoing() {
+                let r_in = incoming(wo);
+                let rec = hit(material);
+                let srec = ScatterRecord {
+                    specular_ray: r_in,
+                    is_specular: false,
+                    attenuation: Vec3::ones(),
+                    pdf_ptr: Box::new(CosinePdf::new(NORMAL)),
+                };
+                for &d in &directions() {
+                    valid(material.eval(r_in, &rec, &srec, Ray::new(rec.p, d, 0.0)));
+                }
+                // and every sampled direction gets a usable weight
+                furnace(material, wo, valid);
+            }
+        }
+    }
+
+    #[test]
+    fn clear_coat_over_white_keeps_the_light() {
+        let coated = Coated::new(Arc::new(Lambertian::new2(Vec3::ones())), 1.5);
+        for &wo in &outgoing() {
+            let mean = furnace(&coated, wo, assert_at_most_one);
+            assert!(mean.x > 0.99, "{:?}", mean);
+        }
+    }
+
+    #[test]
+    fn absorbing_coat_darkens_by_beer_lambert() {
+        // Straight down onto a mirror every trip through the coat is two crossings of its
+        // thickness. The light reflects off the top with probability f, or goes down and up k
+        // times before it leaves, reflected back from inside in between with probability f too.
+        let absorption = Vec3::new(0.5, 1.0, 2.0);
+        let thickness = 0.3;
+        let coated = Coated::new(Arc::new(Metal::new(Vec3::ones(), 0.0)), 1.5)
+            .with_absorption(absorption, thickness);
+        let mean = furnace(&coated, NORMAL, assert_at_most_one);
+        let f = fresnel_dielectric(1.0, 1.5);
+        for &(channel, sigma) in &[
+            (mean.x, absorption.x),
+            (mean.y, absorption.y),
+            (mean.z, absorption.z),
+        ] {
+            let round_trip = (-2.0 * sigma * thickness).exp();
+            let expected = f + (1.0 - f) * (1.0 - f) * round_trip / (1.0 - f * round_trip);
+            assert!(
+                (channel - expected).abs() < 0.01,
+                "{} {}",
+                channel,
+                expected
+            );
+        }
+    }
+}
@@ -1,10 +1,14 @@
 use crate::aabb::surrounding_box;
 use crate::materialfile::Material;
+use crate::onb::Onb;
+use crate::spherefile::random_to_sphere;
+use crate::stats::{count_intersection, count_ray};
 use crate::HitRecord;
 use crate::Hittable;
 use crate::Ray;
 use crate::Vec3;
 use crate::AABB;
+use std::f64::consts::PI;
 
 #[derive(Clone)]
 pub struct MovingSphere<T: Material> {
@@ -33,7 +37,8 @@ impl<T: Material> MovingSphere<T> {
 }
 
 impl<T: Material> Hittable for MovingSphere<T> {
-    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        count_intersection();
         let oc: Vec3 = r.orig - self.center(r.tm);
         let a: f64 = r.dir.squared_length();
         let half_b: f64 = oc * r.dir;
@@ -76,4 +81,23 @@ impl<T: Material> Hittable for MovingSphere<T> {
         *output_box = surrounding_box(box0, box1);
         true
     }
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
+        count_ray();
+        if self
+            .hit(Ray::new(o, v, time), 0.001, f64::INFINITY)
+            .is_none()
+        {
+            return 0.0;
+        }
+        let cos_theta_max =
+            (1.0 - self.radius * self.radius / (self.center(time) - o).squared_length()).sqrt();
+        let solid_angle = 2.0 * PI * (1.0 - cos_theta_max);
+        1.0 / solid_angle
+    }
+    fn random(&self, o: Vec3, time: f64) -> Vec3 {
+        let direction = self.center(time) - o;
+        let distance_squared = direction.squared_length();
+        let uvw = Onb::new(direction);
+        uvw.localbyvector(random_to_sphere(self.radius, distance_squared))
+    }
 }
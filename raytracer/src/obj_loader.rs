@@ -0,0 +1,420 @@
+use crate::materialfile::Metal;
+use crate::texture::ImageTexture;
+use crate::triangle::{MeshData, TriangleMesh};
+use crate::HittableList;
+use crate::Material;
+use crate::Vec3;
+use crate::{Dielectric, DiffuseLight, Lambertian};
+use std::boxed::Box;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::vec::Vec;
+
+#[derive(Debug)]
+pub enum ObjError {
+    Io(String, std::io::Error),
+    Parse {
+        path: String,
+        line: usize,
+        message: String,
+    },
+}
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjError::Io(path, err) => write!(f, "cannot read `{}`: {}", path, err),
+            ObjError::Parse {
+                path,
+                line,
+                message,
+            } => write!(f, "{}:{}: {}", path, line, message),
+        }
+    }
+}
+impl std::error::Error for ObjError {}
+
+struct LineContext<'a> {
+    path: &'a str,
+    line: usize,
+}
+impl<'a> LineContext<'a> {
+    fn error<T>(&self, message: String) -> Result<T, ObjError> {
+        Err(ObjError::Parse {
+            path: self.path.to_string(),
+            line: self.line,
+            message,
+        })
+    }
+    fn number(&self, token: Option<&str>, keyword: &str) -> Result<f64, ObjError> {
+        match token {
+            None => self.error(format!("`{}` is missing a component", keyword)),
+            Some(token) => match token.parse() {
+                Ok(x) => Ok(x),
+                Err(_) => self.error(format!("expected a number, found `{}`", token)),
+            },
+        }
+    }
+    fn vec3(&self, tokens: &[&str], keyword: &str) -> Result<Vec3, ObjError> {
+        if tokens.len() > 3 && keyword != "v" {
+            return self.error(format!("`{}` takes three components", keyword));
+        }
+        Ok(Vec3::new(
+            self.number(tokens.first().copied(), keyword)?,
+            self.number(tokens.get(1).copied(), keyword)?,
+            self.number(tokens.get(2).copied(), keyword)?,
+        ))
+    }
+    // OBJ indices are 1-based, negative ones count back from the last element read so far
+    fn index(&self, token: &str, count: usize, what: &str) -> Result<usize, ObjError> {
+        let i: i64 = match token.parse() {
+            Ok(i) => i,
+            Err(_) => return self.error(format!("invalid {} index `{}`", what, token)),
+        };
+        let resolved = if i > 0 { i - 1 } else { count as i64 + i };
+        if i == 0 || resolved < 0 || resolved >= count as i64 {
+            return self.error(format!(
+                "{} index {} is out of range ({} defined)",
+                what, i, count
+            ));
+        }
+        Ok(resolved as usize)
+    }
+}
+
+fn read_file(path: &Path) -> Result<String, ObjError> {
+    match fs::read_to_string(path) {
+        Ok(source) => Ok(source),
+        Err(err) => Err(ObjError::Io(path.display().to_string(), err)),
+    }
+}
+
+// the last token of a map statement is the file name, everything before it is an option
+fn map_file<'a>(context: &LineContext, tokens: &[&'a str]) -> Result<&'a str, ObjError> {
+    match tokens.last() {
+        Some(file) => Ok(file),
+        None => context.error("texture statement is missing a file name".to_string()),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct MtlDescription {
+    kd: Vec3,
+    ks: Vec3,
+    ke: Vec3,
+    ns: f64,
+    ni: f64,
+    dissolve: f64,
+    illum: i32,
+    map_kd: Option<String>,
+    map_kd_line: usize,
+}
+impl Default for MtlDescription {
+    fn default() -> Self {
+        Self {
+            kd: Vec3::new(0.8, 0.8, 0.8),
+            ks: Vec3::zero(),
+            ke: Vec3::zero(),
+            ns: 0.0,
+            ni: 1.5,
+            dissolve: 1.0,
+            illum: 2,
+            map_kd: None,
+            map_kd_line: 0,
+        }
+    }
+}
+
+fn is_black(c: Vec3) -> bool {
+    c.x <= 0.0 && c.y <= 0.0 && c.z <= 0.0
+}
+
+fn build_mtl(mtl: &MtlDescription, path: &str, dir: &Path) -> Result<Arc<dyn Material>, ObjError> {
+    if !is_black(mtl.ke) {
+        return Ok(Arc::new(DiffuseLight::new2(mtl.ke)));
+    }
+    if mtl.dissolve < 1.0 || [4, 6, 7, 9].contains(&mtl.illum) {
+        return Ok(Arc::new(Dielectric::new(mtl.ni)));
+    }
+    if !is_black(mtl.ks) && (mtl.illum == 3 || (is_black(mtl.kd) && mtl.map_kd.is_none())) {
+        // Phong exponent to a roughness in [0, 1]
+        let fuzz = (2.0 / (mtl.ns.max(0.0) + 2.0)).sqrt();
+        return Ok(Arc::new(Metal::new(mtl.ks, fuzz)));
+    }
+    if let Some(file) = &mtl.map_kd {
+        let texture_path = dir.join(file);
+        return match ImageTexture::open(&texture_path.to_string_lossy()) {
+            Ok(texture) => Ok(Arc::new(Lambertian::new1(texture))),
+            Err(err) => LineContext {
+                path,
+                line: mtl.map_kd_line,
+            }
+            .error(format!(
+                "cannot open texture `{}`: {}",
+                texture_path.display(),
+                err
+            )),
+        };
+    }
+    Ok(Arc::new(Lambertian::new2(mtl.kd)))
+}
+
+pub fn load_mtl(path: &Path) -> Result<HashMap<String, Arc<dyn Material>>, ObjError> {
+    let source = read_file(path)?;
+    let path_name = path.display().to_string();
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut descriptions: Vec<(String, MtlDescription)> = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let context = LineContext {
+            path: &path_name,
+            line: i + 1,
+        };
+        let line = line.split('#').next().unwrap_or("");
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let (keyword, args) = match tokens.split_first() {
+            Some((keyword, args)) => (*keyword, args),
+            None => continue,
+        };
+        if keyword == "newmtl" {
+            if args.is_empty() {
+                return context.error("`newmtl` is missing a name".to_string());
+            }
+            descriptions.push((args.join(" "), MtlDescription::default()));
+            continue;
+        }
+        let mtl = match descriptions.last_mut() {
+            Some((_, mtl)) => mtl,
+            None => return context.error(format!("`{}` before any `newmtl`", keyword)),
+        };
+        match keyword {
+            "Kd" => mtl.kd = context.vec3(args, keyword)?,
+            "Ks" => mtl.ks = context.vec3(args, keyword)?,
+            "Ke" => mtl.ke = context.vec3(args, keyword)?,
+            "Ns" => mtl.ns = context.number(args.first().copied(), keyword)?,
+            "Ni" => mtl.ni = context.number(args.first().copied(), keyword)?,
+            "d" => mtl.dissolve = context.number(args.last().copied(), keyword)?,
+            "Tr" => mtl.dissolve = 1.0 - context.number(args.first().copied(), keyword)?,
+            "illum" => mtl.illum = context.number(args.first().copied(), keyword)? as i32,
+            "map_Kd" => {
+                mtl.map_kd = Some(map_file(&context, args)?.to_string());
+                mtl.map_kd_line = context.line;
+            }
+            _ => {}
+        }
+    }
+    let mut materials = HashMap::new();
+    for (name, mtl) in &descriptions {
+        materials.insert(name.clone(), build_mtl(mtl, &path_name, dir)?);
+    }
+    Ok(materials)
+}
+
+#[derive(Default)]
+struct MeshBuilder {
+    mesh: MeshData,
+    vertices: HashMap<(usize, Option<usize>, Option<usize>), usize>,
+    missing_normals: bool,
+}
+
+pub fn load_obj(
+    path: &str,
+    material_override: Option<Arc<dyn Material>>,
+) -> Result<HittableList, ObjError> {
+    let obj_path = Path::new(path);
+    let source = read_file(obj_path)?;
+    let dir = obj_path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut positions: Vec<Vec3> = Vec::new();
+    let mut uvs: Vec<[f64; 2]> = Vec::new();
+    let mut normals: Vec<Vec3> = Vec::new();
+    let mut materials: HashMap<String, Arc<dyn Material>> = HashMap::new();
+    let mut group = String::new();
+    let mut material = String::new();
+    let mut builders: Vec<((String, String), MeshBuilder)> = Vec::new();
+
+    for (i, line) in source.lines().enumerate() {
+        let context = LineContext { path, line: i + 1 };
+        let line = line.split('#').next().unwrap_or("");
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let (keyword, args) = match tokens.split_first() {
+            Some((keyword, args)) => (*keyword, args),
+            None => continue,
+        };
+        match keyword {
+            "v" => positions.push(context.vec3(args, keyword)?),
+            "vn" => normals.push(context.vec3(args, keyword)?),
+            "vt" => uvs.push([
+                context.number(args.first().copied(), keyword)?,
+                match args.get(1) {
+                    Some(v) => context.number(Some(v), keyword)?,
+                    None => 0.0,
+                },
+            ]),
+            "g" | "o" => group = args.join(" "),
+            "usemtl" => {
+                material = args.join(" ");
+                if material_override.is_none() && !materials.contains_key(&material) {
+                    return context.error(format!("undefined material `{}`", material));
+                }
+            }
+            "mtllib" => {
+                if material_override.is_some() {
+                    continue;
+                }
+                if args.is_empty() {
+                    return context.error("`mtllib` is missing a file name".to_string());
+                }
+                for file in args {
+                    materials.extend(load_mtl(&dir.join(file))?);
+                }
+            }
+            "f" => {
+                if args.len() < 3 {
+                    return context.error(format!(
+                        "a face needs at least three vertices, found {}",
+                        args.len()
+                    ));
+                }
+                let mut corners = Vec::with_capacity(args.len());
+                for arg in args {
+                    let mut parts = arg.split('/');
+                    let v = context.index(parts.next().unwrap_or(""), positions.len(), "vertex")?;
+                    let vt = match parts.next() {
+                        Some(t) if !t.is_empty() => Some(context.index(t, uvs.len(), "texture")?),
+                        _ => None,
+                    };
+                    let vn = match parts.next() {
+                        Some(n) if !n.is_empty() => {
+                            Some(context.index(n, normals.len(), "normal")?)
+                        }
+                        _ => None,
+                    };
+                    if parts.next().is_some() {
+                        return context.error(format!("malformed face vertex `{}`", arg));
+                    }
+                    corners.push((v, vt, vn));
+                }
+                let key = (group.clone(), material.clone());
+                let builder = match builders.iter().position(|(k, _)| *k == key) {
+                    Some(i) => &mut builders[i].1,
+                    None => {
+                        builders.push((key, MeshBuilder::default()));
+                        &mut builders.last_mut().unwrap().1
+                    }
+                };
+                let mut indices = Vec::with_capacity(corners.len());
+                for corner in corners {
+                    let mesh = &mut builder.mesh;
+                    let index = *builder.vertices.entry(corner).or_insert_with(|| {
+                        mesh.positions.push(positions[corner.0]);
+                        mesh.uvs.push(corner.1.map_or([0.0, 0.0], |t| uvs[t]));
+                        mesh.normals
+                            .push(corner.2.map_or(Vec3::zero(), |n| normals[n]));
+                        mesh.positions.len() - 1
+                    });
+                    builder.missing_normals |= corner.2.is_none();
+                    indices.push(index);
+                }
+                for k in 1..indices.len() - 1 {
+                    builder
+                        .mesh
+                        .indices
+                        .push([indices[0], indices[k], indices[k + 1]]);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let default_material: Arc<dyn Material> = match material_override {
+        Some(m) => m,
+        None => Arc::new(Lambertian::new2(Vec3::new(0.73, 0.73, 0.73))),
+    };
+    let mut objects = HittableList::new();
+    for ((_, name), mut builder) in builders {
+        if builder.missing_normals {
+            builder.mesh.normals.clear();
+        }
+        if uvs.is_empty() {
+            builder.mesh.uvs.clear();
+        }
+        let m = match materials.get(&name) {
+            Some(m) => m.clone(),
+            None => default_material.clone(),
+        };
+        objects.add(Box::new(TriangleMesh::new(builder.mesh, m)));
+    }
+    Ok(objects)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Hittable, Ray};
+    use std::env;
+    use std::process;
+
+    fn load(name: &str, source: &str) -> Result<HittableList, ObjError> {
+        let path = env::temp_dir().join(format!("raytracer-{}-{}.obj", process::id(), name));
+        fs::write(&path, source).unwrap();
+        let objects = load_obj(&path.to_string_lossy(), None);
+        fs::remove_file(&path).unwrap();
+        objects
+    }
+    // whether a ray straight down at (x, z) hits the objects
+    fn covers(objects: &HittableList, x: f64, z: f64) -> bool {
+        let r = Ray::new(Vec3::new(x, 1.0, z), Vec3::new(0.0, -1.0, 0.0), 0.0);
+        objects.hit(r, 0.001, f64::INFINITY).is_some()
+    }
+
+    #[test]
+    fn negative_indices_count_back_from_the_last_element() {
+        let context = LineContext {
+            path: "a.obj",
+            line: 1,
+        };
+        assert_eq!(context.index("-1", 4, "vertex").unwrap(), 3);
+        assert_eq!(context.index("-4", 4, "vertex").unwrap(), 0);
+        assert_eq!(context.index("2", 4, "vertex").unwrap(), 1);
+        assert!(context.index("-5", 4, "vertex").is_err());
+        assert!(context.index("0", 4, "vertex").is_err());
+    }
+
+    #[test]
+    fn faces_may_use_negative_indices() {
+        let objects = load(
+            "negative",
+            "v 5 0 5\nv 0 0 0\nv 1 0 0\nv 0 0 1\nf -3 -2 -1\n",
+        )
+        .unwrap();
+        assert!(covers(&objects, 0.2, 0.2));
+        assert!(!covers(&objects, 2.0, 2.0));
+    }
+
+    #[test]
+    fn polygons_are_split_into_a_fan() {
+        // a pentagon over the unit square with a tip at z = 1.5
+        let objects = load(
+            "fan",
+            "v 0 0 0\nv 1 0 0\nv 1 0 1\nv 0.5 0 1.5\nv 0 0 1\nf 1 2 3 4 5\n",
+        )
+        .unwrap();
+        assert_eq!(objects.objects.len(), 1);
+        for &(x, z) in &[(0.9, 0.1), (0.9, 0.9), (0.5, 1.4), (0.1, 0.9)] {
+            assert!(covers(&objects, x, z));
+        }
+        assert!(!covers(&objects, 0.9, 1.4));
+    }
+
+    #[test]
+    fn out_of_range_indices_name_the_line() {
+        let err = load("range", "v 0 0 0\nv 1 0 0\n\nf 1 2 3\n")
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .ends_with(":4: vertex index 3 is out of range (2 defined)"));
+    }
+}
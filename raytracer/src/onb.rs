@@ -11,6 +11,10 @@ impl Onb {
     pub fn localbyvector(&self, a: Vec3) -> Vec3 {
         self.axis[0] * a.x + self.axis[1] * a.y + self.axis[2] * a.z
     }
+    // the inverse of localbyvector
+    pub fn worldtolocal(&self, a: Vec3) -> Vec3 {
+        Vec3::new(a * self.axis[0], a * self.axis[1], a * self.axis[2])
+    }
     pub fn new(n: Vec3) -> Self {
         let mut axis = [Vec3::zero(); 3];
         axis[2] = n.unit();
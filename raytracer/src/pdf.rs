@@ -1,5 +1,5 @@
 use crate::rtweekend::random_cosine_direction;
-use crate::rtweekend::random_f64;
+use crate::sampler::sample_1d;
 use crate::Hittable;
 use crate::Onb;
 use crate::Vec3;
@@ -34,19 +34,24 @@ impl Pdf for CosinePdf {
 #[derive(Clone)]
 pub struct HittablePdf<'a, T: Hittable> {
     pub o: Vec3,
+    pub time: f64,
     pub ptr: &'a T,
 }
 impl<'a, T: Hittable> HittablePdf<'a, T> {
-    pub fn new(p: &'a T, origin: Vec3) -> Self {
-        Self { ptr: p, o: origin }
+    pub fn new(p: &'a T, origin: Vec3, time: f64) -> Self {
+        Self {
+            ptr: p,
+            o: origin,
+            time,
+        }
     }
 }
 impl<'a, T: Hittable> Pdf for HittablePdf<'a, T> {
     fn value(&self, direction: Vec3) -> f64 {
-        self.ptr.pdf_value(self.o, direction)
+        self.ptr.pdf_value(self.o, direction, self.time)
     }
     fn generate(&self) -> Vec3 {
-        self.ptr.random(self.o)
+        self.ptr.random(self.o, self.time)
     }
 }
 #[derive(Clone)]
@@ -64,7 +69,7 @@ impl<'a> Pdf for MixturePdf<'a> {
         0.5 * self.p0.value(direction) + 0.5 * self.p1.value(direction)
     }
     fn generate(&self) -> Vec3 {
-        if random_f64(0.0, 1.0) < 0.5 {
+        if sample_1d() < 0.5 {
             return self.p0.generate();
         } else {
             self.p1.generate()
@@ -0,0 +1,236 @@
+use crate::framebuffer::FrameBuffer;
+use crate::scheduler::Tile;
+use crate::tonemap::luminance;
+use crate::Vec3;
+use std::vec::Vec;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PixelStats {
+    pub sum: Vec3,
+    pub luminance_sum: f64,
+    pub luminance_squared_sum: f64,
+    pub samples: u32,
+}
+impl Default for PixelStats {
+    fn default() -> Self {
+        Self {
+            sum: Vec3::zero(),
+            luminance_sum: 0.0,
+            luminance_squared_sum: 0.0,
+            samples: 0,
+        }
+    }
+}
+impl PixelStats {
+    pub fn add(&mut self, color: Vec3) {
+        let l = luminance(color);
+        self.sum += color;
+        self.luminance_sum += l;
+        self.luminance_squared_sum += l * l;
+        self.samples += 1;
+    }
+    pub fn merge(&mut self, other: &PixelStats) {
+        self.sum += other.sum;
+        self.luminance_sum += other.luminance_sum;
+        self.luminance_squared_sum += other.luminance_squared_sum;
+        self.samples += other.samples;
+    }
+    pub fn mean(&self) -> Vec3 {
+        if self.samples == 0 {
+            Vec3::zero()
+        } else {
+            self.sum / self.samples as f64
+        }
+    }
+    // standard error of the mean luminance relative to the luminance itself, dark pixels are
+    // measured against a floor so that they don't need an unbounded number of samples
+    pub fn relative_error(&self) -> f64 {
+        if self.samples < 2 {
+            return f64::INFINITY;
+        }
+        let n = self.samples as f64;
+        let mean = self.luminance_sum / n;
+        let variance =
+            ((self.luminance_squared_sum - mean * self.luminance_sum) / (n - 1.0)).max(0.0);
+        (variance / n).sqrt() / mean.max(0.01)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PassSettings {
+    pub pass_spp: u32,
+    pub max_spp: u32,
+    // adaptive sampling stops pixels whose neighbourhood is below this relative error
+    pub noise_threshold: Option<f64>,
+    pub min_spp: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Accumulator {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<PixelStats>,
+}
+impl Accumulator {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![PixelStats::default(); width * height],
+        }
+    }
+    // `stats` holds the tile's pixels row by row
+    pub fn add_tile(&mut self, tile: &Tile, stats: &[PixelStats]) {
+        for y in tile.y0..tile.y1 {
+            let row = &stats[(y - tile.y0) * tile.width()..(y - tile.y0 + 1) * tile.width()];
+            let start = y * self.width + tile.x0;
+            for (pixel, stats) in self.pixels[start..start + tile.width()].iter_mut().zip(row) {
+                pixel.merge(stats);
+            }
+        }
+    }
+    pub fn frame(&self) -> FrameBuffer {
+        let mut frame = FrameBuffer::new(self.width, self.height);
+        for (out, stats) in frame.pixels.iter_mut().zip(&self.pixels) {
+            *out = stats.mean();
+        }
+        frame
+    }
+    pub fn total_samples(&self) -> u64 {
+        self.pixels.iter().map(|p| p.samples as u64).sum()
+    }
+
+    fn neighbourhood_errors(&self) -> Vec<f64> {
+        let errors: Vec<f64> = self.pixels.iter().map(|p| p.relative_error()).collect();
+        let mut worst = vec![0.0; errors.len()];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let mut e: f64 = 0.0;
+                for ny in y.saturating_sub(1)..(y + 2).min(self.height) {
+                    for nx in x.saturating_sub(1)..(x + 2).min(self.width) {
+                        e = e.max(errors[ny * self.width + nx]);
+                    }
+                }
+                worst[y * self.width + x] = e;
+            }
+        }
+        worst
+    }
+
+    // number of samples each pixel takes in the next pass, all zero once the render is done
+    pub fn plan(&self, settings: &PassSettings) -> Vec<u32> {
+        let errors = match settings.noise_threshold {
+            Some(_) => self.neighbourhood_errors(),
+            None => Vec::new(),
+        };
+        self.pixels
+            .iter()
+            .enumerate()
+            .map(|(i, p)| {
+                let remaining = settings.max_spp.saturating_sub(p.samples);
+                let converged = match settings.noise_threshold {
+                    Some(threshold) => p.samples >= settings.min_spp && errors[i] < threshold,
+                    None => false,
+                };
+                if converged {
+                    0
+                } else {
+                    remaining.min(settings.pass_spp)
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grey(x: f64) -> Vec3 {
+        Vec3::new(x, x, x)
+    }
+    fn adaptive() -> PassSettings {
+        PassSettings {
+            pass_spp: 4,
+            max_spp: 64,
+            noise_threshold: Some(0.05),
+            min_spp: 8,
+        }
+    }
+    // a pixel that always sees the same color, converged from its second sample on
+    fn converged(samples: u32) -> PixelStats {
+        let mut stats = PixelStats::default();
+        for _ in 0..samples {
+            stats.add(grey(0.5));
+        }
+        stats
+    }
+
+    #[test]
+    fn relative_error_needs_two_samples() {
+        assert_eq!(converged(1).relative_error(), f64::INFINITY);
+        assert_eq!(converged(2).relative_error(), 0.0);
+        let mut noisy = PixelStats::default();
+        for i in 0..16 {
+            noisy.add(grey((i % 2) as f64));
+        }
+        assert!(noisy.relative_error() > 0.1);
+    }
+
+    #[test]
+    fn converged_pixels_stop_after_the_minimum() {
+        let mut accumulator = Accumulator::new(1, 1);
+        accumulator.pixels[0] = converged(4);
+        assert_eq!(accumulator.plan(&adaptive()), vec![4]);
+        accumulator.pixels[0] = converged(8);
+        assert_eq!(accumulator.plan(&adaptive()), vec![0]);
+    }
+
+    #[test]
+    fn noisy_pixels_keep_their_neighbours_going() {
+        let mut accumulator = Accumulator::new(5, 5);
+        for pixel in accumulator.pixels.iter_mut() {
+            *pixel = converged(16);
+        }
+        let mut noisy = PixelStats::default();
+        for i in 0..16 {
+            noisy.add(grey((i % 2) as f64));
+        }
+        accumulator.pixels[2 * 5 + 1] = noisy;
+        let plan = accumulator.plan(&adaptive());
+        for y in 0..5 {
+            for x in 0..5 {
+                let expected = if x <= 2 && (1..=3).contains(&y) { 4 } else { 0 };
+                assert_eq!(plan[y * 5 + x], expected, "pixel ({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn plans_never_go_past_the_samples_per_pixel() {
+        for &noise_threshold in &[None, Some(1e-9)] {
+            let settings = PassSettings {
+                pass_spp: 4,
+                max_spp: 10,
+                noise_threshold,
+                min_spp: 2,
+            };
+            let mut accumulator = Accumulator::new(3, 2);
+            let mut passes = 0;
+            loop {
+                let plan = accumulator.plan(&settings);
+                if plan.iter().all(|&n| n == 0) {
+                    break;
+                }
+                for (i, (pixel, &n)) in accumulator.pixels.iter_mut().zip(&plan).enumerate() {
+                    for s in 0..n {
+                        pixel.add(grey((i as u32 + s) as f64));
+                    }
+                }
+                passes += 1;
+            }
+            assert_eq!(passes, 3);
+            assert!(accumulator.pixels.iter().all(|p| p.samples == 10));
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use crate::mat4::Mat4;
+use crate::rtweekend::degrees_to_radians;
+use crate::Vec3;
+
+// Unit quaternion of a rotation, `w` is the real part.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quat {
+    pub w: f64,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+impl Quat {
+    pub fn new(w: f64, x: f64, y: f64, z: f64) -> Self {
+        Self { w, x, y, z }
+    }
+    pub fn identity() -> Self {
+        Self::new(1.0, 0.0, 0.0, 0.0)
+    }
+    // the same rotation as Mat4::rotate(axis, angle)
+    pub fn from_axis_angle(axis: Vec3, angle: f64) -> Self {
+        let a = axis.unit();
+        let (sin, cos) = (degrees_to_radians(angle) * 0.5).sin_cos();
+        Self::new(cos, a.x * sin, a.y * sin, a.z * sin)
+    }
+
+    pub fn dot(&self, other: Self) -> f64 {
+        self.w * other.w + self.x * other.x + self.y * other.y + self.z * other.z
+    }
+    pub fn normalize(&self) -> Self {
+        let len = self.dot(*self).sqrt();
+        Self::new(self.w / len, self.x / len, self.y / len, self.z / len)
+    }
+    pub fn conjugate(&self) -> Self {
+        Self::new(self.w, -self.x, -self.y, -self.z)
+    }
+    // spherical interpolation along the shorter arc, `t` runs from 0 (self) to 1 (other)
+    pub fn slerp(&self, other: Self, t: f64) -> Self {
+        let mut other = other;
+        let mut cos = self.dot(other);
+        if cos < 0.0 {
+            other = Self::new(-other.w, -other.x, -other.y, -other.z);
+            cos = -cos;
+        }
+        // nearly the same rotation, the sine below would divide by almost zero
+        let (a, b) = if cos > 0.9995 {
+            (1.0 - t, t)
+        } else {
+            let theta = cos.acos();
+            let sin = theta.sin();
+            (((1.0 - t) * theta).sin() / sin, (t * theta).sin() / sin)
+        };
+        Self::new(
+            a * self.w + b * other.w,
+            a * self.x + b * other.x,
+            a * self.y + b * other.y,
+            a * self.z + b * other.z,
+        )
+        .normalize()
+    }
+
+    pub fn to_mat4(self) -> Mat4 {
+        let Self { w, x, y, z } = self;
+        Mat4::new([
+            [
+                1.0 - 2.0 * (y * y + z * z),
+                2.0 * (x * y - w * z),
+                2.0 * (x * z + w * y),
+                0.0,
+            ],
+            [
+                2.0 * (x * y + w * z),
+                1.0 - 2.0 * (x * x + z * z),
+                2.0 * (y * z - w * x),
+                0.0,
+            ],
+            [
+                2.0 * (x * z - w * y),
+                2.0 * (y * z + w * x),
+                1.0 - 2.0 * (x * x + y * y),
+                0.0,
+            ],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_same_rotation(a: Quat, b: Quat) {
+        let (a, b) = (a.to_mat4(), b.to_mat4());
+        for i in 0..4 {
+            for j in 0..4 {
+                assert!((a.m[i][j] - b.m[i][j]).abs() < 1e-9, "{:?} != {:?}", a, b);
+            }
+        }
+    }
+    fn negated(q: Quat) -> Quat {
+        Quat::new(-q.w, -q.x, -q.y, -q.z)
+    }
+
+    #[test]
+    fn matrix_is_the_rotation_about_the_axis() {
+        for &(axis, angle) in &[
+            (Vec3::new(0.0, 1.0, 0.0), 90.0),
+            (Vec3::new(1.0, 2.0, 3.0), 30.0),
+            (Vec3::new(-1.0, 0.5, 0.0), 250.0),
+        ] {
+            let expected = Mat4::rotate(axis, angle);
+            let m = Quat::from_axis_angle(axis, angle).to_mat4();
+            for i in 0..4 {
+                for j in 0..4 {
+                    assert!((m.m[i][j] - expected.m[i][j]).abs() < 1e-12);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn slerp_starts_and_ends_at_the_keys() {
+        let a = Quat::from_axis_angle(Vec3::new(1.0, 2.0, 3.0), 30.0);
+        let b = Quat::from_axis_angle(Vec3::new(0.0, 1.0, 0.0), 120.0);
+        let start = a.slerp(b, 0.0);
+        assert!((start.dot(a) - 1.0).abs() < 1e-12);
+        assert_same_rotation(a.slerp(b, 1.0), b);
+        // the same for keys close enough to be interpolated linearly
+        let c = Quat::from_axis_angle(Vec3::new(1.0, 2.0, 3.0), 31.0);
+        assert!((a.slerp(c, 0.0).dot(a) - 1.0).abs() < 1e-12);
+        assert_same_rotation(a.slerp(c, 1.0), c);
+    }
+
+    #[test]
+    fn slerp_takes_the_short_arc() {
+        // 300 degrees one way is 60 the other
+        let a = Quat::identity();
+        let b = Quat::from_axis_angle(Vec3::new(0.0, 0.0, 1.0), 300.0);
+        let expected = Quat::from_axis_angle(Vec3::new(0.0, 0.0, 1.0), -30.0);
+        for &b in &[b, negated(b)] {
+            assert_same_rotation(a.slerp(b, 0.5), expected);
+        }
+    }
+}
@@ -5,6 +5,8 @@ pub struct Ray {
     pub orig: Vec3,
     pub dir: Vec3,
     pub tm: f64, //默认是0.0
+    // in nanometres when the path is traced at a single wavelength, None for RGB paths
+    pub wavelength: Option<f64>,
 }
 
 impl Ray {
@@ -12,6 +14,15 @@ impl Ray {
         self.orig + self.dir * t
     }
     pub fn new(orig: Vec3, dir: Vec3, tm: f64) -> Self {
-        Self { orig, dir, tm }
+        Self {
+            orig,
+            dir,
+            tm,
+            wavelength: None,
+        }
+    }
+    pub fn with_wavelength(mut self, wavelength: Option<f64>) -> Self {
+        self.wavelength = wavelength;
+        self
     }
 }
@@ -0,0 +1,259 @@
+use crate::rtweekend::clamp;
+use crate::spectrum::{rgb_matching, SampledSpectrum, SampledWavelengths, LAMBDA_MAX, LAMBDA_MIN};
+use crate::Vec3;
+
+// RGB to spectrum upsampling of Jakob and Hanika, "A Low-Dimensional Function Space for
+// Efficient Spectral Upsampling" (2019). A color becomes the smooth spectrum
+// sigmoid(c0 t^2 + c1 t + c2) with t the wavelength mapped to [0, 1] over the traced range, the
+// coefficients are fitted once on a grid of colors and interpolated in between.
+
+// grid points along each axis of the table
+const RES: usize = 16;
+// wavelength step of the integrals the coefficients are fitted with
+const FIT_STEP: f64 = 5.0;
+const FIT_ITERATIONS: usize = 30;
+
+fn sigmoid(x: f64) -> f64 {
+    if x.is_infinite() {
+        return if x > 0.0 { 1.0 } else { 0.0 };
+    }
+    0.5 + x / (2.0 * (1.0 + x * x).sqrt())
+}
+fn smoothstep(x: f64) -> f64 {
+    x * x * (3.0 - 2.0 * x)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SigmoidPolynomial {
+    pub c: [f64; 3],
+}
+impl SigmoidPolynomial {
+    pub fn value(&self, lambda: f64) -> f64 {
+        self.value_at((lambda - LAMBDA_MIN) / (LAMBDA_MAX - LAMBDA_MIN))
+    }
+    fn value_at(&self, t: f64) -> f64 {
+        sigmoid((self.c[0] * t + self.c[1]) * t + self.c[2])
+    }
+}
+
+// rgb_matching tabulated for the fit, the mapped wavelength and its weight in the integral
+fn matching_table() -> Vec<(f64, Vec3)> {
+    let mut table = Vec::new();
+    let mut lambda = LAMBDA_MIN + 0.5 * FIT_STEP;
+    while lambda < LAMBDA_MAX {
+        let t = (lambda - LAMBDA_MIN) / (LAMBDA_MAX - LAMBDA_MIN);
+        table.push((t, rgb_matching(lambda) * FIT_STEP));
+        lambda += FIT_STEP;
+    }
+    table
+}
+// the color the film shows for the spectrum minus `target`
+fn residual(matching: &[(f64, Vec3)], c: [f64; 3], target: Vec3) -> Vec3 {
+    let spectrum = SigmoidPolynomial { c };
+    let mut rgb = -target;
+    for &(t, weight) in matching {
+        rgb += weight * spectrum.value_at(t);
+    }
+    rgb
+}
+// Gauss-Newton on the residual, starting from and updating `c`. Steps that don't get closer are
+// halved, colors outside of what a spectrum in [0, 1] can show end at the closest one.
+fn fit(matching: &[(f64, Vec3)], target: Vec3, c: &mut [f64; 3]) {
+    let mut r = residual(matching, *c, target);
+    for _ in 0..FIT_ITERATIONS {
+        if r.squared_length() < 1e-12 {
+            return;
+        }
+        // the jacobian by finite differences, one column per coefficient
+        let mut jacobian = [Vec3::zero(); 3];
+        for (i, column) in jacobian.iter_mut().enumerate() {
+            let mut cp = *c;
+            cp[i] += 1e-4;
+            *column = (residual(matching, cp, target) - r) / 1e-4;
+        }
+        let det = jacobian[0] * Vec3::cross(jacobian[1], jacobian[2]);
+        if det.abs() < 1e-15 {
+            return;
+        }
+        // Cramer's rule for jacobian * step = r
+        let step = [
+            r * Vec3::cross(jacobian[1], jacobian[2]) / det,
+            jacobian[0] * Vec3::cross(r, jacobian[2]) / det,
+            jacobian[0] * Vec3::cross(jacobian[1], r) / det,
+        ];
+        let mut scale = 1.0;
+        loop {
+            let next = [
+                c[0] - step[0] * scale,
+                c[1] - step[1] * scale,
+                c[2] - step[2] * scale,
+            ];
+            let next_r = residual(matching, next, target);
+            if next_r.squared_length() < r.squared_length() {
+                *c = next;
+                r = next_r;
+                break;
+            }
+            scale *= 0.5;
+            if scale < 1e-3 {
+                return;
+            }
+        }
+    }
+}
+
+// The fitted coefficients. The largest channel of a color picks one of three tables, its value
+// is the third axis and the other two channels relative to it are the first two, like pbrt-v4's
+// RGBToSpectrumTable.
+pub struct RgbToSpectrum {
+    scale: Vec<f64>,
+    coefficients: Vec<[f64; 3]>,
+}
+impl RgbToSpectrum {
+    pub fn new() -> Self {
+        let scale: Vec<f64> = (0..RES)
+            .map(|k| smoothstep(smoothstep(k as f64 / (RES - 1) as f64)))
+            .collect();
+        let matching = matching_table();
+        let mut coefficients = vec![[0.0; 3]; 3 * RES * RES * RES];
+        for l in 0..3 {
+            for j in 0..RES {
+                let y = j as f64 / (RES - 1) as f64;
+                for i in 0..RES {
+                    let x = i as f64 / (RES - 1) as f64;
+                    // each fit starts from the one of a slightly brighter or darker color, walking
+                    // away from mid grey where the flat spectrum is a good start
+                    let start = RES / 5;
+                    let mut c = [0.0; 3];
+                    for k in (start..RES).chain((0..start).rev()) {
+                        if k == start - 1 {
+                            c = coefficients[Self::index(l, start, j, i)];
+                        }
+                        let b = scale[k];
+                        let mut rgb = [0.0; 3];
+                        rgb[l] = b;
+                        rgb[(l + 1) % 3] = x * b;
+                        rgb[(l + 2) % 3] = y * b;
+                        fit(&matching, Vec3::new(rgb[0], rgb[1], rgb[2]), &mut c);
+                        coefficients[Self::index(l, k, j, i)] = c;
+                    }
+                }
+            }
+        }
+        Self {
+            scale,
+            coefficients,
+        }
+    }
+    fn index(l: usize, k: usize, j: usize, i: usize) -> usize {
+        ((l * RES + k) * RES + j) * RES + i
+    }
+
+    // the spectrum of a color with channels in [0, 1]
+    pub fn albedo(&self, rgb: Vec3) -> SigmoidPolynomial {
+        let rgb = [
+            clamp(rgb.x, 0.0, 1.0),
+            clamp(rgb.y, 0.0, 1.0),
+            clamp(rgb.z, 0.0, 1.0),
+        ];
+        if rgb[0] == rgb[1] && rgb[1] == rgb[2] {
+            // grey is flat, the inverse of the sigmoid
+            let v = rgb[0];
+            return SigmoidPolynomial {
+                c: [0.0, 0.0, (v - 0.5) / (v * (1.0 - v)).sqrt()],
+            };
+        }
+        let l = if rgb[0] > rgb[1] {
+            if rgb[0] > rgb[2] {
+                0
+            } else {
+                2
+            }
+        } else if rgb[1] > rgb[2] {
+            1
+        } else {
+            2
+        };
+        let z = rgb[l];
+        let x = rgb[(l + 1) % 3] * (RES - 1) as f64 / z;
+        let y = rgb[(l + 2) % 3] * (RES - 1) as f64 / z;
+        let xi = (x as usize).min(RES - 2);
+        let yi = (y as usize).min(RES - 2);
+        let zi = self.scale[1..RES - 1]
+            .iter()
+            .take_while(|&&s| s <= z)
+            .count();
+        let (dx, dy) = (x - xi as f64, y - yi as f64);
+        let dz = (z - self.scale[zi]) / (self.scale[zi + 1] - self.scale[zi]);
+        let mut c = [0.0; 3];
+        for (n, coefficient) in c.iter_mut().enumerate() {
+            let at = |k: usize, j: usize, i: usize| self.coefficients[Self::index(l, k, j, i)][n];
+            let lerp = |t: f64, a: f64, b: f64| a + (b - a) * t;
+            *coefficient = lerp(
+                dz,
+                lerp(
+                    dy,
+                    lerp(dx, at(zi, yi, xi), at(zi, yi, xi + 1)),
+                    lerp(dx, at(zi, yi + 1, xi), at(zi, yi + 1, xi + 1)),
+                ),
+                lerp(
+                    dy,
+                    lerp(dx, at(zi + 1, yi, xi), at(zi + 1, yi, xi + 1)),
+                    lerp(dx, at(zi + 1, yi + 1, xi), at(zi + 1, yi + 1, xi + 1)),
+                ),
+            );
+        }
+        SigmoidPolynomial { c }
+    }
+
+    // Any non-negative color at the given wavelengths. Colors brighter than one are scaled into
+    // the table and back, to twice their largest channel so saturated lights stay smooth.
+    pub fn sample(&self, rgb: Vec3, wavelengths: &SampledWavelengths) -> SampledSpectrum {
+        let m = rgb.x.max(rgb.y).max(rgb.z);
+        if m <= 0.0 {
+            return SampledSpectrum::zero();
+        }
+        let (spectrum, scale) = if m <= 1.0 {
+            (self.albedo(rgb), 1.0)
+        } else {
+            (self.albedo(rgb / (2.0 * m)), 2.0 * m)
+        };
+        SampledSpectrum::from_fn(|i| spectrum.value(wavelengths.lambda[i]) * scale)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fitted_spectra_show_their_color() {
+        let upsampler = RgbToSpectrum::new();
+        let matching = matching_table();
+        for &rgb in &[
+            Vec3::new(0.8, 0.3, 0.1),
+            Vec3::new(0.2, 0.5, 0.7),
+            Vec3::new(0.1, 0.6, 0.2),
+            Vec3::new(0.7, 0.1, 0.6),
+            Vec3::new(0.33, 0.41, 0.29),
+            Vec3::new(0.95, 0.8, 0.2),
+            Vec3::new(1.0, 0.0, 0.0),
+        ] {
+            let c = upsampler.albedo(rgb).c;
+            assert!(residual(&matching, c, rgb).length() < 0.01, "{:?}", rgb);
+        }
+    }
+
+    #[test]
+    fn grey_is_flat() {
+        let upsampler = RgbToSpectrum::new();
+        let matching = matching_table();
+        for &v in &[0.05, 0.5, 0.9] {
+            let spectrum = upsampler.albedo(Vec3::new(v, v, v));
+            assert_eq!(&spectrum.c[..2], &[0.0, 0.0]);
+            assert!((spectrum.value(550.0) - v).abs() < 1e-12);
+            let c = spectrum.c;
+            assert!(residual(&matching, c, Vec3::new(v, v, v)).length() < 1e-3);
+        }
+    }
+}
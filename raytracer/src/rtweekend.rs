@@ -1,14 +1,68 @@
+use crate::sampler::sample_2d;
 use crate::Vec3;
-use rand::{thread_rng, Rng};
+use std::cell::RefCell;
 use std::f64::consts::PI;
 
+// PCG32 (XSH RR variant), small and fast enough to be reseeded for every camera sample
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pcg32 {
+    state: u64,
+    inc: u64,
+}
+impl Pcg32 {
+    pub fn new(seed: u64, stream: u64) -> Self {
+        let mut rng = Self {
+            state: 0,
+            inc: (stream << 1) | 1,
+        };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(self.inc);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        xorshifted.rotate_right((old >> 59) as u32)
+    }
+    // uniform in [0, 1) with 53 random bits
+    pub fn next_f64(&mut self) -> f64 {
+        let bits = ((self.next_u32() as u64) << 32 | self.next_u32() as u64) >> 11;
+        bits as f64 * (1.0 / (1u64 << 53) as f64)
+    }
+}
+
+// splitmix64 finalizer, decorrelates neighbouring keys
+pub fn mix_bits(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+thread_local! {
+    static RNG: RefCell<Pcg32> = RefCell::new(Pcg32::new(mix_bits(0), 0));
+}
+
 pub const INFINITY: f64 = f64::INFINITY;
 pub fn degrees_to_radians(degrees: f64) -> f64 {
     degrees * PI / 180.0
 }
+// everything that runs outside of a camera sample (scene construction) draws from this stream
+pub fn seed_rng(seed: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = Pcg32::new(mix_bits(seed), u64::MAX >> 1));
+}
+// Keys the random stream of the current thread by pixel and sample. Everything random_f64 returns
+// until the next call depends only on these three numbers, so a render does not depend on how
+// pixels are spread over threads.
+pub fn start_sample(seed: u64, pixel: u64, sample: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = Pcg32::new(mix_bits(seed ^ mix_bits(pixel)), sample));
+}
 pub fn random_f64(min: f64, max: f64) -> f64 {
-    let mut rng = thread_rng();
-    let random: f64 = rng.gen();
+    let random = RNG.with(|rng| rng.borrow_mut().next_f64());
     min + (max - min) * random
 }
 pub fn fmin(left: f64, right: f64) -> f64 {
@@ -38,11 +92,53 @@ pub fn clamp(x: f64, min: f64, max: f64) -> f64 {
     x
 }
 pub fn random_cosine_direction() -> Vec3 {
-    let r1 = random_f64(0.0, 1.0);
-    let r2 = random_f64(0.0, 1.0);
+    let (r1, r2) = sample_2d();
     let z = (1.0 - r2).sqrt();
     let phi = 2.0 * PI * r1;
     let x = f64::cos(phi) * r2.sqrt();
     let y = f64::sin(phi) * r2.sqrt();
     Vec3::new(x, y, z)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // draws a sample's numbers on a thread of its own, after `noise` unrelated draws
+    fn sequence(seed: u64, pixel: u64, sample: u64, noise: usize) -> Vec<u64> {
+        thread::spawn(move || {
+            for _ in 0..noise {
+                random_f64(0.0, 1.0);
+            }
+            start_sample(seed, pixel, sample);
+            (0..16).map(|_| random_f64(0.0, 1.0).to_bits()).collect()
+        })
+        .join()
+        .unwrap()
+    }
+
+    #[test]
+    fn same_key_gives_the_same_sequence_on_any_thread() {
+        assert_eq!(sequence(7, 1234, 3, 0), sequence(7, 1234, 3, 101));
+    }
+
+    #[test]
+    fn different_keys_give_different_sequences() {
+        let reference = sequence(7, 1234, 3, 0);
+        for &(seed, pixel, sample) in
+            [(8, 1234, 3), (7, 1235, 3), (7, 1234, 4), (1234, 7, 3)].iter()
+        {
+            assert_ne!(sequence(seed, pixel, sample, 0), reference);
+        }
+    }
+
+    #[test]
+    fn samples_are_in_the_unit_interval() {
+        let mut rng = Pcg32::new(mix_bits(1), 2);
+        for _ in 0..10_000 {
+            let x = rng.next_f64();
+            assert!((0.0..1.0).contains(&x));
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use crate::Vec3;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Problem {
+    NaN,
+    Infinite,
+    Negative,
+}
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::NaN => write!(f, "NaN"),
+            Problem::Infinite => write!(f, "infinite"),
+            Problem::Negative => write!(f, "negative"),
+        }
+    }
+}
+
+pub fn classify(c: Vec3) -> Option<Problem> {
+    let channels = [c.x, c.y, c.z];
+    if channels.iter().any(|x| x.is_nan()) {
+        Some(Problem::NaN)
+    } else if channels.iter().any(|x| x.is_infinite()) {
+        Some(Problem::Infinite)
+    } else if channels.iter().any(|&x| x < 0.0) {
+        Some(Problem::Negative)
+    } else {
+        None
+    }
+}
+
+// strips module paths from a type name, `a::b::Lambertian<a::c::SolidColor>` becomes `Lambertian<SolidColor>`
+fn short_type_name(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut segment_start = 0;
+    for c in name.chars() {
+        if c == ':' {
+            out.truncate(segment_start);
+        } else {
+            out.push(c);
+            if !(c.is_alphanumeric() || c == '_') {
+                segment_start = out.len();
+            }
+        }
+    }
+    out
+}
+
+// Counts invalid sample contributions over a whole render. The integrator drops non-finite
+// values and clamps negative ones to zero, every bounce that had to be fixed is attributed to
+// the quantity that went wrong and the material it was evaluated on.
+#[derive(Default)]
+pub struct SampleGuard {
+    samples: AtomicU64,
+    nan: AtomicU64,
+    infinite: AtomicU64,
+    negative: AtomicU64,
+    sources: Mutex<HashMap<(String, Problem), u64>>,
+}
+impl SampleGuard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // returns None if the value has to be dropped, otherwise the value with negative channels clamped
+    pub fn check(&self, c: Vec3, quantity: &str, material: &str) -> Option<Vec3> {
+        let problem = match classify(c) {
+            Some(problem) => problem,
+            None => return Some(c),
+        };
+        let counter = match problem {
+            Problem::NaN => &self.nan,
+            Problem::Infinite => &self.infinite,
+            Problem::Negative => &self.negative,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        let source = format!("{} of {}", quantity, short_type_name(material));
+        *self
+            .sources
+            .lock()
+            .unwrap()
+            .entry((source, problem))
+            .or_insert(0) += 1;
+        match problem {
+            Problem::Negative => Some(Vec3::new(c.x.max(0.0), c.y.max(0.0), c.z.max(0.0))),
+            _ => None,
+        }
+    }
+    pub fn check_scalar(&self, x: f64, quantity: &str, material: &str) -> Option<f64> {
+        self.check(Vec3::new(x, x, x), quantity, material)
+            .map(|c| c.x)
+    }
+
+    // final guard on a camera sample, anything the per-bounce checks let through is dropped to black
+    pub fn check_sample(&self, c: Vec3) -> Vec3 {
+        self.samples.fetch_add(1, Ordering::Relaxed);
+        self.check(c, "radiance", "camera sample")
+            .unwrap_or_else(Vec3::zero)
+    }
+    // the same for a sample of spectral paths, single wavelengths can lie outside of the RGB gamut
+    // so negative channels are valid and only average out over the pixel
+    pub fn check_spectral_sample(&self, c: Vec3) -> Vec3 {
+        match classify(c) {
+            None | Some(Problem::Negative) => {
+                self.samples.fetch_add(1, Ordering::Relaxed);
+                c
+            }
+            Some(_) => self.check_sample(c),
+        }
+    }
+
+    pub fn invalid_count(&self) -> u64 {
+        self.nan.load(Ordering::Relaxed)
+            + self.infinite.load(Ordering::Relaxed)
+            + self.negative.load(Ordering::Relaxed)
+    }
+}
+impl fmt::Display for SampleGuard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} invalid values in {} samples ({} NaN, {} infinite, {} negative)",
+            self.invalid_count(),
+            self.samples.load(Ordering::Relaxed),
+            self.nan.load(Ordering::Relaxed),
+            self.infinite.load(Ordering::Relaxed),
+            self.negative.load(Ordering::Relaxed)
+        )?;
+        let sources = self.sources.lock().unwrap();
+        let mut sources: Vec<_> = sources.iter().collect();
+        sources.sort_by(|a, b| b.1.cmp(a.1).then_with(|| (a.0).0.cmp(&(b.0).0)));
+        for ((source, problem), count) in sources {
+            write!(f, "\n    {:>10}  {} {}", count, problem, source)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tally(guard: &SampleGuard, source: &str, problem: Problem) -> u64 {
+        let sources = guard.sources.lock().unwrap();
+        sources
+            .get(&(source.to_string(), problem))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn valid_values_pass_unchanged() {
+        let guard = SampleGuard::new();
+        for &c in [
+            Vec3::zero(),
+            Vec3::new(0.25, 1.0, 3.0),
+            Vec3::new(1e300, 1e-300, 0.0),
+        ]
+        .iter()
+        {
+            let checked = guard.check(c, "attenuation", "Lambertian").unwrap();
+            assert_eq!(checked.x.to_bits(), c.x.to_bits());
+            assert_eq!(checked.y.to_bits(), c.y.to_bits());
+            assert_eq!(checked.z.to_bits(), c.z.to_bits());
+        }
+        assert_eq!(guard.check_scalar(0.5, "pdf", "Metal"), Some(0.5));
+        assert_eq!(guard.invalid_count(), 0);
+        assert!(guard.sources.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_values_are_dropped_or_clamped() {
+        let guard = SampleGuard::new();
+        let material = "raytracer::material::Lambertian<raytracer::texture::SolidColor>";
+        assert!(guard
+            .check(Vec3::new(1.0, f64::NAN, 0.0), "attenuation", material)
+            .is_none());
+        assert!(guard
+            .check(
+                Vec3::new(f64::INFINITY, 0.0, 0.0),
+                "emission",
+                "DiffuseLight"
+            )
+            .is_none());
+        assert!(guard
+            .check(
+                Vec3::new(f64::NEG_INFINITY, 1.0, 1.0),
+                "emission",
+                "DiffuseLight"
+            )
+            .is_none());
+        // NaN wins over the other problems of the same value
+        assert!(guard
+            .check(
+                Vec3::new(-1.0, f64::INFINITY, f64::NAN),
+                "attenuation",
+                material
+            )
+            .is_none());
+        let clamped = guard
+            .check(Vec3::new(-0.5, 0.25, -2.0), "pdf", "Metal")
+            .unwrap();
+        assert_eq!((clamped.x, clamped.y, clamped.z), (0.0, 0.25, 0.0));
+        assert_eq!(guard.check_scalar(-1.0, "pdf", "Metal"), Some(0.0));
+
+        assert_eq!(guard.invalid_count(), 6);
+        let source = "attenuation of Lambertian<SolidColor>";
+        assert_eq!(tally(&guard, source, Problem::NaN), 2);
+        assert_eq!(tally(&guard, source, Problem::Infinite), 0);
+        assert_eq!(
+            tally(&guard, "emission of DiffuseLight", Problem::Infinite),
+            2
+        );
+        assert_eq!(tally(&guard, "pdf of Metal", Problem::Negative), 2);
+        assert_eq!(guard.sources.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn camera_samples_are_counted_and_dropped_to_black() {
+        let guard = SampleGuard::new();
+        let c = guard.check_sample(Vec3::new(f64::NAN, 1.0, 1.0));
+        assert_eq!((c.x, c.y, c.z), (0.0, 0.0, 0.0));
+        let c = guard.check_sample(Vec3::new(0.5, 0.5, 0.5));
+        assert_eq!((c.x, c.y, c.z), (0.5, 0.5, 0.5));
+        // spectral samples keep their negative channels
+        let c = guard.check_spectral_sample(Vec3::new(-0.1, 0.5, 0.5));
+        assert_eq!((c.x, c.y, c.z), (-0.1, 0.5, 0.5));
+        let c = guard.check_spectral_sample(Vec3::new(f64::INFINITY, 0.5, 0.5));
+        assert_eq!((c.x, c.y, c.z), (0.0, 0.0, 0.0));
+        assert_eq!(guard.samples.load(Ordering::Relaxed), 4);
+        assert_eq!(guard.invalid_count(), 2);
+        assert_eq!(
+            tally(&guard, "radiance of camera sample", Problem::NaN)
+                + tally(&guard, "radiance of camera sample", Problem::Infinite),
+            2
+        );
+    }
+
+    #[test]
+    fn type_names_lose_their_paths() {
+        assert_eq!(
+            short_type_name("a::b::Lambertian<a::c::SolidColor>"),
+            "Lambertian<SolidColor>"
+        );
+        assert_eq!(short_type_name("Metal"), "Metal");
+    }
+}
@@ -0,0 +1,428 @@
+use crate::rtweekend::{self, mix_bits, random_f64};
+use std::boxed::Box;
+use std::cell::RefCell;
+use std::str::FromStr;
+
+// Samples for one camera sample are requested dimension by dimension: the pixel position first,
+// then the lens, the time and whatever the integrator needs at each bounce. Dimensions past the
+// ones a sampler is designed for still get well distributed values, every request is decorrelated
+// from the others by hashing its dimension index.
+pub trait Sampler {
+    fn start_pixel_sample(&mut self, seed: u64, pixel: u64, sample: u64);
+    fn get_1d(&mut self) -> f64;
+    fn get_2d(&mut self) -> (f64, f64);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+}
+impl FromStr for SamplerKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "independent" => Ok(SamplerKind::Independent),
+            "stratified" => Ok(SamplerKind::Stratified),
+            "halton" => Ok(SamplerKind::Halton),
+            "sobol" => Ok(SamplerKind::Sobol),
+            _ => Err(format!("unknown sampler `{}`", s)),
+        }
+    }
+}
+
+fn hash3(a: u64, b: u64, c: u64) -> u64 {
+    mix_bits(a ^ mix_bits(b ^ mix_bits(c)))
+}
+fn to_unit(h: u64) -> f64 {
+    (h >> 11) as f64 * (1.0 / (1u64 << 53) as f64)
+}
+// keeps values from a 32-bit fixed point computation strictly below one
+fn unit_from_u32(x: u32) -> f64 {
+    (x as f64 * (1.0 / 4_294_967_296.0)).min(1.0 - f64::EPSILON)
+}
+
+pub struct IndependentSampler;
+impl Sampler for IndependentSampler {
+    fn start_pixel_sample(&mut self, _seed: u64, _pixel: u64, _sample: u64) {}
+    fn get_1d(&mut self) -> f64 {
+        random_f64(0.0, 1.0)
+    }
+    fn get_2d(&mut self) -> (f64, f64) {
+        (random_f64(0.0, 1.0), random_f64(0.0, 1.0))
+    }
+}
+
+// Kensler's hash based permutation of [0, l), from "Correlated Multi-Jittered Sampling"
+fn permute(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+    (i.wrapping_add(p)) % l
+}
+
+// jittered strata, the order of the strata is shuffled independently for every dimension. Samples
+// past the samples per pixel start another round of strata with its own shuffles and jitter.
+pub struct StratifiedSampler {
+    samples_per_pixel: u32,
+    key: u64,
+    round: u64,
+    sample: u32,
+    dimension: u64,
+}
+impl StratifiedSampler {
+    pub fn new(samples_per_pixel: u32) -> Self {
+        Self {
+            samples_per_pixel: samples_per_pixel.max(1),
+            key: 0,
+            round: 0,
+            sample: 0,
+            dimension: 0,
+        }
+    }
+    fn next_dimension(&mut self) -> (u32, u64) {
+        let h = hash3(self.key, self.dimension, self.round);
+        self.dimension += 1;
+        (h as u32, h)
+    }
+    fn jitter(&self, h: u64, axis: u64) -> f64 {
+        to_unit(hash3(h, self.sample as u64, axis + 1))
+    }
+}
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, seed: u64, pixel: u64, sample: u64) {
+        self.key = hash3(seed, pixel, 0x5354_5241);
+        self.round = sample / self.samples_per_pixel as u64;
+        self.sample = (sample % self.samples_per_pixel as u64) as u32;
+        self.dimension = 0;
+    }
+    fn get_1d(&mut self) -> f64 {
+        let n = self.samples_per_pixel;
+        let (p, h) = self.next_dimension();
+        let stratum = permute(self.sample, n, p);
+        (stratum as f64 + self.jitter(h, 0)) / n as f64
+    }
+    fn get_2d(&mut self) -> (f64, f64) {
+        let n = self.samples_per_pixel;
+        let nx = (n as f64).sqrt().ceil() as u32;
+        let ny = (n + nx - 1) / nx;
+        let (p, h) = self.next_dimension();
+        let stratum = permute(self.sample, nx * ny, p);
+        (
+            ((stratum % nx) as f64 + self.jitter(h, 0)) / nx as f64,
+            ((stratum / nx) as f64 + self.jitter(h, 1)) / ny as f64,
+        )
+    }
+}
+
+const PRIMES: [u64; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193,
+    197, 199, 211, 223, 227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307,
+    311,
+];
+
+// radical inverse with every digit permuted by a hash of the digits below it (Owen scrambling),
+// digits past the end of the index are scrambled too so the result stays uniform. The digits of
+// the larger bases need more than 64 bits, the value is summed in floating point and the digits
+// below only go into the hash.
+fn owen_scrambled_radical_inverse(base: u64, mut index: u64, hash: u64) -> f64 {
+    let inv_base = 1.0 / base as f64;
+    let mut inv_base_m = 1.0;
+    let mut reversed_digits: u64 = 0;
+    let mut value = 0.0;
+    while 1.0 - (base - 1) as f64 * inv_base_m < 1.0 {
+        let next = index / base;
+        let digit = index - next * base;
+        let digit_hash = mix_bits(hash ^ reversed_digits);
+        let digit = permute(digit as u32, base as u32, digit_hash as u32) as u64;
+        reversed_digits = reversed_digits.wrapping_mul(base).wrapping_add(digit);
+        inv_base_m *= inv_base;
+        value += digit as f64 * inv_base_m;
+        index = next;
+    }
+    value.min(1.0 - f64::EPSILON)
+}
+
+// Owen scrambled Halton points, dimensions past the prime table fall back to independent samples
+pub struct HaltonSampler {
+    key: u64,
+    sample: u64,
+    dimension: usize,
+}
+impl HaltonSampler {
+    pub fn new() -> Self {
+        Self {
+            key: 0,
+            sample: 0,
+            dimension: 0,
+        }
+    }
+    fn next(&mut self) -> f64 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        match PRIMES.get(dimension) {
+            Some(&base) => owen_scrambled_radical_inverse(
+                base,
+                self.sample,
+                hash3(self.key, dimension as u64, 0),
+            ),
+            None => random_f64(0.0, 1.0),
+        }
+    }
+}
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, seed: u64, pixel: u64, sample: u64) {
+        self.key = hash3(seed, pixel, 0x4841_4c54);
+        self.sample = sample;
+        self.dimension = 0;
+    }
+    fn get_1d(&mut self) -> f64 {
+        self.next()
+    }
+    fn get_2d(&mut self) -> (f64, f64) {
+        let x = self.next();
+        (x, self.next())
+    }
+}
+
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x
+}
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+// the first two Sobol dimensions, van der Corput and the one built on x + 1
+fn sobol_2d(index: u32) -> (u32, u32) {
+    let (mut x, mut y) = (0u32, 0u32);
+    let mut direction = 1u32 << 31;
+    for bit in 0..32 {
+        if (index >> bit) & 1 == 1 {
+            x ^= 1u32 << (31 - bit);
+            y ^= direction;
+        }
+        direction ^= direction >> 1;
+    }
+    (x, y)
+}
+
+// Owen scrambled Sobol following Burley's "Practical Hash-based Owen Scrambling": every request
+// uses a freshly shuffled and scrambled copy of the first Sobol dimensions
+pub struct SobolSampler {
+    key: u64,
+    sample: u32,
+    dimension: u64,
+}
+impl SobolSampler {
+    pub fn new() -> Self {
+        Self {
+            key: 0,
+            sample: 0,
+            dimension: 0,
+        }
+    }
+    fn next_point(&mut self) -> (f64, f64) {
+        let h = hash3(self.key, self.dimension, 0);
+        self.dimension += 1;
+        let index = nested_uniform_scramble(self.sample, h as u32);
+        let (x, y) = sobol_2d(index);
+        (
+            unit_from_u32(nested_uniform_scramble(x, (h >> 32) as u32)),
+            unit_from_u32(nested_uniform_scramble(y, mix_bits(h) as u32)),
+        )
+    }
+}
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, seed: u64, pixel: u64, sample: u64) {
+        self.key = hash3(seed, pixel, 0x534f_424c);
+        self.sample = sample as u32;
+        self.dimension = 0;
+    }
+    fn get_1d(&mut self) -> f64 {
+        self.next_point().0
+    }
+    fn get_2d(&mut self) -> (f64, f64) {
+        self.next_point()
+    }
+}
+
+pub fn make_sampler(kind: SamplerKind, samples_per_pixel: u32) -> Box<dyn Sampler> {
+    match kind {
+        SamplerKind::Independent => Box::new(IndependentSampler),
+        SamplerKind::Stratified => Box::new(StratifiedSampler::new(samples_per_pixel)),
+        SamplerKind::Halton => Box::new(HaltonSampler::new()),
+        SamplerKind::Sobol => Box::new(SobolSampler::new()),
+    }
+}
+
+thread_local! {
+    static SAMPLER: RefCell<Box<dyn Sampler>> = RefCell::new(Box::new(IndependentSampler));
+}
+
+pub fn set_sampler(kind: SamplerKind, samples_per_pixel: u32) {
+    SAMPLER.with(|sampler| *sampler.borrow_mut() = make_sampler(kind, samples_per_pixel));
+}
+// starts a camera sample on the current thread, also keys the random stream behind random_f64
+pub fn start_sample(seed: u64, pixel: u64, sample: u64) {
+    rtweekend::start_sample(seed, pixel, sample);
+    SAMPLER.with(|sampler| sampler.borrow_mut().start_pixel_sample(seed, pixel, sample));
+}
+pub fn sample_1d() -> f64 {
+    SAMPLER.with(|sampler| sampler.borrow_mut().get_1d())
+}
+pub fn sample_2d() -> (f64, f64) {
+    SAMPLER.with(|sampler| sampler.borrow_mut().get_2d())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn first_dimension(sampler: &mut dyn Sampler, samples: std::ops::Range<u64>) -> Vec<f64> {
+        samples
+            .map(|sample| {
+                sampler.start_pixel_sample(7, 42, sample);
+                sampler.get_1d()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stratified_rounds_past_the_spp_get_fresh_strata() {
+        let mut sampler = StratifiedSampler::new(8);
+        let first = first_dimension(&mut sampler, 0..8);
+        let second = first_dimension(&mut sampler, 8..16);
+        for round in &[&first, &second] {
+            let mut strata: Vec<usize> = round.iter().map(|x| (x * 8.0) as usize).collect();
+            strata.sort_unstable();
+            assert_eq!(strata, (0..8).collect::<Vec<_>>());
+        }
+        assert!(first.iter().zip(&second).all(|(a, b)| a != b));
+    }
+
+    // points of the first `n` samples, each from its own `request`-th call to get_2d
+    fn points(sampler: &mut dyn Sampler, seed: u64, n: u64, request: usize) -> Vec<(f64, f64)> {
+        (0..n)
+            .map(|sample| {
+                sampler.start_pixel_sample(seed, 42, sample);
+                for _ in 0..request {
+                    sampler.get_2d();
+                }
+                sampler.get_2d()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sobol_points_are_stratified_in_every_elementary_interval() {
+        let mut sampler = SobolSampler::new();
+        for request in 0..3 {
+            for k in 0..=8 {
+                let points = points(&mut sampler, 3, 1 << k, request);
+                for a in 0..=k {
+                    let (nx, ny) = ((1 << a) as f64, (1 << (k - a)) as f64);
+                    let mut cells: Vec<(usize, usize)> = points
+                        .iter()
+                        .map(|&(x, y)| ((x * nx) as usize, (y * ny) as usize))
+                        .collect();
+                    cells.sort_unstable();
+                    cells.dedup();
+                    assert_eq!(cells.len(), 1 << k, "2^{} x 2^{} intervals", a, k - a);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn halton_dimensions_are_stratified_in_their_base() {
+        let mut sampler = HaltonSampler::new();
+        for &(dimension, base) in &[(0, 2), (1, 3), (2, 5)] {
+            let mut n = 1;
+            while n <= 125 {
+                let mut strata: Vec<usize> = (0..n as u64)
+                    .map(|sample| {
+                        sampler.start_pixel_sample(3, 42, sample);
+                        for _ in 0..dimension {
+                            sampler.get_1d();
+                        }
+                        (sampler.get_1d() * n as f64) as usize
+                    })
+                    .collect();
+                strata.sort_unstable();
+                assert_eq!(strata, (0..n).collect::<Vec<_>>(), "base {}", base);
+                n *= base;
+            }
+        }
+    }
+
+    #[test]
+    fn scrambling_is_deterministic_per_seed() {
+        for &kind in &[
+            SamplerKind::Stratified,
+            SamplerKind::Halton,
+            SamplerKind::Sobol,
+        ] {
+            let first = points(&mut *make_sampler(kind, 16), 3, 16, 1);
+            assert_eq!(first, points(&mut *make_sampler(kind, 16), 3, 16, 1));
+            assert_ne!(first, points(&mut *make_sampler(kind, 16), 4, 16, 1));
+        }
+    }
+
+    #[test]
+    fn samples_are_in_the_unit_interval() {
+        let kinds = [
+            SamplerKind::Independent,
+            SamplerKind::Stratified,
+            SamplerKind::Halton,
+            SamplerKind::Sobol,
+        ];
+        for &kind in &kinds {
+            let mut sampler = make_sampler(kind, 7);
+            let samples = (0..300).chain(u32::MAX as u64 - 300..u32::MAX as u64 + 300);
+            for sample in samples {
+                sampler.start_pixel_sample(11, sample ^ 5, sample);
+                // past the Halton prime table and the dimensions a camera sample usually needs
+                for _ in 0..40 {
+                    let x = sampler.get_1d();
+                    let (y, z) = sampler.get_2d();
+                    for &v in &[x, y, z] {
+                        assert!((0.0..1.0).contains(&v), "{:?} gave {}", kind, v);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,1276 @@
+use crate::constant_medium::ConstantMedium;
+use crate::mat4::Mat4;
+use crate::materialfile::{
+    absorption_from_transmittance, Coated, Conductor, Dispersion, Metal, Principled,
+    RoughDielectric, SpectralLight,
+};
+use crate::moving_sphere::MovingSphere;
+use crate::obj_loader::load_obj;
+use crate::quat::Quat;
+use crate::spectrum::{Spd, LAMBDA_MAX, LAMBDA_MIN};
+use crate::texture::{CheckerTexture, ImageTexture, NoiseTexture};
+use crate::triangle::{MeshData, Triangle, TriangleMesh};
+use crate::Camera;
+use crate::Hittable;
+use crate::HittableList;
+use crate::Material;
+use crate::RealBox;
+use crate::Sphere;
+use crate::Vec3;
+use crate::AABB;
+use crate::{AnimatedTransform, FlipFace, Instance, Keyframe, Transform};
+use crate::{Dielectric, DiffuseLight, Isotropic, Lambertian};
+use crate::{SolidColor, Texture};
+use crate::{XYRect, XZRect, YZRect};
+use std::boxed::Box;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::sync::Arc;
+use toml::value::Table;
+use toml::Value;
+
+pub struct Scene {
+    pub world: HittableList,
+    pub lights: HittableList,
+    pub camera: Camera,
+    pub background: Vec3,
+}
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io(String, std::io::Error),
+    Parse(String, toml::de::Error),
+    Invalid { entry: String, message: String },
+}
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneError::Io(path, err) => write!(f, "cannot read scene `{}`: {}", path, err),
+            SceneError::Parse(path, err) => write!(f, "cannot parse scene `{}`: {}", path, err),
+            SceneError::Invalid { entry, message } => write!(f, "{}: {}", entry, message),
+        }
+    }
+}
+impl std::error::Error for SceneError {}
+
+fn invalid<T>(entry: &str, message: String) -> Result<T, SceneError> {
+    Err(SceneError::Invalid {
+        entry: entry.to_string(),
+        message,
+    })
+}
+
+fn as_table<'a>(value: &'a Value, entry: &str) -> Result<&'a Table, SceneError> {
+    match value.as_table() {
+        Some(table) => Ok(table),
+        None => invalid(entry, "expected a table".to_string()),
+    }
+}
+fn as_f64(value: &Value) -> Option<f64> {
+    match value {
+        Value::Float(x) => Some(*x),
+        Value::Integer(x) => Some(*x as f64),
+        _ => None,
+    }
+}
+fn as_vec3(value: &Value) -> Option<Vec3> {
+    let array = value.as_array()?;
+    if array.len() != 3 {
+        return None;
+    }
+    Some(Vec3::new(
+        as_f64(&array[0])?,
+        as_f64(&array[1])?,
+        as_f64(&array[2])?,
+    ))
+}
+
+fn get_f64(table: &Table, entry: &str, field: &str) -> Result<f64, SceneError> {
+    match table.get(field) {
+        None => invalid(entry, format!("missing field `{}`", field)),
+        Some(value) => match as_f64(value) {
+            Some(x) => Ok(x),
+            None => invalid(entry, format!("field `{}` must be a number", field)),
+        },
+    }
+}
+fn get_f64_or(table: &Table, entry: &str, field: &str, default: f64) -> Result<f64, SceneError> {
+    if table.contains_key(field) {
+        get_f64(table, entry, field)
+    } else {
+        Ok(default)
+    }
+}
+fn get_vec3(table: &Table, entry: &str, field: &str) -> Result<Vec3, SceneError> {
+    match table.get(field) {
+        None => invalid(entry, format!("missing field `{}`", field)),
+        Some(value) => match as_vec3(value) {
+            Some(v) => Ok(v),
+            None => invalid(
+                entry,
+                format!("field `{}` must be an array of three numbers", field),
+            ),
+        },
+    }
+}
+fn get_vec3_or(table: &Table, entry: &str, field: &str, default: Vec3) -> Result<Vec3, SceneError> {
+    if table.contains_key(field) {
+        get_vec3(table, entry, field)
+    } else {
+        Ok(default)
+    }
+}
+fn get_str<'a>(table: &'a Table, entry: &str, field: &str) -> Result<&'a str, SceneError> {
+    match table.get(field) {
+        None => invalid(entry, format!("missing field `{}`", field)),
+        Some(value) => match value.as_str() {
+            Some(s) => Ok(s),
+            None => invalid(entry, format!("field `{}` must be a string", field)),
+        },
+    }
+}
+fn get_bool_or(table: &Table, entry: &str, field: &str, default: bool) -> Result<bool, SceneError> {
+    match table.get(field) {
+        None => Ok(default),
+        Some(value) => match value.as_bool() {
+            Some(b) => Ok(b),
+            None => invalid(entry, format!("field `{}` must be a boolean", field)),
+        },
+    }
+}
+
+fn get_array<'a>(table: &'a Table, entry: &str, field: &str) -> Result<&'a Vec<Value>, SceneError> {
+    match table.get(field) {
+        None => invalid(entry, format!("missing field `{}`", field)),
+        Some(value) => match value.as_array() {
+            Some(array) => Ok(array),
+            None => invalid(entry, format!("field `{}` must be an array", field)),
+        },
+    }
+}
+fn get_vec3_list(table: &Table, entry: &str, field: &str) -> Result<Vec<Vec3>, SceneError> {
+    let mut list = Vec::new();
+    for (i, value) in get_array(table, entry, field)?.iter().enumerate() {
+        match as_vec3(value) {
+            Some(v) => list.push(v),
+            None => {
+                return invalid(
+                    entry,
+                    format!("`{}[{}]` must be an array of three numbers", field, i),
+                )
+            }
+        }
+    }
+    Ok(list)
+}
+fn get_uv_list(table: &Table, entry: &str, field: &str) -> Result<Vec<[f64; 2]>, SceneError> {
+    let mut list = Vec::new();
+    for (i, value) in get_array(table, entry, field)?.iter().enumerate() {
+        let uv = value.as_array().filter(|uv| uv.len() == 2);
+        match uv.and_then(|uv| Some([as_f64(&uv[0])?, as_f64(&uv[1])?])) {
+            Some(uv) => list.push(uv),
+            None => {
+                return invalid(
+                    entry,
+                    format!("`{}[{}]` must be an array of two numbers", field, i),
+                )
+            }
+        }
+    }
+    Ok(list)
+}
+fn get_index_list(table: &Table, entry: &str, field: &str) -> Result<Vec<[usize; 3]>, SceneError> {
+    let mut list = Vec::new();
+    for (i, value) in get_array(table, entry, field)?.iter().enumerate() {
+        let face = value.as_array().filter(|face| face.len() == 3);
+        let index = |v: &Value| v.as_integer().filter(|&x| x >= 0).map(|x| x as usize);
+        match face.and_then(|f| Some([index(&f[0])?, index(&f[1])?, index(&f[2])?])) {
+            Some(face) => list.push(face),
+            None => {
+                return invalid(
+                    entry,
+                    format!(
+                        "`{}[{}]` must be an array of three vertex indices",
+                        field, i
+                    ),
+                )
+            }
+        }
+    }
+    Ok(list)
+}
+
+// rows of an affine matrix, the last row may be left out and must be [0, 0, 0, 1] otherwise
+fn get_matrix(table: &Table, entry: &str, field: &str) -> Result<Mat4, SceneError> {
+    let rows = get_array(table, entry, field)?;
+    let mut m = Mat4::identity().m;
+    if rows.len() != 3 && rows.len() != 4 {
+        return invalid(entry, format!("`{}` must have three or four rows", field));
+    }
+    for (i, row) in rows.iter().enumerate() {
+        let row = row.as_array().filter(|row| row.len() == 4);
+        match row.and_then(|r| {
+            Some([
+                as_f64(&r[0])?,
+                as_f64(&r[1])?,
+                as_f64(&r[2])?,
+                as_f64(&r[3])?,
+            ])
+        }) {
+            Some(row) => m[i] = row,
+            None => {
+                return invalid(
+                    entry,
+                    format!("`{}[{}]` must be an array of four numbers", field, i),
+                )
+            }
+        }
+    }
+    let last_row = [0.0, 0.0, 0.0, 1.0];
+    if m[3]
+        .iter()
+        .zip(&last_row)
+        .any(|(x, y)| (x - y).abs() > 1e-9)
+    {
+        return invalid(
+            entry,
+            format!("the last row of `{}` must be [0, 0, 0, 1]", field),
+        );
+    }
+    Ok(Mat4::new(m))
+}
+
+const TRANSFORMS: [&str; 8] = [
+    "translate",
+    "rotate_x",
+    "rotate_y",
+    "rotate_z",
+    "rotate",
+    "scale",
+    "shear",
+    "matrix",
+];
+
+// one entry of an object's `transforms` list
+fn build_transform(table: &Table, entry: &str) -> Result<Mat4, SceneError> {
+    let keys: Vec<&str> = TRANSFORMS
+        .iter()
+        .copied()
+        .filter(|key| table.contains_key(*key))
+        .collect();
+    if keys.len() != 1 {
+        return invalid(
+            entry,
+            format!("expected exactly one of `{}`", TRANSFORMS.join("`, `")),
+        );
+    }
+    let matrix = match keys[0] {
+        "translate" => Mat4::translate(get_vec3(table, entry, "translate")?),
+        "rotate_x" => Mat4::rotate(Vec3::new(1.0, 0.0, 0.0), get_f64(table, entry, "rotate_x")?),
+        "rotate_y" => Mat4::rotate(Vec3::new(0.0, 1.0, 0.0), get_f64(table, entry, "rotate_y")?),
+        "rotate_z" => Mat4::rotate(Vec3::new(0.0, 0.0, 1.0), get_f64(table, entry, "rotate_z")?),
+        "rotate" => {
+            let axis = get_vec3(table, entry, "axis")?;
+            if axis.squared_length() == 0.0 {
+                return invalid(entry, "`axis` must not be zero".to_string());
+            }
+            Mat4::rotate(axis, get_f64(table, entry, "rotate")?)
+        }
+        // a single number scales uniformly
+        "scale" => match table.get("scale").and_then(as_f64) {
+            Some(factor) => Mat4::scale(Vec3::new(factor, factor, factor)),
+            None => Mat4::scale(get_vec3(table, entry, "scale")?),
+        },
+        "shear" => {
+            let shear_entry = format!("{}.shear", entry);
+            let shear = as_table(&table["shear"], &shear_entry)?;
+            for key in shear.keys() {
+                if !["xy", "xz", "yx", "yz", "zx", "zy"].contains(&key.as_str()) {
+                    return invalid(&shear_entry, format!("unknown shear factor `{}`", key));
+                }
+            }
+            let factor = |key| get_f64_or(shear, &shear_entry, key, 0.0);
+            Mat4::shear(
+                factor("xy")?,
+                factor("xz")?,
+                factor("yx")?,
+                factor("yz")?,
+                factor("zx")?,
+                factor("zy")?,
+            )
+        }
+        _ => get_matrix(table, entry, "matrix")?,
+    };
+    Ok(matrix)
+}
+
+// the composed matrix of an object's `transforms` list, None if it has none
+fn get_transforms(table: &Table, entry: &str) -> Result<Option<Mat4>, SceneError> {
+    let transforms = match table.get("transforms") {
+        Some(value) => match value.as_array() {
+            Some(transforms) => transforms,
+            None => return invalid(entry, "field `transforms` must be an array".to_string()),
+        },
+        None => return Ok(None),
+    };
+    // applied in order, composed into a single matrix
+    let mut matrix = Mat4::identity();
+    for (i, transform) in transforms.iter().enumerate() {
+        let transform_entry = format!("{}.transforms[{}]", entry, i);
+        let transform = as_table(transform, &transform_entry)?;
+        matrix = build_transform(transform, &transform_entry)? * matrix;
+    }
+    Ok(Some(matrix))
+}
+fn singular_transforms<T>(entry: &str) -> Result<T, SceneError> {
+    invalid(
+        entry,
+        "the composed `transforms` matrix is not invertible".to_string(),
+    )
+}
+
+// one entry of an object's `keyframes` list
+fn build_keyframe(table: &Table, entry: &str) -> Result<Keyframe, SceneError> {
+    for key in table.keys() {
+        if !["time", "translate", "rotate", "axis", "scale"].contains(&key.as_str()) {
+            return invalid(entry, format!("unknown keyframe field `{}`", key));
+        }
+    }
+    let rotation = if table.contains_key("rotate") {
+        let axis = get_vec3(table, entry, "axis")?;
+        if axis.squared_length() == 0.0 {
+            return invalid(entry, "`axis` must not be zero".to_string());
+        }
+        Quat::from_axis_angle(axis, get_f64(table, entry, "rotate")?)
+    } else {
+        Quat::identity()
+    };
+    // a single number scales uniformly
+    let scale = match table.get("scale").and_then(as_f64) {
+        Some(factor) => Vec3::new(factor, factor, factor),
+        None => get_vec3_or(table, entry, "scale", Vec3::ones())?,
+    };
+    Ok(Keyframe {
+        time: get_f64(table, entry, "time")?,
+        translation: get_vec3_or(table, entry, "translate", Vec3::zero())?,
+        rotation,
+        scale,
+    })
+}
+
+// the poses of an animated object, None if it doesn't move
+fn get_keyframes(table: &Table, entry: &str) -> Result<Option<Vec<Keyframe>>, SceneError> {
+    if !table.contains_key("keyframes") {
+        return Ok(None);
+    }
+    let values = get_array(table, entry, "keyframes")?;
+    if values.is_empty() {
+        return invalid(entry, "field `keyframes` must not be empty".to_string());
+    }
+    let mut keyframes = Vec::new();
+    for (i, value) in values.iter().enumerate() {
+        let keyframe_entry = format!("{}.keyframes[{}]", entry, i);
+        keyframes.push(build_keyframe(
+            as_table(value, &keyframe_entry)?,
+            &keyframe_entry,
+        )?);
+    }
+    Ok(Some(keyframes))
+}
+
+// how strongly the inside of a dielectric absorbs, either the coefficient itself or the color
+// that is left after `transmittance_distance`, clear if neither is given
+fn get_absorption(table: &Table, entry: &str) -> Result<Vec3, SceneError> {
+    if table.contains_key("absorption") {
+        if table.contains_key("transmittance") {
+            return invalid(
+                entry,
+                "expected only one of `absorption` and `transmittance`".to_string(),
+            );
+        }
+        let absorption = get_vec3(table, entry, "absorption")?;
+        if absorption.x < 0.0 || absorption.y < 0.0 || absorption.z < 0.0 {
+            return invalid(entry, "`absorption` must not be negative".to_string());
+        }
+        Ok(absorption)
+    } else if table.contains_key("transmittance") {
+        let color = get_vec3(table, entry, "transmittance")?;
+        if [color.x, color.y, color.z]
+            .iter()
+            .any(|&c| c <= 0.0 || c > 1.0)
+        {
+            return invalid(
+                entry,
+                "`transmittance` must be in (0, 1] on every channel".to_string(),
+            );
+        }
+        let distance = get_f64_or(table, entry, "transmittance_distance", 1.0)?;
+        if distance <= 0.0 {
+            return invalid(
+                entry,
+                "`transmittance_distance` must be positive".to_string(),
+            );
+        }
+        Ok(absorption_from_transmittance(color, distance))
+    } else {
+        Ok(Vec3::zero())
+    }
+}
+
+// a wavelength dependent index of refraction, which replaces `ior`
+fn get_dispersion(table: &Table, entry: &str) -> Result<Option<Dispersion>, SceneError> {
+    let keys: Vec<&str> = ["cauchy", "sellmeier_b"]
+        .iter()
+        .copied()
+        .filter(|key| table.contains_key(*key))
+        .collect();
+    if keys.is_empty() {
+        return Ok(None);
+    }
+    if keys.len() > 1 || table.contains_key("ior") {
+        return invalid(
+            entry,
+            "expected only one of `ior`, `cauchy` and `sellmeier_b`".to_string(),
+        );
+    }
+    let coefficients = |field: &str, n: usize| -> Result<Vec<f64>, SceneError> {
+        let values = get_array(table, entry, field)?;
+        match values.iter().map(as_f64).collect::<Option<Vec<f64>>>() {
+            Some(values) if values.len() == n => Ok(values),
+            _ => invalid(
+                entry,
+                format!("field `{}` must be an array of {} numbers", field, n),
+            ),
+        }
+    };
+    let dispersion = if keys[0] == "cauchy" {
+        let c = coefficients("cauchy", 2)?;
+        Dispersion::Cauchy { a: c[0], b: c[1] }
+    } else {
+        let b = coefficients("sellmeier_b", 3)?;
+        let c = coefficients("sellmeier_c", 3)?;
+        Dispersion::Sellmeier {
+            b: [b[0], b[1], b[2]],
+            c: [c[0], c[1], c[2]],
+        }
+    };
+    // the index of refraction has to stay real over all traced wavelengths
+    let mut lambda = LAMBDA_MIN;
+    while lambda <= LAMBDA_MAX {
+        let ior = dispersion.ior(lambda);
+        if !ior.is_finite() || ior <= 0.0 {
+            return invalid(
+                entry,
+                format!("the index of refraction is invalid at {} nm", lambda),
+            );
+        }
+        lambda += 10.0;
+    }
+    Ok(Some(dispersion))
+}
+// a blackbody `temperature` in kelvin or measured `values` at increasing `wavelengths` in nm
+fn get_spd(table: &Table, entry: &str) -> Result<Spd, SceneError> {
+    if table.contains_key("temperature") {
+        if table.contains_key("wavelengths") {
+            return invalid(
+                entry,
+                "expected only one of `temperature` and `wavelengths`".to_string(),
+            );
+        }
+        let temperature = get_f64(table, entry, "temperature")?;
+        if temperature <= 0.0 {
+            return invalid(entry, "field `temperature` must be positive".to_string());
+        }
+        return Ok(Spd::Blackbody(temperature));
+    }
+    let numbers = |field: &str| -> Result<Vec<f64>, SceneError> {
+        match get_array(table, entry, field)?
+            .iter()
+            .map(as_f64)
+            .collect::<Option<Vec<f64>>>()
+        {
+            Some(values) => Ok(values),
+            None => invalid(
+                entry,
+                format!("field `{}` must be an array of numbers", field),
+            ),
+        }
+    };
+    let lambda = numbers("wavelengths")?;
+    let values = numbers("values")?;
+    if lambda.len() < 2 || lambda.len() != values.len() {
+        return invalid(
+            entry,
+            "`wavelengths` and `values` must have the same length of at least two".to_string(),
+        );
+    }
+    if lambda.windows(2).any(|w| w[0] >= w[1]) {
+        return invalid(entry, "`wavelengths` must be increasing".to_string());
+    }
+    if values.iter().any(|&v| v < 0.0) {
+        return invalid(entry, "`values` must not be negative".to_string());
+    }
+    Ok(Spd::Sampled { lambda, values })
+}
+
+// The names in a section of named entries, ordered so that every entry comes after the ones it
+// refers to. `refs` lists the names an entry refers to, names that aren't in the section are
+// left for the entry itself to report.
+fn dependency_order(
+    section: &Table,
+    section_name: &str,
+    refs: fn(&Table) -> Vec<&str>,
+) -> Result<Vec<String>, SceneError> {
+    // false while the references of an entry are followed, true once it is in `order`
+    fn visit(
+        name: &str,
+        section: &Table,
+        section_name: &str,
+        refs: fn(&Table) -> Vec<&str>,
+        done: &mut HashMap<String, bool>,
+        order: &mut Vec<String>,
+    ) -> Result<(), SceneError> {
+        match done.get(name) {
+            Some(true) => return Ok(()),
+            Some(false) => {
+                return invalid(
+                    &format!("{}.{}", section_name, name),
+                    "the entry refers back to itself".to_string(),
+                )
+            }
+            None => {}
+        }
+        done.insert(name.to_string(), false);
+        if let Some(table) = section[name].as_table() {
+            for r in refs(table) {
+                if section.contains_key(r) {
+                    visit(r, section, section_name, refs, done, order)?;
+                }
+            }
+        }
+        done.insert(name.to_string(), true);
+        order.push(name.to_string());
+        Ok(())
+    }
+    let mut done = HashMap::new();
+    let mut order = Vec::new();
+    for name in section.keys() {
+        visit(name, section, section_name, refs, &mut done, &mut order)?;
+    }
+    Ok(order)
+}
+// the material a coat is applied to
+fn material_refs(table: &Table) -> Vec<&str> {
+    match table.get("type").and_then(Value::as_str) {
+        Some("coated") => table
+            .get("base")
+            .and_then(Value::as_str)
+            .into_iter()
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+// the geometry an instance uses, also inside the boundary of a medium
+fn geometry_refs(table: &Table) -> Vec<&str> {
+    let mut refs = Vec::new();
+    if table.get("type").and_then(Value::as_str) == Some("instance") {
+        refs.extend(table.get("geometry").and_then(Value::as_str));
+    }
+    if let Some(boundary) = table.get("boundary").and_then(Value::as_table) {
+        refs.extend(geometry_refs(boundary));
+    }
+    refs
+}
+
+struct Loader {
+    textures: HashMap<String, Arc<dyn Texture>>,
+    materials: HashMap<String, Arc<dyn Material>>,
+    // objects that instances refer to, built once and shared
+    geometry: HashMap<String, Arc<dyn Hittable>>,
+    // geometry that cannot be sampled as a light
+    unsampled: HashSet<String>,
+}
+
+impl Loader {
+    fn texture(
+        &self,
+        table: &Table,
+        entry: &str,
+        color_field: &str,
+    ) -> Result<Arc<dyn Texture>, SceneError> {
+        if table.contains_key("texture") {
+            let name = get_str(table, entry, "texture")?;
+            match self.textures.get(name) {
+                Some(texture) => Ok(texture.clone()),
+                None => invalid(entry, format!("undefined texture `{}`", name)),
+            }
+        } else {
+            let color = get_vec3(table, entry, color_field)?;
+            Ok(Arc::new(SolidColor::new1(color)))
+        }
+    }
+    // a parameter that is a number, a color or the name of a texture, None if it is missing
+    fn texture_param(
+        &self,
+        table: &Table,
+        entry: &str,
+        field: &str,
+    ) -> Result<Option<Arc<dyn Texture>>, SceneError> {
+        let value = match table.get(field) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        if let Some(name) = value.as_str() {
+            return match self.textures.get(name) {
+                Some(texture) => Ok(Some(texture.clone())),
+                None => invalid(entry, format!("undefined texture `{}`", name)),
+            };
+        }
+        let color = match (as_f64(value), as_vec3(value)) {
+            (Some(x), _) => Vec3::new(x, x, x),
+            (_, Some(color)) => color,
+            _ => {
+                return invalid(
+                    entry,
+                    format!(
+                        "field `{}` must be a number, an array of three numbers or a texture name",
+                        field
+                    ),
+                )
+            }
+        };
+        Ok(Some(Arc::new(SolidColor::new1(color))))
+    }
+    fn principled(&self, table: &Table, entry: &str) -> Result<Principled, SceneError> {
+        let base_color = match self.texture_param(table, entry, "base_color")? {
+            Some(texture) => texture,
+            None => return invalid(entry, "missing field `base_color`".to_string()),
+        };
+        let mut principled = Principled::new(base_color);
+        let params = [
+            ("metallic", &mut principled.metallic),
+            ("roughness", &mut principled.roughness),
+            ("specular", &mut principled.specular),
+            ("specular_tint", &mut principled.specular_tint),
+            ("sheen", &mut principled.sheen),
+            ("sheen_tint", &mut principled.sheen_tint),
+            ("clearcoat", &mut principled.clearcoat),
+            ("clearcoat_gloss", &mut principled.clearcoat_gloss),
+            ("transmission", &mut principled.transmission),
+            ("ior", &mut principled.ior),
+            ("anisotropic", &mut principled.anisotropic),
+        ];
+        for (field, param) in params {
+            if let Some(texture) = self.texture_param(table, entry, field)? {
+                *param = texture;
+            }
+        }
+        Ok(principled)
+    }
+    fn material(&self, table: &Table, entry: &str) -> Result<Arc<dyn Material>, SceneError> {
+        let name = get_str(table, entry, "material")?;
+        match self.materials.get(name) {
+            Some(material) => Ok(material.clone()),
+            None => invalid(entry, format!("undefined material `{}`", name)),
+        }
+    }
+
+    // a medium has no surface to sample, and neither has an instance of one
+    fn can_sample(&self, table: &Table) -> bool {
+        match table.get("type").and_then(Value::as_str) {
+            Some("constant_medium") => false,
+            Some("instance") => match table.get("geometry").and_then(Value::as_str) {
+                Some(name) => !self.unsampled.contains(name),
+                None => true,
+            },
+            _ => true,
+        }
+    }
+
+    fn build_texture(&self, table: &Table, entry: &str) -> Result<Arc<dyn Texture>, SceneError> {
+        let kind = get_str(table, entry, "type")?;
+        let texture: Arc<dyn Texture> = match kind {
+            "solid" => Arc::new(SolidColor::new1(get_vec3(table, entry, "color")?)),
+            "checker" => Arc::new(CheckerTexture::new2(
+                get_vec3(table, entry, "even")?,
+                get_vec3(table, entry, "odd")?,
+            )),
+            "noise" => Arc::new(NoiseTexture::new(get_f64(table, entry, "scale")?)),
+            "image" => {
+                let path = get_str(table, entry, "path")?;
+                match ImageTexture::open(path) {
+                    Ok(texture) => Arc::new(texture),
+                    Err(err) => {
+                        return invalid(entry, format!("cannot open image `{}`: {}", path, err))
+                    }
+                }
+            }
+            _ => return invalid(entry, format!("unknown texture type `{}`", kind)),
+        };
+        Ok(texture)
+    }
+
+    fn build_material(&self, table: &Table, entry: &str) -> Result<Arc<dyn Material>, SceneError> {
+        let kind = get_str(table, entry, "type")?;
+        let material: Arc<dyn Material> = match kind {
+            "lambertian" => Arc::new(Lambertian::new1(self.texture(table, entry, "albedo")?)),
+            "metal" => Arc::new(Metal::new(
+                get_vec3(table, entry, "albedo")?,
+                get_f64_or(table, entry, "fuzz", 0.0)?,
+            )),
+            // a named metal or its complex index of refraction
+            "conductor" => {
+                let roughness = get_f64_or(table, entry, "roughness", 0.0)?;
+                if table.contains_key("preset") {
+                    let name = get_str(table, entry, "preset")?;
+                    match Conductor::preset(name, roughness) {
+                        Some(conductor) => Arc::new(conductor),
+                        None => return invalid(entry, format!("unknown conductor `{}`", name)),
+                    }
+                } else {
+                    Arc::new(Conductor::new(
+                        get_vec3(table, entry, "eta")?,
+                        get_vec3(table, entry, "k")?,
+                        roughness,
+                    ))
+                }
+            }
+            "dielectric" => {
+                let dielectric = match get_dispersion(table, entry)? {
+                    Some(dispersion) => Dielectric::dispersive(dispersion),
+                    None => Dielectric::new(get_f64(table, entry, "ior")?),
+                };
+                Arc::new(dielectric.with_absorption(get_absorption(table, entry)?))
+            }
+            "rough_dielectric" => {
+                let roughness = get_f64(table, entry, "roughness")?;
+                let dielectric = match get_dispersion(table, entry)? {
+                    Some(dispersion) => RoughDielectric::dispersive(dispersion, roughness),
+                    None => RoughDielectric::new(get_f64(table, entry, "ior")?, roughness),
+                };
+                Arc::new(dielectric.with_absorption(get_absorption(table, entry)?))
+            }
+            "principled" => Arc::new(self.principled(table, entry)?),
+            // a dielectric coat over the material named `base`, absorbing if it has a color
+            "coated" => {
+                let name = get_str(table, entry, "base")?;
+                let base = match self.materials.get(name) {
+                    Some(material) => material.clone(),
+                    None => return invalid(entry, format!("undefined material `{}`", name)),
+                };
+                let ior = get_f64_or(table, entry, "ior", 1.5)?;
+                if ior < 1.0 {
+                    return invalid(entry, "`ior` must be at least 1".to_string());
+                }
+                let thickness = get_f64_or(table, entry, "thickness", 1.0)?;
+                if thickness < 0.0 {
+                    return invalid(entry, "`thickness` must not be negative".to_string());
+                }
+                Arc::new(
+                    Coated::new(base, ior)
+                        .with_absorption(get_absorption(table, entry)?, thickness),
+                )
+            }
+            "diffuse_light" => Arc::new(DiffuseLight::new1(self.texture(table, entry, "emit")?)),
+            "spectral_light" => Arc::new(SpectralLight::new(
+                get_spd(table, entry)?,
+                get_f64_or(table, entry, "luminance", 1.0)?,
+            )),
+            "isotropic" => Arc::new(Isotropic::new2(self.texture(table, entry, "albedo")?)),
+            _ => return invalid(entry, format!("unknown material type `{}`", kind)),
+        };
+        Ok(material)
+    }
+
+    fn build_object(&self, table: &Table, entry: &str) -> Result<Box<dyn Hittable>, SceneError> {
+        let kind = get_str(table, entry, "type")?;
+        let entry = &format!("{} ({})", entry, kind);
+        let mut object: Box<dyn Hittable> = match kind {
+            "sphere" => Box::new(Sphere::new(
+                get_vec3(table, entry, "center")?,
+                get_f64(table, entry, "radius")?,
+                self.material(table, entry)?,
+            )),
+            "moving_sphere" => Box::new(MovingSphere::new(
+                get_vec3(table, entry, "center0")?,
+                get_vec3(table, entry, "center1")?,
+                get_f64(table, entry, "time0")?,
+                get_f64(table, entry, "time1")?,
+                get_f64(table, entry, "radius")?,
+                self.material(table, entry)?,
+            )),
+            "xy_rect" => Box::new(XYRect::new(
+                get_f64(table, entry, "x0")?,
+                get_f64(table, entry, "x1")?,
+                get_f64(table, entry, "y0")?,
+                get_f64(table, entry, "y1")?,
+                get_f64(table, entry, "k")?,
+                self.material(table, entry)?,
+            )),
+            "xz_rect" => Box::new(XZRect::new(
+                get_f64(table, entry, "x0")?,
+                get_f64(table, entry, "x1")?,
+                get_f64(table, entry, "z0")?,
+                get_f64(table, entry, "z1")?,
+                get_f64(table, entry, "k")?,
+                self.material(table, entry)?,
+            )),
+            "yz_rect" => Box::new(YZRect::new(
+                get_f64(table, entry, "y0")?,
+                get_f64(table, entry, "y1")?,
+                get_f64(table, entry, "z0")?,
+                get_f64(table, entry, "z1")?,
+                get_f64(table, entry, "k")?,
+                self.material(table, entry)?,
+            )),
+            "box" => Box::new(RealBox::new(
+                get_vec3(table, entry, "min")?,
+                get_vec3(table, entry, "max")?,
+                self.material(table, entry)?,
+            )),
+            "triangle" => {
+                let mut triangle = Triangle::new(
+                    get_vec3(table, entry, "p0")?,
+                    get_vec3(table, entry, "p1")?,
+                    get_vec3(table, entry, "p2")?,
+                    self.material(table, entry)?,
+                );
+                if table.contains_key("normals") {
+                    let n = get_vec3_list(table, entry, "normals")?;
+                    if n.len() != 3 {
+                        return invalid(
+                            entry,
+                            "field `normals` must hold three normals".to_string(),
+                        );
+                    }
+                    triangle = triangle.with_normals(n[0], n[1], n[2]);
+                }
+                if table.contains_key("uvs") {
+                    let uv = get_uv_list(table, entry, "uvs")?;
+                    if uv.len() != 3 {
+                        return invalid(entry, "field `uvs` must hold three uvs".to_string());
+                    }
+                    triangle = triangle.with_uvs(uv[0], uv[1], uv[2]);
+                }
+                Box::new(triangle)
+            }
+            "mesh" => {
+                let mut mesh = MeshData::new(
+                    get_vec3_list(table, entry, "positions")?,
+                    get_index_list(table, entry, "indices")?,
+                );
+                if table.contains_key("normals") {
+                    mesh.normals = get_vec3_list(table, entry, "normals")?;
+                }
+                if table.contains_key("uvs") {
+                    mesh.uvs = get_uv_list(table, entry, "uvs")?;
+                }
+                if !mesh.is_valid() {
+                    return invalid(
+                        entry,
+                        "vertex attributes differ in length or an index is out of range"
+                            .to_string(),
+                    );
+                }
+                Box::new(TriangleMesh::new(mesh, self.material(table, entry)?))
+            }
+            "obj" => {
+                let path = get_str(table, entry, "path")?;
+                let material = if table.contains_key("material") {
+                    Some(self.material(table, entry)?)
+                } else {
+                    None
+                };
+                match load_obj(path, material) {
+                    Ok(objects) => Box::new(objects),
+                    Err(err) => return invalid(entry, err.to_string()),
+                }
+            }
+            "constant_medium" => {
+                let boundary_entry = format!("{}.boundary", entry);
+                let boundary = match table.get("boundary") {
+                    Some(value) => as_table(value, &boundary_entry)?,
+                    None => return invalid(entry, "missing field `boundary`".to_string()),
+                };
+                Box::new(ConstantMedium::new1(
+                    self.build_object(boundary, &boundary_entry)?,
+                    get_f64(table, entry, "density")?,
+                    self.texture(table, entry, "albedo")?,
+                ))
+            }
+            // the transforms of an instance are part of it, the material replaces the geometry's
+            "instance" => {
+                let name = get_str(table, entry, "geometry")?;
+                let geometry = match self.geometry.get(name) {
+                    Some(geometry) => geometry.clone(),
+                    None => return invalid(entry, format!("undefined geometry `{}`", name)),
+                };
+                let material = if table.contains_key("material") {
+                    Some(self.material(table, entry)?)
+                } else {
+                    None
+                };
+                let matrix = get_transforms(table, entry)?.unwrap_or_else(Mat4::identity);
+                match Instance::new(geometry, matrix, material) {
+                    Some(instance) => Box::new(instance),
+                    None => return singular_transforms(entry),
+                }
+            }
+            _ => return invalid(entry, format!("unknown object type `{}`", kind)),
+        };
+        if get_bool_or(table, entry, "flip_face", false)? {
+            object = Box::new(FlipFace::new(object));
+        }
+        if kind != "instance" {
+            if let Some(matrix) = get_transforms(table, entry)? {
+                object = match Transform::new(object, matrix) {
+                    Some(transformed) => Box::new(transformed),
+                    None => return singular_transforms(entry),
+                };
+            }
+        }
+        // the animation moves the object after its own transforms
+        if let Some(keyframes) = get_keyframes(table, entry)? {
+            object = match AnimatedTransform::new(object, keyframes) {
+                Some(animated) => Box::new(animated),
+                None => return invalid(entry, "a keyframe scales an axis to zero".to_string()),
+            };
+        }
+        Ok(object)
+    }
+}
+
+fn build_camera(table: &Table, aspect_ratio: f64) -> Result<Camera, SceneError> {
+    let entry = "camera";
+    Ok(Camera::new(
+        get_vec3(table, entry, "lookfrom")?,
+        get_vec3(table, entry, "lookat")?,
+        get_vec3_or(table, entry, "vup", Vec3::new(0.0, 1.0, 0.0))?,
+        &get_f64(table, entry, "vfov")?,
+        &aspect_ratio,
+        &get_f64_or(table, entry, "aperture", 0.0)?,
+        &get_f64_or(table, entry, "focus_dist", 10.0)?,
+        get_f64_or(table, entry, "time0", 0.0)?,
+        get_f64_or(table, entry, "time1", 1.0)?,
+    ))
+}
+
+pub fn load_scene(path: &str, aspect_ratio: f64) -> Result<Scene, SceneError> {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(err) => return Err(SceneError::Io(path.to_string(), err)),
+    };
+    let root: Value = match source.parse() {
+        Ok(root) => root,
+        Err(err) => return Err(SceneError::Parse(path.to_string(), err)),
+    };
+    parse_scene(as_table(&root, path)?, aspect_ratio)
+}
+
+pub fn parse_scene(root: &Table, aspect_ratio: f64) -> Result<Scene, SceneError> {
+    let mut loader = Loader {
+        textures: HashMap::new(),
+        materials: HashMap::new(),
+        geometry: HashMap::new(),
+        unsampled: HashSet::new(),
+    };
+    if let Some(textures) = root.get("textures") {
+        for (name, value) in as_table(textures, "textures")? {
+            let entry = format!("textures.{}", name);
+            let texture = loader.build_texture(as_table(value, &entry)?, &entry)?;
+            loader.textures.insert(name.clone(), texture);
+        }
+    }
+    // coated materials are built after their base
+    if let Some(materials) = root.get("materials") {
+        let materials = as_table(materials, "materials")?;
+        for name in dependency_order(materials, "materials", material_refs)? {
+            let entry = format!("materials.{}", name);
+            let material = loader.build_material(as_table(&materials[&name], &entry)?, &entry)?;
+            loader.materials.insert(name, material);
+        }
+    }
+    // instances in the geometry are built after the geometry they use
+    if let Some(geometry) = root.get("geometry") {
+        let geometry = as_table(geometry, "geometry")?;
+        for name in dependency_order(geometry, "geometry", geometry_refs)? {
+            let entry = format!("geometry.{}", name);
+            let table = as_table(&geometry[&name], &entry)?;
+            let object = loader.build_object(table, &entry)?;
+            if !loader.can_sample(table) {
+                loader.unsampled.insert(name.clone());
+            }
+            loader.geometry.insert(name, Arc::from(object));
+        }
+    }
+
+    let camera = match root.get("camera") {
+        Some(camera) => build_camera(as_table(camera, "camera")?, aspect_ratio)?,
+        None => return invalid("camera", "missing table `camera`".to_string()),
+    };
+    let (time0, time1) = camera.shutter();
+
+    let mut world = HittableList::new();
+    let mut lights = HittableList::new();
+    if let Some(objects) = root.get("objects") {
+        let objects = match objects.as_array() {
+            Some(objects) => objects,
+            None => return invalid("objects", "expected an array of tables".to_string()),
+        };
+        for (i, value) in objects.iter().enumerate() {
+            let entry = format!("objects[{}]", i);
+            let table = as_table(value, &entry)?;
+            let object: Arc<dyn Hittable> = Arc::from(loader.build_object(table, &entry)?);
+            let kind_entry = format!("{} ({})", entry, get_str(table, &entry, "type")?);
+            // the BVH places objects by their bounding box, an OBJ file without faces has none
+            let mut boxb = AABB::new(Vec3::zero(), Vec3::zero());
+            if !object.bounding_box(time0, time1, &mut boxb) {
+                return invalid(&kind_entry, "the object has no bounding box".to_string());
+            }
+            // the lights share the object with the world
+            if get_bool_or(table, &entry, "sample", false)? {
+                if !loader.can_sample(table) {
+                    return invalid(
+                        &kind_entry,
+                        "the object cannot be sampled as a light".to_string(),
+                    );
+                }
+                lights.add(Box::new(object.clone()));
+            }
+            world.add(Box::new(object));
+        }
+    }
+
+    let background = get_vec3_or(root, "scene", "background", Vec3::zero())?;
+    Ok(Scene {
+        world,
+        lights,
+        camera,
+        background,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAMERA: &str = "[camera]\nlookfrom = [0, 0, 5]\nlookat = [0, 0, 0]\nvfov = 40\n";
+
+    fn parse(source: &str) -> Result<Scene, SceneError> {
+        let root: Value = format!("{}{}", CAMERA, source).parse().unwrap();
+        parse_scene(root.as_table().unwrap(), 1.0)
+    }
+    fn error(source: &str) -> String {
+        match parse(source) {
+            Ok(_) => panic!("the scene loaded"),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn missing_fields_name_the_entry() {
+        let message = error(
+            r#"
+            [[objects]]
+            type = "sphere"
+            center = [0, 0, 0]
+            material = "grey"
+            "#,
+        );
+        assert_eq!(message, "objects[0] (sphere): missing field `radius`");
+    }
+
+    #[test]
+    fn undefined_names_are_reported() {
+        let message = error(
+            r#"
+            [[objects]]
+            type = "sphere"
+            center = [0, 0, 0]
+            radius = 1
+            material = "gold"
+            "#,
+        );
+        assert_eq!(message, "objects[0] (sphere): undefined material `gold`");
+        let message = error(
+            r#"
+            [materials.grey]
+            type = "lambertian"
+            texture = "marble"
+            "#,
+        );
+        assert_eq!(message, "materials.grey: undefined texture `marble`");
+        let message = error(
+            r#"
+            [[objects]]
+            type = "instance"
+            geometry = "teapot"
+            "#,
+        );
+        assert_eq!(
+            message,
+            "objects[0] (instance): undefined geometry `teapot`"
+        );
+    }
+
+    #[test]
+    fn sampled_objects_are_shared_with_the_lights() {
+        let scene = parse(
+            r#"
+            [materials.light]
+            type = "diffuse_light"
+            emit = [4, 4, 4]
+
+            [[objects]]
+            type = "xz_rect"
+            x0 = 0
+            x1 = 1
+            z0 = 0
+            z1 = 1
+            k = 2
+            material = "light"
+            sample = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(scene.world.objects.len(), 1);
+        assert_eq!(scene.lights.objects.len(), 1);
+    }
+
+    #[test]
+    fn media_cannot_be_sampled() {
+        let message = error(
+            r#"
+            [materials.grey]
+            type = "lambertian"
+            albedo = [0.5, 0.5, 0.5]
+
+            [geometry.fog]
+            type = "constant_medium"
+            density = 0.1
+            albedo = [1, 1, 1]
+            boundary = { type = "box", min = [0, 0, 0], max = [1, 1, 1], material = "grey" }
+
+            [[objects]]
+            type = "instance"
+            geometry = "fog"
+            sample = true
+            "#,
+        );
+        assert_eq!(
+            message,
+            "objects[0] (instance): the object cannot be sampled as a light"
+        );
+    }
+
+    #[test]
+    fn objects_without_a_bounding_box_are_rejected() {
+        let message = error(
+            r#"
+            [[objects]]
+            type = "mesh"
+            positions = []
+            indices = []
+            material = "grey"
+
+            [materials.grey]
+            type = "lambertian"
+            albedo = [0.5, 0.5, 0.5]
+            "#,
+        );
+        assert_eq!(message, "objects[0] (mesh): the object has no bounding box");
+    }
+
+    #[test]
+    fn instances_may_use_geometry_named_after_them() {
+        let scene = parse(
+            r#"
+            [materials.grey]
+            type = "lambertian"
+            albedo = [0.5, 0.5, 0.5]
+
+            [geometry.a_copy]
+            type = "instance"
+            geometry = "b_copy"
+            transforms = [{ translate = [1, 0, 0] }]
+
+            [geometry.b_copy]
+            type = "instance"
+            geometry = "sphere"
+
+            [geometry.sphere]
+            type = "sphere"
+            center = [0, 0, 0]
+            radius = 1
+            material = "grey"
+
+            [[objects]]
+            type = "instance"
+            geometry = "a_copy"
+            "#,
+        );
+        assert!(scene.is_ok());
+    }
+
+    #[test]
+    fn geometry_cycles_are_reported() {
+        let message = error(
+            r#"
+            [geometry.a]
+            type = "instance"
+            geometry = "b"
+
+            [geometry.b]
+            type = "constant_medium"
+            density = 0.1
+            albedo = [1, 1, 1]
+            boundary = { type = "instance", geometry = "a" }
+            "#,
+        );
+        assert_eq!(message, "geometry.a: the entry refers back to itself");
+    }
+
+    #[test]
+    fn coats_may_use_materials_named_after_them() {
+        let scene = parse(
+            r#"
+            [materials.a_varnish]
+            type = "coated"
+            base = "b_lacquer"
+
+            [materials.b_lacquer]
+            type = "coated"
+            base = "wood"
+            ior = 1.4
+
+            [materials.wood]
+            type = "lambertian"
+            albedo = [0.6, 0.4, 0.2]
+
+            [[objects]]
+            type = "sphere"
+            center = [0, 0, 0]
+            radius = 1
+            material = "a_varnish"
+            "#,
+        );
+        assert!(scene.is_ok());
+    }
+
+    #[test]
+    fn material_cycles_are_reported() {
+        let message = error(
+            r#"
+            [materials.a]
+            type = "coated"
+            base = "b"
+
+            [materials.b]
+            type = "coated"
+            base = "a"
+            "#,
+        );
+        assert_eq!(message, "materials.a: the entry refers back to itself");
+    }
+}
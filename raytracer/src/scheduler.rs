@@ -0,0 +1,206 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::vec::Vec;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TileOrder {
+    Scanline,
+    Spiral,
+    Hilbert,
+}
+impl FromStr for TileOrder {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "scanline" => Ok(TileOrder::Scanline),
+            "spiral" => Ok(TileOrder::Spiral),
+            "hilbert" => Ok(TileOrder::Hilbert),
+            _ => Err(format!("unknown tile order `{}`", s)),
+        }
+    }
+}
+
+// pixel rectangle [x0, x1) x [y0, y1), rows counted from the top of the image
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tile {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+}
+impl Tile {
+    pub fn width(&self) -> usize {
+        self.x1 - self.x0
+    }
+    pub fn height(&self) -> usize {
+        self.y1 - self.y0
+    }
+}
+
+// grid coordinates walked outwards from the centre tile in a square spiral
+fn spiral_order(columns: usize, rows: usize) -> Vec<(usize, usize)> {
+    let mut order = Vec::with_capacity(columns * rows);
+    let (mut x, mut y) = (((columns - 1) / 2) as i64, ((rows - 1) / 2) as i64);
+    let directions = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+    let mut leg = 1;
+    let mut direction = 0;
+    while order.len() < columns * rows {
+        // every length is walked twice before it grows, the walk leaves the grid on its way around
+        for _ in 0..2 {
+            let (dx, dy) = directions[direction % 4];
+            for _ in 0..leg {
+                if x >= 0 && y >= 0 && (x as usize) < columns && (y as usize) < rows {
+                    order.push((x as usize, y as usize));
+                }
+                x += dx;
+                y += dy;
+            }
+            direction += 1;
+        }
+        leg += 1;
+    }
+    order
+}
+
+// position of (x, y) along the Hilbert curve filling a n x n grid, n a power of two
+fn hilbert_index(n: usize, mut x: usize, mut y: usize) -> usize {
+    let mut index = 0;
+    let mut half = n / 2;
+    while half > 0 {
+        let rx = (x & half > 0) as usize;
+        let ry = (y & half > 0) as usize;
+        index += half * half * ((3 * rx) ^ ry);
+        x &= half - 1;
+        y &= half - 1;
+        // rotate the quadrant so the curve inside it starts and ends at the right corners
+        if ry == 0 {
+            if rx == 1 {
+                x = half - 1 - x;
+                y = half - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        half /= 2;
+    }
+    index
+}
+
+pub fn make_tiles(width: usize, height: usize, tile_size: usize, order: TileOrder) -> Vec<Tile> {
+    let columns = (width + tile_size - 1) / tile_size;
+    let rows = (height + tile_size - 1) / tile_size;
+    let mut grid: Vec<(usize, usize)> = match order {
+        TileOrder::Spiral => spiral_order(columns, rows),
+        _ => (0..rows)
+            .flat_map(|y| (0..columns).map(move |x| (x, y)))
+            .collect(),
+    };
+    if order == TileOrder::Hilbert {
+        let n = columns.max(rows).next_power_of_two();
+        grid.sort_by_key(|&(x, y)| hilbert_index(n, x, y));
+    }
+    grid.into_iter()
+        .map(|(x, y)| Tile {
+            x0: x * tile_size,
+            y0: y * tile_size,
+            x1: ((x + 1) * tile_size).min(width),
+            y1: ((y + 1) * tile_size).min(height),
+        })
+        .collect()
+}
+
+// Tiles are handed out one at a time to whichever worker asks next, so a slow tile only keeps
+// its own worker busy instead of holding up a fixed share of the image.
+pub struct TileQueue {
+    tiles: Vec<Tile>,
+    next: AtomicUsize,
+}
+impl TileQueue {
+    pub fn new(tiles: Vec<Tile>) -> Self {
+        Self {
+            tiles,
+            next: AtomicUsize::new(0),
+        }
+    }
+    pub fn pop(&self) -> Option<Tile> {
+        let i = self.next.fetch_add(1, Ordering::Relaxed);
+        self.tiles.get(i).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORDERS: [TileOrder; 3] = [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert];
+
+    // 7x3 and 1x5 grids with ragged last tiles, a square grid and one that fits exactly
+    const IMAGES: [(usize, usize, usize); 5] = [
+        (107, 45, 16),
+        (10, 37, 8),
+        (100, 100, 16),
+        (64, 32, 16),
+        (1, 1, 32),
+    ];
+
+    #[test]
+    fn tiles_cover_every_pixel_once() {
+        for &order in ORDERS.iter() {
+            for &(width, height, tile_size) in IMAGES.iter() {
+                let tiles = make_tiles(width, height, tile_size, order);
+                let columns = (width + tile_size - 1) / tile_size;
+                let rows = (height + tile_size - 1) / tile_size;
+                assert_eq!(tiles.len(), columns * rows, "{:?}", order);
+                let mut covered = vec![0; width * height];
+                for tile in &tiles {
+                    assert!(tile.width() > 0 && tile.height() > 0);
+                    assert!(tile.width() <= tile_size && tile.height() <= tile_size);
+                    for y in tile.y0..tile.y1 {
+                        for x in tile.x0..tile.x1 {
+                            covered[y * width + x] += 1;
+                        }
+                    }
+                }
+                assert!(
+                    covered.iter().all(|&count| count == 1),
+                    "{:?} {}x{}",
+                    order,
+                    width,
+                    height
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn spiral_visits_every_grid_cell_once() {
+        for &(columns, rows) in [(7, 3), (1, 5), (5, 1), (4, 4), (1, 1), (2, 9)].iter() {
+            let mut order = spiral_order(columns, rows);
+            assert_eq!(order[0], ((columns - 1) / 2, (rows - 1) / 2));
+            order.sort_unstable();
+            order.dedup();
+            assert_eq!(order.len(), columns * rows);
+        }
+    }
+
+    #[test]
+    fn hilbert_neighbours_are_adjacent() {
+        for &n in [1, 2, 4, 8, 16].iter() {
+            let tiles = make_tiles(n * 4, n * 4, 4, TileOrder::Hilbert);
+            assert_eq!(tiles.len(), n * n);
+            for pair in tiles.windows(2) {
+                let dx = (pair[0].x0 as i64 - pair[1].x0 as i64).abs();
+                let dy = (pair[0].y0 as i64 - pair[1].y0 as i64).abs();
+                assert_eq!(dx + dy, 4, "{:?} -> {:?}", pair[0], pair[1]);
+            }
+        }
+    }
+
+    #[test]
+    fn queue_hands_out_every_tile_once() {
+        let tiles = make_tiles(107, 45, 16, TileOrder::Spiral);
+        let queue = TileQueue::new(tiles.clone());
+        let popped: Vec<Tile> = std::iter::from_fn(|| queue.pop()).collect();
+        assert_eq!(popped, tiles);
+        assert_eq!(queue.pop(), None);
+    }
+}
@@ -0,0 +1,198 @@
+use crate::Vec3;
+use std::ops::{Add, Mul};
+
+// The range of wavelengths in nanometres that is traced, outside of it the eye is blind.
+pub const LAMBDA_MIN: f64 = 360.0;
+pub const LAMBDA_MAX: f64 = 830.0;
+
+fn piecewise_gaussian(x: f64, mu: f64, sigma_left: f64, sigma_right: f64) -> f64 {
+    let t = (x - mu) / if x < mu { sigma_left } else { sigma_right };
+    (-0.5 * t * t).exp()
+}
+// the CIE 1931 standard observer, the multi-lobe fit of Wyman, Sloan and Shirley, "Simple
+// Analytic Approximations to the CIE XYZ Color Matching Functions" (2013)
+pub fn cie_xyz(lambda: f64) -> Vec3 {
+    Vec3::new(
+        1.056 * piecewise_gaussian(lambda, 599.8, 37.9, 31.0)
+            + 0.362 * piecewise_gaussian(lambda, 442.0, 16.0, 26.7)
+            - 0.065 * piecewise_gaussian(lambda, 501.1, 20.4, 26.2),
+        0.821 * piecewise_gaussian(lambda, 568.8, 46.9, 40.5)
+            + 0.286 * piecewise_gaussian(lambda, 530.9, 16.3, 31.1),
+        1.217 * piecewise_gaussian(lambda, 437.0, 11.8, 36.0)
+            + 0.681 * piecewise_gaussian(lambda, 459.0, 26.0, 13.8),
+    )
+}
+// to the linear sRGB primaries with the D65 white point
+pub fn xyz_to_rgb(xyz: Vec3) -> Vec3 {
+    Vec3::new(
+        3.240_454_2 * xyz.x - 1.537_138_5 * xyz.y - 0.498_531_4 * xyz.z,
+        -0.969_266_0 * xyz.x + 1.876_010_8 * xyz.y + 0.041_556_0 * xyz.z,
+        0.055_643_4 * xyz.x - 0.204_025_9 * xyz.y + 1.057_225_2 * xyz.z,
+    )
+}
+
+// Wavelengths are drawn where the eye is most sensitive, from the density of pbrt-v4's
+// SampleVisibleWavelengths.
+pub fn sample_wavelength(u: f64) -> f64 {
+    538.0 - 138.888_889 * (0.856_910_62 - 1.827_501_97 * u).atanh()
+}
+pub fn wavelength_pdf(lambda: f64) -> f64 {
+    if !(LAMBDA_MIN..=LAMBDA_MAX).contains(&lambda) {
+        return 0.0;
+    }
+    let c = (0.0072 * (lambda - 538.0)).cosh();
+    0.003_939_804_2 / (c * c)
+}
+
+// the integrals of xyz_to_rgb(cie_xyz(lambda)) over the traced range
+const RGB_INTEGRALS: [f64; 3] = [128.335_162, 101.543_786, 97.116_889];
+
+// The color one unit of radiance at `lambda` contributes to the film. Each channel integrates
+// to one over the traced range, so a flat spectrum is white like an RGB color of equal channels.
+pub fn rgb_matching(lambda: f64) -> Vec3 {
+    let rgb = xyz_to_rgb(cie_xyz(lambda));
+    Vec3::new(
+        rgb.x / RGB_INTEGRALS[0],
+        rgb.y / RGB_INTEGRALS[1],
+        rgb.z / RGB_INTEGRALS[2],
+    )
+}
+
+// wavelengths carried by one camera sample, the first is the hero wavelength
+pub const N_WAVELENGTHS: usize = 4;
+
+// Values of a spectrum at the wavelengths of a path.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampledSpectrum {
+    pub values: [f64; N_WAVELENGTHS],
+}
+impl SampledSpectrum {
+    pub fn new(values: [f64; N_WAVELENGTHS]) -> Self {
+        Self { values }
+    }
+    pub fn constant(c: f64) -> Self {
+        Self::new([c; N_WAVELENGTHS])
+    }
+    pub fn zero() -> Self {
+        Self::constant(0.0)
+    }
+    pub fn from_fn<F: FnMut(usize) -> f64>(mut f: F) -> Self {
+        let mut values = [0.0; N_WAVELENGTHS];
+        for (i, value) in values.iter_mut().enumerate() {
+            *value = f(i);
+        }
+        Self::new(values)
+    }
+    // the estimate of the film color, each wavelength weighted by the density it was drawn with
+    pub fn to_rgb(self, wavelengths: &SampledWavelengths) -> Vec3 {
+        let mut rgb = Vec3::zero();
+        for i in 0..N_WAVELENGTHS {
+            if wavelengths.pdf[i] > 0.0 {
+                rgb += rgb_matching(wavelengths.lambda[i]) * (self.values[i] / wavelengths.pdf[i]);
+            }
+        }
+        rgb / N_WAVELENGTHS as f64
+    }
+}
+impl Add for SampledSpectrum {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        Self::from_fn(|i| self.values[i] + other.values[i])
+    }
+}
+impl Mul for SampledSpectrum {
+    type Output = Self;
+    fn mul(self, other: Self) -> Self {
+        Self::from_fn(|i| self.values[i] * other.values[i])
+    }
+}
+impl Mul<f64> for SampledSpectrum {
+    type Output = Self;
+    fn mul(self, other: f64) -> Self {
+        Self::from_fn(|i| self.values[i] * other)
+    }
+}
+
+// Hero wavelength sampling (Wilkie et al. 2014): the hero is drawn from the visible density and
+// the others are spread evenly from it, so one path estimates the whole spectrum.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampledWavelengths {
+    pub lambda: [f64; N_WAVELENGTHS],
+    pub pdf: [f64; N_WAVELENGTHS],
+}
+impl SampledWavelengths {
+    pub fn sample(u: f64) -> Self {
+        let mut lambda = [0.0; N_WAVELENGTHS];
+        let mut pdf = [0.0; N_WAVELENGTHS];
+        for i in 0..N_WAVELENGTHS {
+            let up = (u + i as f64 / N_WAVELENGTHS as f64).fract();
+            lambda[i] = sample_wavelength(up);
+            pdf[i] = wavelength_pdf(lambda[i]);
+        }
+        Self { lambda, pdf }
+    }
+    pub fn hero(&self) -> f64 {
+        self.lambda[0]
+    }
+    // after a wavelength dependent scattering only the hero wavelength follows its path
+    pub fn terminate_secondary(&mut self) {
+        if self.pdf[1..].iter().all(|&pdf| pdf == 0.0) {
+            return;
+        }
+        for pdf in self.pdf[1..].iter_mut() {
+            *pdf = 0.0;
+        }
+        self.pdf[0] /= N_WAVELENGTHS as f64;
+    }
+}
+
+// A spectral power distribution of a light.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Spd {
+    // Planck's law at a temperature in kelvin, normalized to one at its peak
+    Blackbody(f64),
+    // measured values at increasing wavelengths, linear in between and zero outside
+    Sampled { lambda: Vec<f64>, values: Vec<f64> },
+}
+fn planck(lambda: f64, temperature: f64) -> f64 {
+    const C: f64 = 299_792_458.0;
+    const H: f64 = 6.626_070_15e-34;
+    const KB: f64 = 1.380_649e-23;
+    let l = lambda * 1e-9;
+    2.0 * H * C * C / (l.powi(5) * ((H * C / (l * KB * temperature)).exp() - 1.0))
+}
+impl Spd {
+    pub fn value(&self, lambda: f64) -> f64 {
+        match self {
+            Spd::Blackbody(temperature) => {
+                // Wien's displacement law
+                let peak = 2.897_771_955e-3 / temperature * 1e9;
+                planck(lambda, *temperature) / planck(peak, *temperature)
+            }
+            Spd::Sampled { lambda: l, values } => {
+                if l.is_empty() || lambda < l[0] || lambda > l[l.len() - 1] {
+                    return 0.0;
+                }
+                let i = l.iter().position(|&x| x >= lambda).unwrap();
+                if i == 0 || l[i] == lambda {
+                    return values[i];
+                }
+                let t = (lambda - l[i - 1]) / (l[i] - l[i - 1]);
+                values[i - 1] + (values[i] - values[i - 1]) * t
+            }
+        }
+    }
+    pub fn sample(&self, wavelengths: &SampledWavelengths) -> SampledSpectrum {
+        SampledSpectrum::from_fn(|i| self.value(wavelengths.lambda[i]))
+    }
+    // the color the RGB integrator uses, what the film shows for this spectrum
+    pub fn to_rgb(&self) -> Vec3 {
+        let mut rgb = Vec3::zero();
+        let mut lambda = LAMBDA_MIN + 0.5;
+        while lambda < LAMBDA_MAX {
+            rgb += rgb_matching(lambda) * self.value(lambda);
+            lambda += 1.0;
+        }
+        rgb
+    }
+}
@@ -1,5 +1,6 @@
 use crate::materialfile::Material;
-use crate::rtweekend::random_f64;
+use crate::sampler::sample_2d;
+use crate::stats::{count_intersection, count_ray};
 use crate::HitRecord;
 use crate::Hittable;
 use crate::Lambertian;
@@ -32,6 +33,7 @@ fn get_sphere_uv(p: Vec3, u: &mut f64, v: &mut f64) {
 
 impl<T: Material> Hittable for Sphere<T> {
     fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        count_intersection();
         let oc: Vec3 = r.orig - self.center;
         let a: f64 = r.dir * r.dir;
         let half_b: f64 = r.dir * oc;
@@ -83,8 +85,10 @@ impl<T: Material> Hittable for Sphere<T> {
         );
         true
     }
-    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
-        if let None = self.hit(Ray::new(o, v, 0.0), 0.001, f64::INFINITY) {
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
+        count_ray();
+        let r = Ray::new(o, v, time);
+        if self.hit(r, 0.001, f64::INFINITY).is_none() {
             return 0.0;
         }
         let cos_theta_max =
@@ -92,7 +96,7 @@ impl<T: Material> Hittable for Sphere<T> {
         let solid_angle = 2.0 * PI * (1.0 - cos_theta_max);
         1.0 / solid_angle
     }
-    fn random(&self, o: Vec3) -> Vec3 {
+    fn random(&self, o: Vec3, _time: f64) -> Vec3 {
         let direction = self.center - o;
         let distance_squared = direction.squared_length();
         let uvw = Onb::new(direction);
@@ -100,8 +104,7 @@ impl<T: Material> Hittable for Sphere<T> {
     }
 }
 pub fn random_to_sphere(radius: f64, distance_squared: f64) -> Vec3 {
-    let r1 = random_f64(0.0, 1.0);
-    let r2 = random_f64(0.0, 1.0);
+    let (r1, r2) = sample_2d();
     let z = 1.0 + r2 * ((1.0 - radius * radius / distance_squared).sqrt() - 1.0);
 
     let phi = 2.0 * PI * r1;
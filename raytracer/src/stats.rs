@@ -0,0 +1,97 @@
+use std::cell::Cell;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Rays traced through the scene and ray-primitive intersection tests. Every thread counts into
+// its own cells and adds them to the totals with `flush`, the hot path never touches memory
+// shared between threads.
+thread_local! {
+    static RAYS: Cell<u64> = Cell::new(0);
+    static INTERSECTIONS: Cell<u64> = Cell::new(0);
+}
+static TOTAL_RAYS: AtomicU64 = AtomicU64::new(0);
+static TOTAL_INTERSECTIONS: AtomicU64 = AtomicU64::new(0);
+
+pub fn count_ray() {
+    RAYS.with(|n| n.set(n.get() + 1));
+}
+pub fn count_intersection() {
+    INTERSECTIONS.with(|n| n.set(n.get() + 1));
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RayStats {
+    pub rays: u64,
+    pub intersections: u64,
+}
+impl RayStats {
+    // the counts of the current thread since the last take or flush
+    pub fn take() -> Self {
+        Self {
+            rays: RAYS.with(|n| n.replace(0)),
+            intersections: INTERSECTIONS.with(|n| n.replace(0)),
+        }
+    }
+    pub fn flush() {
+        let stats = Self::take();
+        TOTAL_RAYS.fetch_add(stats.rays, Ordering::Relaxed);
+        TOTAL_INTERSECTIONS.fetch_add(stats.intersections, Ordering::Relaxed);
+    }
+    // counts flushed by all threads
+    pub fn total() -> Self {
+        Self {
+            rays: TOTAL_RAYS.load(Ordering::Relaxed),
+            intersections: TOTAL_INTERSECTIONS.load(Ordering::Relaxed),
+        }
+    }
+    pub fn intersections_per_ray(&self) -> f64 {
+        if self.rays == 0 {
+            0.0
+        } else {
+            self.intersections as f64 / self.rays as f64
+        }
+    }
+}
+impl fmt::Display for RayStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} rays, {} intersection tests ({:.2} per ray)",
+            self.rays,
+            self.intersections,
+            self.intersections_per_ray()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Hittable, HittableList, Lambertian, Ray, Sphere, Vec3};
+
+    #[test]
+    fn a_list_tests_every_object_once() {
+        // spheres one behind the other, every one of them in the way of the ray
+        let mut list = HittableList::new();
+        for i in 0..5 {
+            let center = Vec3::new(0.0, 0.0, -3.0 * (i + 1) as f64);
+            list.add(Box::new(Sphere::new(
+                center,
+                1.0,
+                Lambertian::new2(Vec3::ones()),
+            )));
+        }
+        RayStats::take();
+        count_ray();
+        let r = Ray::new(Vec3::zero(), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let rec = list.hit(r, 0.001, f64::INFINITY).unwrap();
+        assert!((rec.t - 2.0).abs() < 1e-12);
+        assert_eq!(
+            RayStats::take(),
+            RayStats {
+                rays: 1,
+                intersections: 5
+            }
+        );
+    }
+}
@@ -2,13 +2,18 @@ use crate::rtweekend::clamp;
 use crate::Perlin;
 use crate::Vec3;
 use image;
-use imageproc::drawing::Canvas;
 use std::str;
+use std::sync::Arc;
 use std::vec::Vec;
 
 pub trait Texture {
     fn value(&self, u: f64, v: f64, p: Vec3) -> Vec3;
 }
+impl<T: Texture + ?Sized> Texture for Arc<T> {
+    fn value(&self, u: f64, v: f64, p: Vec3) -> Vec3 {
+        (**self).value(u, v, p)
+    }
+}
 #[derive(Clone, Debug, PartialEq, Copy)]
 pub struct SolidColor {
     color_value: Vec3,
@@ -94,13 +99,16 @@ impl ImageTexture {
         }
     }
     pub fn new2(path: &str) -> Self {
-        let img = image::open(path).unwrap();
-        Self {
-            data: img.clone().into_bytes(),
-            width: img.clone().width() as i32,
-            height: img.clone().height() as i32,
-            bytes_per_scanline: img.clone().width() as i32 * 3,
-        }
+        Self::open(path).unwrap()
+    }
+    pub fn open(path: &str) -> Result<Self, image::ImageError> {
+        let img = image::open(path)?.to_rgb8();
+        Ok(Self {
+            width: img.width() as i32,
+            height: img.height() as i32,
+            bytes_per_scanline: img.width() as i32 * 3,
+            data: img.into_raw(),
+        })
     }
 }
 impl Texture for ImageTexture {
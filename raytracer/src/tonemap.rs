@@ -0,0 +1,184 @@
+use crate::Vec3;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ToneMapper {
+    Clamp,
+    Reinhard,
+    ExtendedReinhard,
+    Aces,
+    Uncharted2,
+}
+impl FromStr for ToneMapper {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clamp" => Ok(ToneMapper::Clamp),
+            "reinhard" => Ok(ToneMapper::Reinhard),
+            "reinhard-extended" => Ok(ToneMapper::ExtendedReinhard),
+            "aces" => Ok(ToneMapper::Aces),
+            "uncharted2" => Ok(ToneMapper::Uncharted2),
+            _ => Err(format!("unknown tone mapper `{}`", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ToneMapping {
+    pub operator: ToneMapper,
+    // in stops, the radiance is scaled by 2^exposure before the operator
+    pub exposure: f64,
+    // smallest luminance that maps to white with the extended Reinhard operator
+    pub white: f64,
+}
+impl Default for ToneMapping {
+    fn default() -> Self {
+        Self {
+            operator: ToneMapper::Clamp,
+            exposure: 0.0,
+            white: 4.0,
+        }
+    }
+}
+
+// relative luminance of a linear sRGB color
+pub fn luminance(c: Vec3) -> f64 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+fn scale_luminance(c: Vec3, mapped: f64) -> Vec3 {
+    let l = luminance(c);
+    if l <= 0.0 {
+        Vec3::zero()
+    } else {
+        c * (mapped / l)
+    }
+}
+
+// Narkowicz's fit of the ACES reference rendering transform
+fn aces(x: f64) -> f64 {
+    (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+}
+
+// Hable's filmic curve from Uncharted 2
+fn uncharted2(x: f64) -> f64 {
+    const SHOULDER: f64 = 0.15;
+    const LINEAR: f64 = 0.50;
+    const LINEAR_ANGLE: f64 = 0.10;
+    const TOE: f64 = 0.20;
+    const TOE_NUMERATOR: f64 = 0.02;
+    const TOE_DENOMINATOR: f64 = 0.30;
+    ((x * (SHOULDER * x + LINEAR_ANGLE * LINEAR) + TOE * TOE_NUMERATOR)
+        / (x * (SHOULDER * x + LINEAR) + TOE * TOE_DENOMINATOR))
+        - TOE_NUMERATOR / TOE_DENOMINATOR
+}
+
+fn map_channels(c: Vec3, f: impl Fn(f64) -> f64) -> Vec3 {
+    Vec3::new(f(c.x), f(c.y), f(c.z))
+}
+
+pub fn srgb_oetf(c: f64) -> f64 {
+    if c <= 0.003_130_8 {
+        12.92 * c
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+impl ToneMapping {
+    // linear radiance to linear display values in [0, 1]
+    pub fn apply(&self, color: Vec3) -> Vec3 {
+        let c = map_channels(color * 2f64.powf(self.exposure), |x| x.max(0.0));
+        let mapped = match self.operator {
+            ToneMapper::Clamp => c,
+            ToneMapper::Reinhard => {
+                let l = luminance(c);
+                scale_luminance(c, l / (1.0 + l))
+            }
+            ToneMapper::ExtendedReinhard => {
+                let l = luminance(c);
+                let white_squared = self.white * self.white;
+                scale_luminance(c, l * (1.0 + l / white_squared) / (1.0 + l))
+            }
+            ToneMapper::Aces => map_channels(c, aces),
+            ToneMapper::Uncharted2 => {
+                let white_scale = 1.0 / uncharted2(11.2);
+                map_channels(c, |x| uncharted2(2.0 * x) * white_scale)
+            }
+        };
+        map_channels(mapped, |x| x.clamp(0.0, 1.0))
+    }
+    pub fn srgb8(&self, color: Vec3) -> [u8; 3] {
+        let quantize = |x: f64| (255.0 * srgb_oetf(x) + 0.5) as u8;
+        let c = self.apply(color);
+        [quantize(c.x), quantize(c.y), quantize(c.z)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grey(x: f64) -> Vec3 {
+        Vec3::new(x, x, x)
+    }
+    fn mapped(operator: ToneMapper, x: f64) -> f64 {
+        let tonemap = ToneMapping {
+            operator,
+            ..ToneMapping::default()
+        };
+        tonemap.apply(grey(x)).x
+    }
+
+    #[test]
+    fn srgb_curve_is_continuous_and_keeps_the_ends() {
+        assert_eq!(srgb_oetf(0.0), 0.0);
+        assert!((srgb_oetf(1.0) - 1.0).abs() < 1e-12);
+        let knee = 0.003_130_8;
+        assert!((srgb_oetf(knee - 1e-9) - srgb_oetf(knee + 1e-9)).abs() < 1e-6);
+        assert_eq!(ToneMapping::default().srgb8(grey(0.5)), [188, 188, 188]);
+    }
+
+    #[test]
+    fn exposure_scales_by_stops() {
+        let tonemap = ToneMapping {
+            exposure: 1.0,
+            ..ToneMapping::default()
+        };
+        assert!((tonemap.apply(grey(0.25)).x - 0.5).abs() < 1e-12);
+        assert_eq!(tonemap.apply(grey(0.75)).x, 1.0);
+    }
+
+    #[test]
+    fn curves_map_black_to_black_and_white_to_white() {
+        assert!((mapped(ToneMapper::Reinhard, 1.0) - 0.5).abs() < 1e-12);
+        assert!((mapped(ToneMapper::ExtendedReinhard, 4.0) - 1.0).abs() < 1e-12);
+        assert!((mapped(ToneMapper::Uncharted2, 5.6) - 1.0).abs() < 1e-12);
+        assert_eq!(mapped(ToneMapper::Aces, 100.0), 1.0);
+        for &operator in &[
+            ToneMapper::Clamp,
+            ToneMapper::Reinhard,
+            ToneMapper::ExtendedReinhard,
+            ToneMapper::Aces,
+            ToneMapper::Uncharted2,
+        ] {
+            assert!(mapped(operator, 0.0).abs() < 1e-12);
+            let mut previous = 0.0;
+            for i in 1..100 {
+                let y = mapped(operator, i as f64 * 0.05);
+                assert!(y >= previous && y <= 1.0);
+                previous = y;
+            }
+        }
+    }
+
+    #[test]
+    fn reinhard_keeps_the_hue() {
+        let tonemap = ToneMapping {
+            operator: ToneMapper::Reinhard,
+            ..ToneMapping::default()
+        };
+        let c = tonemap.apply(Vec3::new(2.0, 1.0, 0.5));
+        assert!((c.x / c.y - 2.0).abs() < 1e-12 && (c.y / c.z - 2.0).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,397 @@
+use crate::bvh::FlatBvh;
+use crate::materialfile::Material;
+use crate::rtweekend::{fmax, fmin};
+use crate::sampler::{sample_1d, sample_2d};
+use crate::stats::{count_intersection, count_ray};
+use crate::HitRecord;
+use crate::Hittable;
+use crate::Ray;
+use crate::Vec3;
+use crate::AABB;
+use std::boxed::Box;
+use std::sync::Arc;
+use std::vec::Vec;
+
+// Möller–Trumbore, returns (t, b1, b2) where b1 and b2 are the barycentric weights of p1 and p2
+fn intersect(
+    r: Ray,
+    p0: Vec3,
+    p1: Vec3,
+    p2: Vec3,
+    t_min: f64,
+    t_max: f64,
+) -> Option<(f64, f64, f64)> {
+    let e1 = p1 - p0;
+    let e2 = p2 - p0;
+    let pvec = Vec3::cross(r.dir, e2);
+    let det = e1 * pvec;
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+    let tvec = r.orig - p0;
+    let b1 = (tvec * pvec) * inv_det;
+    if !(0.0..=1.0).contains(&b1) {
+        return None;
+    }
+    let qvec = Vec3::cross(tvec, e1);
+    let b2 = (r.dir * qvec) * inv_det;
+    if b2 < 0.0 || b1 + b2 > 1.0 {
+        return None;
+    }
+    let t = (e2 * qvec) * inv_det;
+    if t < t_min || t > t_max {
+        return None;
+    }
+    Some((t, b1, b2))
+}
+
+fn triangle_box(p0: Vec3, p1: Vec3, p2: Vec3) -> AABB {
+    let pad = 0.0001;
+    AABB::new(
+        Vec3::new(
+            fmin(p0.x, fmin(p1.x, p2.x)) - pad,
+            fmin(p0.y, fmin(p1.y, p2.y)) - pad,
+            fmin(p0.z, fmin(p1.z, p2.z)) - pad,
+        ),
+        Vec3::new(
+            fmax(p0.x, fmax(p1.x, p2.x)) + pad,
+            fmax(p0.y, fmax(p1.y, p2.y)) + pad,
+            fmax(p0.z, fmax(p1.z, p2.z)) + pad,
+        ),
+    )
+}
+
+// the density of the direction of `r` towards points picked uniformly on `area`, of which the
+// triangle is a part
+fn triangle_pdf_value(triangle: &dyn Hittable, p: [Vec3; 3], area: f64, r: Ray) -> f64 {
+    let v = r.dir;
+    match triangle.hit(r, 0.001, f64::INFINITY) {
+        None => 0.0,
+        Some(rec) => {
+            let n = Vec3::cross(p[1] - p[0], p[2] - p[0]);
+            let distance_squared = rec.t * rec.t * v.squared_length();
+            let cosine = (v * n / (v.length() * n.length())).abs();
+            distance_squared / (cosine * area)
+        }
+    }
+}
+
+fn triangle_random(p0: Vec3, p1: Vec3, p2: Vec3, o: Vec3) -> Vec3 {
+    let (u, v) = sample_2d();
+    let su = u.sqrt();
+    let b1 = su * (1.0 - v);
+    let b2 = su - b1;
+    p0 * (1.0 - su) + p1 * b1 + p2 * b2 - o
+}
+
+fn make_record(
+    r: Ray,
+    hit: (f64, f64, f64),
+    points: [Vec3; 3],
+    normals: Option<[Vec3; 3]>,
+    uvs: Option<[[f64; 2]; 3]>,
+    mat_ptr: &dyn Material,
+) -> HitRecord<'_> {
+    let (t, b1, b2) = hit;
+    let b0 = 1.0 - b1 - b2;
+    let (u, v) = match uvs {
+        Some(uv) => (
+            b0 * uv[0][0] + b1 * uv[1][0] + b2 * uv[2][0],
+            b0 * uv[0][1] + b1 * uv[1][1] + b2 * uv[2][1],
+        ),
+        None => (b1, b2),
+    };
+    let mut rec = HitRecord {
+        t,
+        p: r.at(t),
+        mat_ptr,
+        front_face: true,
+        u,
+        v,
+        normal: Vec3::zero(),
+    };
+    rec.set_face_normal(
+        r,
+        Vec3::cross(points[1] - points[0], points[2] - points[0]).unit(),
+    );
+    if let Some(n) = normals {
+        let shading = (n[0] * b0 + n[1] * b1 + n[2] * b2).unit();
+        rec.normal = if rec.front_face { shading } else { -shading };
+    }
+    rec
+}
+
+#[derive(Clone)]
+pub struct Triangle<T: Material> {
+    pub p: [Vec3; 3],
+    pub normals: Option<[Vec3; 3]>,
+    pub uvs: Option<[[f64; 2]; 3]>,
+    pub mat_ptr: T,
+}
+impl<T: Material> Triangle<T> {
+    pub fn new(p0: Vec3, p1: Vec3, p2: Vec3, m: T) -> Self {
+        Self {
+            p: [p0, p1, p2],
+            normals: None,
+            uvs: None,
+            mat_ptr: m,
+        }
+    }
+    pub fn with_normals(mut self, n0: Vec3, n1: Vec3, n2: Vec3) -> Self {
+        self.normals = Some([n0.unit(), n1.unit(), n2.unit()]);
+        self
+    }
+    pub fn with_uvs(mut self, uv0: [f64; 2], uv1: [f64; 2], uv2: [f64; 2]) -> Self {
+        self.uvs = Some([uv0, uv1, uv2]);
+        self
+    }
+}
+impl<T: Material> Hittable for Triangle<T> {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        count_intersection();
+        let hit = intersect(r, self.p[0], self.p[1], self.p[2], t_min, t_max)?;
+        Some(make_record(
+            r,
+            hit,
+            self.p,
+            self.normals,
+            self.uvs,
+            &self.mat_ptr,
+        ))
+    }
+    fn bounding_box(&self, _time0: f64, _time1: f64, output_box: &mut AABB) -> bool {
+        *output_box = triangle_box(self.p[0], self.p[1], self.p[2]);
+        true
+    }
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
+        count_ray();
+        let p = self.p;
+        let area = 0.5 * Vec3::cross(p[1] - p[0], p[2] - p[0]).length();
+        triangle_pdf_value(self, p, area, Ray::new(o, v, time))
+    }
+    fn random(&self, o: Vec3, _time: f64) -> Vec3 {
+        triangle_random(self.p[0], self.p[1], self.p[2], o)
+    }
+}
+
+// vertex attributes are indexed together, normals and uvs are either empty or as long as positions
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct MeshData {
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub uvs: Vec<[f64; 2]>,
+    pub indices: Vec<[usize; 3]>,
+}
+impl MeshData {
+    pub fn new(positions: Vec<Vec3>, indices: Vec<[usize; 3]>) -> Self {
+        Self {
+            positions,
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            indices,
+        }
+    }
+    pub fn is_valid(&self) -> bool {
+        let n = self.positions.len();
+        (self.normals.is_empty() || self.normals.len() == n)
+            && (self.uvs.is_empty() || self.uvs.len() == n)
+            && self.indices.iter().all(|face| face.iter().all(|&i| i < n))
+    }
+}
+
+// Faces are only sampled as part of their mesh, their density is relative to its whole area.
+struct MeshTriangle<M: Material> {
+    mesh: Arc<MeshData>,
+    face: usize,
+    mat_ptr: Arc<M>,
+    mesh_area: f64,
+}
+impl<M: Material> MeshTriangle<M> {
+    fn points(&self) -> [Vec3; 3] {
+        let face = self.mesh.indices[self.face];
+        [
+            self.mesh.positions[face[0]],
+            self.mesh.positions[face[1]],
+            self.mesh.positions[face[2]],
+        ]
+    }
+}
+impl<M: Material> Hittable for MeshTriangle<M> {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        count_intersection();
+        let p = self.points();
+        let hit = intersect(r, p[0], p[1], p[2], t_min, t_max)?;
+        let face = self.mesh.indices[self.face];
+        let normals = if self.mesh.normals.is_empty() {
+            None
+        } else {
+            Some([
+                self.mesh.normals[face[0]],
+                self.mesh.normals[face[1]],
+                self.mesh.normals[face[2]],
+            ])
+        };
+        let uvs = if self.mesh.uvs.is_empty() {
+            None
+        } else {
+            Some([
+                self.mesh.uvs[face[0]],
+                self.mesh.uvs[face[1]],
+                self.mesh.uvs[face[2]],
+            ])
+        };
+        Some(make_record(r, hit, p, normals, uvs, &*self.mat_ptr))
+    }
+    fn bounding_box(&self, _time0: f64, _time1: f64, output_box: &mut AABB) -> bool {
+        let p = self.points();
+        *output_box = triangle_box(p[0], p[1], p[2]);
+        true
+    }
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
+        let p = self.points();
+        triangle_pdf_value(self, p, self.mesh_area, Ray::new(o, v, time))
+    }
+}
+
+fn face_area(mesh: &MeshData, face: usize) -> f64 {
+    let face = mesh.indices[face];
+    let p = [
+        mesh.positions[face[0]],
+        mesh.positions[face[1]],
+        mesh.positions[face[2]],
+    ];
+    0.5 * Vec3::cross(p[1] - p[0], p[2] - p[0]).length()
+}
+
+// As a light the mesh is sampled uniformly by area, a triangle is picked in proportion to its
+// area and then a point on it.
+pub struct TriangleMesh {
+    mesh: Arc<MeshData>,
+    bvh: FlatBvh,
+    // running sum of the face areas, the last one is the area of the mesh
+    cumulative_area: Vec<f64>,
+}
+impl TriangleMesh {
+    pub fn new<M: 'static + Material>(mesh: MeshData, m: M) -> Self {
+        let mesh = Arc::new(mesh);
+        let mat_ptr = Arc::new(m);
+        let mut cumulative_area = Vec::with_capacity(mesh.indices.len());
+        let mut area = 0.0;
+        for face in 0..mesh.indices.len() {
+            area += face_area(&mesh, face);
+            cumulative_area.push(area);
+        }
+        let mut triangles: Vec<Box<dyn Hittable>> = Vec::with_capacity(mesh.indices.len());
+        for face in 0..mesh.indices.len() {
+            triangles.push(Box::new(MeshTriangle {
+                mesh: mesh.clone(),
+                face,
+                mat_ptr: mat_ptr.clone(),
+                mesh_area: area,
+            }));
+        }
+        Self {
+            mesh,
+            bvh: FlatBvh::new(triangles, 0.0, 1.0),
+            cumulative_area,
+        }
+    }
+    fn area(&self) -> f64 {
+        self.cumulative_area.last().copied().unwrap_or(0.0)
+    }
+}
+impl Hittable for TriangleMesh {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        self.bvh.hit(r, t_min, t_max)
+    }
+    fn bounding_box(&self, time0: f64, time1: f64, output_box: &mut AABB) -> bool {
+        self.bvh.bounding_box(time0, time1, output_box)
+    }
+    // every face along the direction could have been picked, not only the closest one
+    fn pdf_value(&self, o: Vec3, v: Vec3, time: f64) -> f64 {
+        if self.area() <= 0.0 {
+            return 0.0;
+        }
+        count_ray();
+        let mut pdf = 0.0;
+        self.bvh
+            .for_each_candidate(Ray::new(o, v, time), 0.001, f64::INFINITY, |face| {
+                pdf += face.pdf_value(o, v, time);
+            });
+        pdf
+    }
+    fn random(&self, o: Vec3, _time: f64) -> Vec3 {
+        let target = sample_1d() * self.area();
+        let face = self
+            .cumulative_area
+            .iter()
+            .position(|&a| a > target)
+            .unwrap_or(self.cumulative_area.len() - 1);
+        let face = self.mesh.indices[face];
+        let p = &self.mesh.positions;
+        triangle_random(p[face[0]], p[face[1]], p[face[2]], o)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Lambertian, SolidColor, XZRect};
+
+    fn grey() -> Lambertian<SolidColor> {
+        Lambertian::new2(Vec3::new(0.5, 0.5, 0.5))
+    }
+    // the unit square at y = 0 as two faces of different shape
+    fn square() -> TriangleMesh {
+        let positions = vec![
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 1.0),
+            Vec3::new(0.0, 0.0, 1.0),
+        ];
+        TriangleMesh::new(MeshData::new(positions, vec![[0, 1, 2], [0, 2, 3]]), grey())
+    }
+
+    #[test]
+    fn hit_interpolates_uvs() {
+        let triangle = Triangle::new(
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            grey(),
+        )
+        .with_uvs([0.0, 0.0], [1.0, 0.0], [0.0, 1.0]);
+        let r = Ray::new(Vec3::new(0.25, 0.5, 2.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let rec = triangle.hit(r, 0.001, f64::INFINITY).unwrap();
+        assert!((rec.t - 2.0).abs() < 1e-12);
+        assert!((rec.u - 0.25).abs() < 1e-12 && (rec.v - 0.5).abs() < 1e-12);
+        assert!(rec.front_face);
+        let miss = Ray::new(Vec3::new(0.75, 0.5, 2.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        assert!(triangle.hit(miss, 0.001, f64::INFINITY).is_none());
+    }
+
+    #[test]
+    fn mesh_density_matches_rect() {
+        let mesh = square();
+        let rect = XZRect::new(0.0, 1.0, 0.0, 1.0, 0.0, grey());
+        let o = Vec3::new(0.3, 2.0, 0.4);
+        for &target in &[(0.5, 0.3), (0.1, 0.9), (0.9, 0.2), (0.6, 0.7)] {
+            let v = Vec3::new(target.0, 0.0, target.1) - o;
+            let expected = rect.pdf_value(o, v, 0.0);
+            assert!((mesh.pdf_value(o, v, 0.0) - expected).abs() < 1e-9 * expected);
+        }
+        assert_eq!(mesh.pdf_value(o, Vec3::new(0.0, 1.0, 0.0), 0.0), 0.0);
+    }
+
+    #[test]
+    fn mesh_samples_point_at_the_mesh() {
+        let mesh = square();
+        let o = Vec3::new(0.3, 2.0, 0.4);
+        for _ in 0..100 {
+            let v = mesh.random(o, 0.0);
+            assert!((o + v).y.abs() < 1e-12);
+            assert!(mesh.pdf_value(o, v, 0.0) > 0.0);
+        }
+    }
+}
@@ -37,10 +37,6 @@ impl Vec3 {
             z: left.x * right.y - left.y * right.x,
         }
     }
-    pub fn near_zero(&self) -> bool {
-        let s = 1e-8;
-        self.x.abs() < s && self.y.abs() < s && self.z.abs() < s
-    }
     pub fn length(&self) -> f64 {
         Self::squared_length(&self).sqrt()
     }
@@ -92,22 +88,18 @@ pub fn refract(uv: &Vec3, n: &Vec3, etai_over_etat: &f64) -> Vec3 {
     let r_out_parallel: Vec3 = *n * (-(1.0 - r_out_perp.squared_length()).abs().sqrt());
     r_out_perp + r_out_parallel
 }
-pub fn random_in_unit_disk() -> Vec3 {
-    loop {
-        let p: Vec3 = Vec3::new(random_f64(-1.0, 1.0), random_f64(-1.0, 1.0), 0.0);
-        if p.squared_length() >= 1.0 {
-            continue;
-        }
-        return p;
-    }
-}
-pub fn random_in_hemisphere(normal: Vec3) -> Vec3 {
-    let in_unit_sphere = random_in_unit_sphere();
-    if in_unit_sphere * normal > 0.0 {
-        in_unit_sphere
+// maps a point of the unit square onto the unit disk, Shirley and Chiu's concentric mapping
+pub fn sample_unit_disk(u: f64, v: f64) -> Vec3 {
+    let (offset_x, offset_y) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+    if offset_x == 0.0 && offset_y == 0.0 {
+        return Vec3::zero();
+    }
+    let (radius, theta) = if offset_x.abs() > offset_y.abs() {
+        (offset_x, PI / 4.0 * (offset_y / offset_x))
     } else {
-        -in_unit_sphere
-    }
+        (offset_y, PI / 2.0 - PI / 4.0 * (offset_x / offset_y))
+    };
+    Vec3::new(radius * theta.cos(), radius * theta.sin(), 0.0)
 }
 impl Add for Vec3 {
     type Output = Self;